@@ -0,0 +1,100 @@
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+#[cfg(feature = "dashmap")]
+use pleme_middleware_rate_limit::ConcurrentMap;
+use pleme_middleware_rate_limit::{InMemoryStore, MutexMap, ShardedMap};
+
+const THREADS: usize = 8;
+const KEYS: usize = 1024;
+const WINDOW_SECS: u64 = 60;
+
+fn maps() -> Vec<(&'static str, Arc<dyn InMemoryStore>)> {
+    #[cfg_attr(not(feature = "dashmap"), allow(unused_mut))]
+    let mut maps: Vec<(&'static str, Arc<dyn InMemoryStore>)> = vec![
+        ("mutex", Arc::new(MutexMap::new())),
+        ("sharded_16", Arc::new(ShardedMap::new(16))),
+    ];
+    #[cfg(feature = "dashmap")]
+    maps.push(("dashmap", Arc::new(ConcurrentMap::new())));
+    maps
+}
+
+fn keys() -> Vec<String> {
+    (0..KEYS).map(|i| format!("10.0.{}.{}:/api", i / 256, i % 256)).collect()
+}
+
+/// Seconds since `epoch`, standing in for the store's clock
+fn now(epoch: Instant) -> u64 {
+    epoch.elapsed().as_secs()
+}
+
+/// Time `iters` records per thread from `THREADS` threads at once, each
+/// thread picking keys with `key`
+fn contended(
+    map: &Arc<dyn InMemoryStore>,
+    keys: &Arc<Vec<String>>,
+    epoch: Instant,
+    iters: u64,
+    key: fn(usize, u64) -> usize,
+) -> Duration {
+    let barrier = Arc::new(Barrier::new(THREADS + 1));
+    let threads: Vec<_> = (0..THREADS)
+        .map(|thread| {
+            let (map, keys, barrier) = (Arc::clone(map), Arc::clone(keys), Arc::clone(&barrier));
+            thread::spawn(move || {
+                barrier.wait();
+                for i in 0..iters {
+                    map.record(&keys[key(thread, i)], 1, WINDOW_SECS, now(epoch));
+                }
+            })
+        })
+        .collect();
+
+    barrier.wait();
+    let start = Instant::now();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    start.elapsed()
+}
+
+fn uncontended(c: &mut Criterion) {
+    let (keys, epoch) = (keys(), Instant::now());
+    let mut group = c.benchmark_group("uncontended");
+    for (name, map) in maps() {
+        let mut i = 0u64;
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                i += 1;
+                map.record(&keys[i as usize % KEYS], 1, WINDOW_SECS, now(epoch))
+            })
+        });
+    }
+    group.finish();
+}
+
+fn contended_distinct_keys(c: &mut Criterion) {
+    let (keys, epoch) = (Arc::new(keys()), Instant::now());
+    let mut group = c.benchmark_group("contended_distinct_keys");
+    for (name, map) in maps() {
+        group.bench_function(name, |b| {
+            b.iter_custom(|iters| contended(&map, &keys, epoch, iters, |thread, i| (thread * 131 + i as usize) % KEYS))
+        });
+    }
+    group.finish();
+}
+
+fn contended_hot_key(c: &mut Criterion) {
+    let (keys, epoch) = (Arc::new(keys()), Instant::now());
+    let mut group = c.benchmark_group("contended_hot_key");
+    for (name, map) in maps() {
+        group.bench_function(name, |b| b.iter_custom(|iters| contended(&map, &keys, epoch, iters, |_, _| 0)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, uncontended, contended_distinct_keys, contended_hot_key);
+criterion_main!(benches);
@@ -0,0 +1,50 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use pleme_middleware_rate_limit::{InMemoryStore, MutexMap};
+
+const WINDOW_SECS: u64 = 3600;
+
+/// Attempt windows pruned with a full `retain` on every check, as before
+/// pruning popped expired attempts off the front
+#[derive(Default)]
+struct EagerMap {
+    windows: Mutex<HashMap<String, Vec<u64>>>,
+}
+
+impl InMemoryStore for EagerMap {
+    fn record(&self, key: &str, hits: u32, window_secs: u64, now: u64) -> u32 {
+        let mut windows = self.windows.lock().unwrap();
+        let window = windows.entry(key.to_string()).or_default();
+        let window_start = now.saturating_sub(window_secs);
+        window.retain(|&t| t > window_start);
+        window.extend(std::iter::repeat_n(now, hits as usize));
+        window.len() as u32
+    }
+
+    fn cleanup(&self, _now: u64) {}
+}
+
+/// Checks on a hot key holding `attempts` in-window attempts, as for a key
+/// at its limit whose rejected requests record nothing
+fn hot_key(c: &mut Criterion) {
+    let mut group = c.benchmark_group("hot_key_pruning");
+    for attempts in [1_000, 50_000] {
+        let maps: [(&str, Box<dyn InMemoryStore>); 2] =
+            [("eager_retain", Box::<EagerMap>::default()), ("lazy_front", Box::new(MutexMap::new()))];
+        for (name, map) in maps {
+            for t in 0..attempts {
+                map.record("hot", 1, WINDOW_SECS, t / 100);
+            }
+            let now = attempts / 100;
+            group.bench_with_input(BenchmarkId::new(name, attempts), &now, |b, &now| {
+                b.iter(|| map.record("hot", 0, WINDOW_SECS, now))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, hot_key);
+criterion_main!(benches);
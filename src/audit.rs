@@ -0,0 +1,61 @@
+//! Structured login audit events
+
+use std::net::IpAddr;
+use serde::Serialize;
+
+/// Kind of a [`LoginAuditEvent`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoginEventKind {
+    /// A login attempt passed the limiter
+    Attempt,
+    /// A failed attempt was recorded
+    Failure,
+    /// The account was locked out
+    Lockout,
+    /// An expired lockout was lifted
+    Unlock,
+    /// Attempts were cleared after a successful login
+    Clear,
+}
+
+/// Audit record of a login limiter event
+///
+/// Identifiers are passed as given; sinks writing to shared audit stores
+/// may want to hash them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LoginAuditEvent {
+    /// Unix timestamp of the event
+    pub timestamp: u64,
+    /// Login identifier the event concerns
+    pub identifier: String,
+    /// What happened
+    pub kind: LoginEventKind,
+    /// Client IP, when the event came through `login_rate_limit_middleware`
+    pub source: Option<IpAddr>,
+    /// Failed attempts left before lockout, for attempts and failures
+    pub attempts_remaining: Option<u32>,
+    /// Unix timestamp the lockout ends, for lockouts
+    pub locked_until: Option<u64>,
+}
+
+/// Destination for login audit events, registered with
+/// `LoginRateLimiter::with_audit_sink`
+///
+/// Events are delivered synchronously under the limiter lock, so sinks
+/// should hand them off (e.g. to a channel) rather than block. Closures
+/// taking `&LoginAuditEvent` implement this trait. Without a sink, events
+/// only appear as the limiter's usual `tracing` logs.
+pub trait LoginAuditSink: Send + Sync {
+    /// Handle one event
+    fn record(&self, event: &LoginAuditEvent);
+}
+
+impl<F> LoginAuditSink for F
+where
+    F: Fn(&LoginAuditEvent) + Send + Sync,
+{
+    fn record(&self, event: &LoginAuditEvent) {
+        self(event)
+    }
+}
@@ -0,0 +1,144 @@
+//! Calendar-aligned quotas, e.g. daily limits resetting at local midnight
+
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{
+    clock::{civil_from_days, days_from_civil, Clock},
+    decision::{DecisionReason, LimitTier, RateLimitDecision, RejectionDetail},
+    limiter::RateLimitKey,
+};
+
+/// Calendar period a [`CalendarQuota`] resets on
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CalendarPeriod {
+    /// Resets at local midnight
+    #[default]
+    Daily,
+    /// Resets at local midnight on the first of each month
+    Monthly,
+}
+
+/// Requests allowed per calendar period in a fixed-offset timezone
+///
+/// Offsets are fixed, so zones observing DST keep one offset all year; use
+/// the standard-time offset and periods will reset an hour off local
+/// midnight during summer time. Periods never overlap or skip, so a DST
+/// change can't double or void a day's budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CalendarQuota {
+    /// Requests allowed per period
+    pub max_requests: u32,
+    /// Period the count resets on
+    #[serde(default)]
+    pub period: CalendarPeriod,
+    /// Offset of the local timezone from UTC in seconds, e.g. `-18000` for
+    /// UTC-5
+    #[serde(default)]
+    pub utc_offset_secs: i32,
+}
+
+impl CalendarQuota {
+    /// Unix seconds bounding the period containing `now`, as `[start, end)`
+    fn period_bounds(&self, now: u64) -> (u64, u64) {
+        let offset = i64::from(self.utc_offset_secs);
+        let local_days = (now as i64 + offset).div_euclid(86_400);
+        let (start_days, end_days) = match self.period {
+            CalendarPeriod::Daily => (local_days, local_days + 1),
+            CalendarPeriod::Monthly => {
+                let (year, month, _) = civil_from_days(local_days);
+                let (next_year, next_month) = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+                (days_from_civil(year, month, 1), days_from_civil(next_year, next_month, 1))
+            }
+        };
+        let to_unix = |days: i64| (days * 86_400 - offset).max(0) as u64;
+        (to_unix(start_days), to_unix(end_days))
+    }
+}
+
+/// Limiter counting requests per key over calendar periods rather than a
+/// rolling window
+///
+/// Each key tracks the period it was last seen in and its count there; the
+/// count restarts when a request arrives in a later period.
+///
+/// # Example
+/// ```rust
+/// use pleme_middleware_rate_limit::{CalendarPeriod, CalendarQuota, CalendarRateLimiter};
+///
+/// # async fn example() {
+/// // 1000 requests per day, resetting at midnight UTC+2
+/// let limiter: CalendarRateLimiter = CalendarRateLimiter::new(CalendarQuota {
+///     max_requests: 1000,
+///     period: CalendarPeriod::Daily,
+///     utc_offset_secs: 2 * 3600,
+/// });
+/// assert!(limiter.check("api-key").await.allowed);
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct CalendarRateLimiter<K = String> {
+    quota: CalendarQuota,
+    periods: Arc<Mutex<HashMap<K, (u64, u32)>>>,
+    clock: Clock,
+}
+
+impl<K: RateLimitKey> CalendarRateLimiter<K> {
+    /// Create a limiter applying `quota` to every key
+    pub fn new(quota: CalendarQuota) -> Self {
+        Self {
+            quota,
+            periods: Arc::new(Mutex::new(HashMap::new())),
+            clock: Clock::new(),
+        }
+    }
+
+    /// Count a request against a key for the current period
+    ///
+    /// Rejected requests aren't counted. `retry_after` is the time left until
+    /// the period ends.
+    pub async fn check<Q>(&self, key: &Q) -> RateLimitDecision<K>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        let now = self.clock.now();
+        let (start, end) = self.quota.period_bounds(now);
+
+        let mut periods = self.periods.lock().await;
+        let (period_start, count) = periods.entry(key.to_owned()).or_insert((start, 0));
+        if *period_start != start {
+            *period_start = start;
+            *count = 0;
+        }
+
+        if *count >= self.quota.max_requests {
+            return RateLimitDecision::reject(key.to_owned(), DecisionReason::Exceeded, Some(end - now))
+                .with_detail(RejectionDetail::Limit {
+                    tier: LimitTier::Key,
+                    count: *count,
+                    limit: self.quota.max_requests,
+                    window_secs: end - start,
+                });
+        }
+
+        *count += 1;
+        RateLimitDecision::allow(key.to_owned(), self.quota.max_requests - *count, DecisionReason::WithinLimit)
+    }
+
+    /// Quota applied to every key
+    pub fn quota(&self) -> CalendarQuota {
+        self.quota
+    }
+
+    /// Remove keys last seen in an earlier period
+    pub async fn cleanup(&self) {
+        let (start, _) = self.quota.period_bounds(self.clock.now());
+        self.periods.lock().await.retain(|_, (period_start, _)| *period_start == start);
+    }
+}
@@ -0,0 +1,144 @@
+//! Per-key circuit breaker in front of the limiter
+
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::hash::Hash;
+use std::sync::{PoisonError, RwLock};
+use tracing::{info, warn};
+
+use crate::sanitize::LogKey;
+
+/// Circuit breaker state of a key, reported by `RateLimiter::status`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Checks run normally
+    Closed,
+    /// Checks are rejected without touching the limiter until the given unix
+    /// timestamp
+    Open { until: u64 },
+    /// Cooldown elapsed; the next check is a probe that closes the circuit if
+    /// allowed and reopens it if rejected
+    HalfOpen,
+}
+
+#[derive(Debug, Default)]
+struct Circuit {
+    /// Consecutive limit rejections while closed
+    failures: u32,
+    open_until: Option<u64>,
+    /// Start of the half-open probe in flight, if any
+    probing: Option<u64>,
+}
+
+/// Circuits for keys that have been rejected recently
+///
+/// Open circuits are checked under a read lock, so hot abusive keys are
+/// turned away without contending for the limiter lock.
+pub(crate) struct CircuitBreakers<K> {
+    circuits: RwLock<HashMap<K, Circuit>>,
+    failure_threshold: u32,
+    cooldown_secs: u64,
+    /// Log keys as fingerprints, per `fingerprint_keys`
+    fingerprint_keys: bool,
+}
+
+impl<K: Hash + Eq + Clone> CircuitBreakers<K> {
+    pub(crate) fn new(failure_threshold: u32, cooldown_secs: u64, fingerprint_keys: bool) -> Self {
+        Self {
+            circuits: RwLock::new(HashMap::new()),
+            failure_threshold: failure_threshold.max(1),
+            cooldown_secs,
+            fingerprint_keys,
+        }
+    }
+
+    /// Let a check through, or return the seconds until the circuit may be
+    /// probed
+    pub(crate) fn admit<Q>(&self, key: &Q, now: u64) -> Result<(), u64>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ?Sized,
+    {
+        {
+            let circuits = self.circuits.read().unwrap_or_else(PoisonError::into_inner);
+            match circuits.get(key).and_then(|circuit| circuit.open_until) {
+                None => return Ok(()),
+                Some(until) if now < until => return Err(until - now),
+                Some(_) => {}
+            }
+        }
+
+        // Cooldown elapsed: let exactly one probe through
+        let mut circuits = self.circuits.write().unwrap_or_else(PoisonError::into_inner);
+        let Some(circuit) = circuits.get_mut(key) else {
+            return Ok(());
+        };
+        // A probe that never reported back (e.g. a cancelled request) is
+        // replaced after a cooldown
+        let probe_deadline = circuit.probing.map(|started| started.saturating_add(self.cooldown_secs.max(1)));
+        match circuit.open_until {
+            Some(until) if now < until => Err(until - now),
+            Some(_) if probe_deadline.is_some_and(|deadline| now < deadline) => Err(1),
+            Some(_) => {
+                circuit.probing = Some(now);
+                info!("Circuit half-open for key: {}", LogKey { key, fingerprint: self.fingerprint_keys });
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Update a key's circuit with the outcome of a check it admitted:
+    /// `exceeded` for a limit rejection, otherwise an allowed request
+    pub(crate) fn record<Q>(&self, key: &Q, exceeded: bool, now: u64)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + ?Sized,
+    {
+        if !exceeded {
+            if !self.circuits.read().unwrap_or_else(PoisonError::into_inner).contains_key(key) {
+                return;
+            }
+            let mut circuits = self.circuits.write().unwrap_or_else(PoisonError::into_inner);
+            if circuits.remove(key).is_some_and(|circuit| circuit.probing.is_some()) {
+                info!("Circuit closed for key: {}", LogKey { key, fingerprint: self.fingerprint_keys });
+            }
+            return;
+        }
+
+        let mut circuits = self.circuits.write().unwrap_or_else(PoisonError::into_inner);
+        let circuit = circuits.entry(key.to_owned()).or_default();
+        circuit.failures = circuit.failures.saturating_add(1);
+        if circuit.probing.is_some() || circuit.failures >= self.failure_threshold {
+            circuit.open_until = Some(now.saturating_add(self.cooldown_secs));
+            circuit.probing = None;
+            warn!("Circuit opened for key: {} ({} consecutive rejections)",
+                LogKey { key, fingerprint: self.fingerprint_keys }, circuit.failures);
+        }
+    }
+
+    /// Current state of a key's circuit, with `Open` carrying a monotonic
+    /// timestamp
+    pub(crate) fn state<Q>(&self, key: &Q, now: u64) -> CircuitState
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let circuits = self.circuits.read().unwrap_or_else(PoisonError::into_inner);
+        match circuits.get(key).and_then(|circuit| circuit.open_until) {
+            None => CircuitState::Closed,
+            Some(until) if now < until => CircuitState::Open { until },
+            Some(_) => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Forget circuits that are neither open nor probing for keys no longer
+    /// tracked by the limiter
+    pub(crate) fn retain(&self, now: u64, mut tracked: impl FnMut(&K) -> bool) {
+        let mut circuits = self.circuits.write().unwrap_or_else(PoisonError::into_inner);
+        circuits.retain(|key, circuit| {
+            circuit.probing.is_some() || circuit.open_until.is_some_and(|until| now < until) || tracked(key)
+        });
+    }
+}
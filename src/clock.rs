@@ -0,0 +1,103 @@
+//! Time source for window calculations
+
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::Instant;
+
+/// Monotonic clock reporting unix-epoch seconds
+///
+/// Window math runs on a `tokio::time::Instant` anchored to the wall clock once
+/// at construction, so NTP adjustments can't move timestamps backwards and
+/// shrink or stretch windows. Readings start at the unix time of construction
+/// and drift from the wall clock only by adjustments made after that.
+/// Timestamps handed to clients (ban and lockout expiry) are mapped back onto
+/// the wall clock with [`Clock::wall_time`].
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Clock {
+    anchor: Instant,
+    anchor_unix: u64,
+}
+
+impl Clock {
+    /// Create a clock anchored at the current wall time
+    pub(crate) fn new() -> Self {
+        Self {
+            anchor: Instant::now(),
+            anchor_unix: wall_now(),
+        }
+    }
+
+    /// Create a clock reading `unix_secs` now, e.g. near `u64::MAX`
+    #[cfg(test)]
+    pub(crate) fn at(unix_secs: u64) -> Self {
+        Self {
+            anchor: Instant::now(),
+            anchor_unix: unix_secs,
+        }
+    }
+
+    /// Current monotonic time in seconds
+    pub(crate) fn now(&self) -> u64 {
+        self.anchor_unix.saturating_add(self.anchor.elapsed().as_secs())
+    }
+
+    /// Convert a monotonic timestamp to unix wall-clock seconds
+    pub(crate) fn wall_time(&self, timestamp: u64) -> u64 {
+        let now = self.now();
+        let wall = wall_now();
+        if timestamp >= now {
+            wall.saturating_add(timestamp - now)
+        } else {
+            wall.saturating_sub(now - timestamp)
+        }
+    }
+}
+
+/// Format unix seconds as an RFC 7231 IMF-fixdate, e.g.
+/// `Sun, 06 Nov 1994 08:49:37 GMT`
+pub(crate) fn http_date(unix_secs: u64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let days = unix_secs / 86_400;
+    let secs = unix_secs % 86_400;
+    let (year, month, day) = civil_from_days(days as i64);
+
+    format!("{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize], day, MONTHS[(month - 1) as usize], year,
+        secs / 3600, secs % 3600 / 60, secs % 60)
+}
+
+/// Civil `(year, month, day)` of a day counted from the unix epoch
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    // Years start in March so the leap day falls at the end of the year
+    let era_days = days + 719_468;
+    let era = era_days.div_euclid(146_097);
+    let day_of_era = era_days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    (year_of_era + era * 400 + i64::from(month <= 2), month as u32, day as u32)
+}
+
+/// Days from the unix epoch to a civil date, the inverse of [`civil_from_days`]
+#[cfg(feature = "calendar-windows")]
+pub(crate) fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let shifted_month = i64::from((month + 9) % 12);
+    let day_of_year = (153 * shifted_month + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn wall_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
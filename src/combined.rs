@@ -0,0 +1,93 @@
+//! Combined API and login rate limiting
+
+use std::net::SocketAddr;
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::Response,
+    body::Body,
+};
+
+use crate::{
+    decision::RateLimitDecision,
+    error::RateLimitError,
+    limiter::RateLimiter,
+    login::{LoginCheckResult, LoginRateLimiter},
+};
+
+/// API and login limiters applied together by [`combined_rate_limit_middleware`]
+#[derive(Clone)]
+pub struct CombinedRateLimiter {
+    /// General limiter, checked first
+    pub api: RateLimiter,
+    /// Login limiter, checked once the API limit allows the request
+    pub login: LoginRateLimiter,
+}
+
+impl CombinedRateLimiter {
+    /// Combine an API and a login limiter
+    pub fn new(api: RateLimiter, login: LoginRateLimiter) -> Self {
+        Self { api, login }
+    }
+
+    /// Check `key` against the API limit and `identifier` against the login
+    /// limit, recording the API attempt only if both have headroom
+    ///
+    /// All or nothing: a locked account leaves the API budget untouched, and
+    /// an API rejection returns before the login limiter is consulted. The
+    /// API check uses the default quota, and as with `check_login_attempt`
+    /// nothing is recorded against the identifier until a failure is.
+    ///
+    /// Lock ordering: the API limiter's lock is taken first and held while
+    /// the login limiter's is taken for the check. Nothing holds the login
+    /// lock while waiting for the API one, so the two can't deadlock; with
+    /// an API store, the login check runs first and no API lock is held.
+    pub async fn check_and_reserve(
+        &self,
+        key: &str,
+        identifier: &str,
+    ) -> Result<(RateLimitDecision, LoginCheckResult), RateLimitError> {
+        self.api.check_and_reserve(key, || self.login.check_login_attempt(identifier)).await
+    }
+}
+
+/// API and login rate limiting in a single middleware, for login endpoints
+///
+/// The API limit is checked first, so a client over it is rejected without
+/// buffering its body or touching its login attempts. Those rejections match
+/// [`rate_limit_middleware`]: `429 Too Many Requests` with `Retry-After` when
+/// known and the [`RateLimitDecision`] in the response extensions.
+///
+/// Requests within the API limit then get the login check of
+/// [`login_rate_limit_middleware`], reading the identifier from the login
+/// limiter's `login_identifier_field`: locked accounts get a `429` and
+/// oversized bodies `413`. Those rejections give back the API attempt and
+/// carry the API limit's headers and decision, reporting its restored
+/// headroom. Handlers find both the decision and the [`LoginIdentifier`] in
+/// the request extensions.
+///
+/// [`rate_limit_middleware`]: crate::rate_limit_middleware
+/// [`login_rate_limit_middleware`]: crate::login_rate_limit_middleware
+/// [`RateLimitDecision`]: crate::RateLimitDecision
+/// [`LoginIdentifier`]: crate::LoginIdentifier
+pub async fn combined_rate_limit_middleware(
+    State(limiters): State<CombinedRateLimiter>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: Request<Body>,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let (request, admission) = match limiters.api.admit(addr, request).await {
+        Ok(admitted) => admitted,
+        Err(rejection) => return Ok(rejection),
+    };
+
+    match limiters.login.admit(addr, request).await {
+        Ok((request, _guard)) => {
+            let response = limiters.login.finish(next.run(request).await);
+            Ok(limiters.api.finish(admission, response).await)
+        }
+        // The API limit doesn't count requests the login limit turned away
+        Err(rejection) => Ok(limiters.api.abandon(admission, rejection).await),
+    }
+}
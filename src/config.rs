@@ -1,14 +1,60 @@
 //! Rate limiting configuration
 
-use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use serde::{
+    de::{self, value::MapAccessDeserializer, MapAccess, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use tracing::{warn, Level};
+
+use crate::{error::ParseQuotaError, sanitize::LogKey};
 
 /// Rate limiting configuration
+///
+/// When deserialized, a `rate` shorthand such as `"100/min"` may stand in for
+/// `max_requests_per_window` and `rate_window_secs` (see [`Quota`]'s
+/// [`FromStr`] impl for the format). Giving both forms is rejected.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(remote = "Self")]
 pub struct RateLimitConfig {
     /// Enable/disable rate limiting
     #[serde(default = "default_enabled")]
     pub enabled: bool,
 
+    /// Log a warning when a limiter is created with `enabled` off, so limiting
+    /// switched off by mistake doesn't go unnoticed
+    #[serde(default)]
+    pub warn_when_disabled: bool,
+
+    /// Add `X-RateLimit-Disabled: true` to responses passed through while
+    /// `enabled` is off
+    #[serde(default)]
+    pub disabled_header: bool,
+
+    /// Allow every request without limiting for this many seconds after the
+    /// limiter is created, so caches can warm before enforcement starts
+    /// (enforced from the start when unset)
+    ///
+    /// Requests during warm-up aren't recorded, so keys start enforcement
+    /// with empty windows, and are reported with `DecisionReason::WarmingUp`.
+    /// The switch to enforcement is logged by the first check after it.
+    #[serde(default)]
+    pub warm_up_secs: Option<u64>,
+
+    /// Log keys and login identifiers as their `key_fingerprint` instead of
+    /// in full, for keys that carry personal data such as emails or tokens
+    ///
+    /// Log lines and trace spans about the same key still share a
+    /// fingerprint, so they can be correlated. Limiting itself, the status
+    /// endpoint and snapshots still use full keys, and `LayeredStore` takes
+    /// its own `with_fingerprint_keys`.
+    #[serde(default)]
+    pub fingerprint_keys: bool,
+
     /// Maximum requests per window (for general API rate limiting)
     #[serde(default = "default_max_requests")]
     pub max_requests_per_window: u32,
@@ -17,6 +63,314 @@ pub struct RateLimitConfig {
     #[serde(default = "default_rate_window")]
     pub rate_window_secs: u64,
 
+    /// Sub-buckets per window for an approximate sliding window (exact log when unset)
+    ///
+    /// Attempts may expire up to `rate_window_secs / window_buckets` seconds
+    /// early; `1` is a fixed window.
+    #[serde(default)]
+    pub window_buckets: Option<u32>,
+
+    /// Width in seconds of the buckets attempts are stored in, decoupling
+    /// storage granularity from the decision window (takes precedence over
+    /// `window_buckets`; unset stores as `window_buckets` says)
+    ///
+    /// Decisions still count every attempt over the key's full window, e.g.
+    /// `rate_window_secs` of 3600, while only one counter is kept per
+    /// `storage_window_secs`. A key then stores at most
+    /// `window / storage_window_secs` counters of 16 bytes however much
+    /// traffic it sends: 60 for an hour at one-minute granularity, where an
+    /// exact log would keep a timestamp per request. The price is precision,
+    /// as attempts may expire up to `storage_window_secs` early. A width of
+    /// the window or more is a fixed window.
+    #[serde(default)]
+    pub storage_window_secs: Option<u64>,
+
+    /// Attempt timestamps stored per key before the key switches to bucketed
+    /// counters, bounding memory under huge limits
+    ///
+    /// A switched key is counted as with `window_buckets` (64 buckets when
+    /// unset) for as long as it's tracked, and a warning is logged.
+    #[serde(default = "default_max_stored_attempts")]
+    pub max_stored_attempts: usize,
+
+    /// Let the first request of a fresh window through without counting it
+    ///
+    /// A window is fresh once a key has no attempts in it and its last free
+    /// request has also left the window, so a key gets one free request per
+    /// idle window rather than once ever. Applies to in-memory limiting; with
+    /// the fast path enabled, a first request admitted through it is counted.
+    #[serde(default)]
+    pub always_allow_first: bool,
+
+    /// How requests over the limit are handled
+    #[serde(default)]
+    pub mode: RateLimitMode,
+
+    /// Minimum milliseconds between two requests for the same key, on top of
+    /// the window limit (no minimum when unset)
+    ///
+    /// Only each key's last request time is kept. Requests arriving sooner
+    /// are rejected, or in `Delay` mode held until the interval has passed if
+    /// that's within `max_wait_secs`. The spacing is tracked in process memory
+    /// even when counts live in an external store.
+    #[serde(default)]
+    pub min_interval_ms: Option<u64>,
+
+    /// Shape each key's traffic through a leaky bucket in the middleware
+    /// instead of counting it against a window (window limiting when unset)
+    ///
+    /// A token bucket lets a burst through at once and rejects the rest; a
+    /// leaky bucket lets requests out at a steady `leak_rate`, holding a burst
+    /// in the bucket and rejecting only when it overflows. Subnet and global
+    /// levels, bans and `mode` don't apply to shaped requests.
+    #[serde(default)]
+    pub leaky_bucket: Option<LeakyBucket>,
+
+    /// Limit each key's accumulated cost, e.g. bytes or compute units, with
+    /// a bucket that leaks at a committed rate (`check_cost` allows
+    /// everything when unset)
+    ///
+    /// Costs are charged with `check_cost` rather than by the middleware.
+    /// A charge is rejected when it would take the bucket past `bucket_size`,
+    /// so bursts up to the bucket size pass and the sustained rate is
+    /// `leak_rate_per_sec`.
+    #[serde(default)]
+    pub cost_bucket: Option<CostBucket>,
+
+    /// Form of the `Retry-After` header on rejections
+    #[serde(default)]
+    pub retry_after_format: RetryAfterFormat,
+
+    /// Rate limit headers added to responses
+    #[serde(default)]
+    pub rate_limit_headers: RateLimitHeaders,
+
+    /// Add an `X-RateLimit-Detail` header describing which limit rejected a
+    /// request, e.g. `subnet limit reached: 500 of 500 requests in 60 seconds`
+    ///
+    /// For debugging only: the header reveals limits and counts of other
+    /// tiers, so keep it off in production.
+    #[serde(default)]
+    pub rejection_detail_header: bool,
+
+    /// Add an `X-RateLimit-Debug` header naming the window algorithm and a
+    /// digest of the config to every response, e.g.
+    /// `algo=sliding_log;cfg=9c1f3e0a5b7d2468`, to tell which config an
+    /// instance runs during a rollout
+    ///
+    /// For debugging only: the digest lets clients tell configs apart and
+    /// spot changes, and the algorithm hints at how the limit can be gamed,
+    /// so keep it off in production.
+    #[serde(default)]
+    pub debug_header: bool,
+
+    /// Header advertising the configured limit on every response, allowed or
+    /// rejected, so clients can pace themselves (none when unset)
+    ///
+    /// Unlike `rate_limit_headers`, the value is fixed per limiter and costs
+    /// nothing per request. It reflects `max_requests_per_window` and
+    /// `rate_window_secs`, not per-key or resolver quotas.
+    #[serde(default)]
+    pub policy_header: Option<PolicyHeader>,
+
+    /// Send rejections with an `application/json` body describing the limit
+    /// instead of an empty one
+    ///
+    /// The body is an object with `error` (`rate_limited`, `banned`,
+    /// `circuit_open`, `draining`, `invalid_key`, `blocked` or `contended`),
+    /// `limit` and `window` (the quota in requests and seconds of the level
+    /// that rejected the request, the key's own unless a subnet or global
+    /// limit did),
+    /// `remaining` (0 unless the decision says otherwise) and `retry_after`
+    /// (seconds, or `null` when unknown), e.g.
+    /// `{"error":"rate_limited","limit":100,"window":60,"remaining":0,"retry_after":30}`.
+    #[serde(default)]
+    pub json_rejection_body: bool,
+
+    /// Up to this many seconds of random delay added to `Retry-After`, so
+    /// clients rejected together don't all retry at the same instant
+    ///
+    /// Jitter only lengthens the suggested wait. Defaults to 0.
+    #[serde(default)]
+    pub retry_after_jitter_secs: u64,
+
+    /// Longest `Retry-After` advertised, in seconds (uncapped when unset)
+    ///
+    /// Also caps the reset in `rate_limit_headers` and how long `Delay` mode
+    /// sleeps before checking again. Enforcement is unchanged: a client
+    /// retrying after the capped delay is rejected again until the limit
+    /// really frees up.
+    #[serde(default)]
+    pub max_retry_after_secs: Option<u64>,
+
+    /// Level of rejection logs: `error`, `warn`, `info`, `debug` or `trace`
+    ///
+    /// Unrecognized values fall back to `warn`.
+    #[serde(default = "default_rejection_log_level")]
+    pub rejection_log_level: String,
+
+    /// Handling of keys and login identifiers containing control characters
+    #[serde(default)]
+    pub control_chars: ControlCharPolicy,
+
+    /// Handling of checks after `RateLimiter::quiesce`
+    #[serde(default)]
+    pub drain_policy: DrainPolicy,
+
+    /// Decision `try_check` returns when it can't check a key without waiting
+    #[serde(default)]
+    pub contended_policy: ContendedPolicy,
+
+    /// Address in the `X-Forwarded-For` chain that identifies the client
+    #[serde(default)]
+    pub forwarded_ip: ForwardedIpStrategy,
+
+    /// Proxy addresses skipped by `ForwardedIpStrategy::RightMostUntrusted`
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+
+    /// Per-region limits keyed by the code returned from a `RegionResolver`
+    #[serde(default)]
+    pub region_limits: HashMap<String, Quota>,
+
+    /// User-Agent patterns given tighter limits or blocked outright, checked
+    /// in order with the first match applying (requires the
+    /// `user-agent-rules` feature)
+    ///
+    /// User-Agent is chosen by the client and trivially spoofed, so this
+    /// only deters bots that identify themselves honestly; it is not a
+    /// security boundary.
+    #[serde(default)]
+    pub user_agent_rules: Vec<UserAgentRule>,
+
+    /// Path groups sharing one counter per client IP
+    ///
+    /// A request whose path matches a group is counted under the group's
+    /// quota against the key `<ip>:group:<name>`, in place of the
+    /// `KeyExtractor` key and any region limit. The first matching group
+    /// wins. A quota set with `set_key_quota` on a group key still overrides
+    /// the group's quota.
+    #[serde(default)]
+    pub endpoint_groups: Vec<EndpointGroup>,
+
+    /// Separate, more generous budgets for requests an upstream proxy marks
+    /// with a priority header (the header is ignored when unset)
+    ///
+    /// The header is trusted as-is: make sure the edge strips or overwrites
+    /// it on every client request, or clients can grant themselves the
+    /// priority budget. See [`RequestPriority`].
+    #[serde(default)]
+    pub priority: Option<RequestPriority>,
+
+    /// Rewriting of request paths before they're keyed and matched against
+    /// `endpoint_groups`, so equivalent paths share a limit
+    ///
+    /// Applies to the default IP and path key; a custom `KeyExtractor` builds
+    /// its own keys. Off by default.
+    #[serde(default)]
+    pub path_normalization: PathNormalization,
+
+    /// Query string included in the default IP and path key, so e.g.
+    /// `/search?q=a` and `/search?q=b` are limited separately
+    ///
+    /// Every distinct value makes a new key, so including the full query or
+    /// a free-form parameter lets clients create keys without bound and
+    /// sidestep their limit by varying it. Prefer `params` naming only
+    /// parameters with few values. Ignored by default.
+    #[serde(default)]
+    pub key_query: KeyQuery,
+
+    /// Request count above which a warning is logged while requests are still
+    /// allowed, for early warning before the hard limit (disabled when unset)
+    #[serde(default)]
+    pub soft_limit: Option<u32>,
+
+    /// Warn only when a key's count first crosses `soft_limit` rather than on
+    /// every request above it
+    #[serde(default)]
+    pub soft_limit_once_per_window: bool,
+
+    /// Fraction of a key's limit, in `(0, 1]`, from which its allowed
+    /// requests carry `ThrottleLevel::Soft` rather than `None` (no
+    /// `ThrottleLevel` is inserted when unset)
+    ///
+    /// With a ratio of 0.8 and a limit of 100, the first 79 requests in a
+    /// window are `None`, the 80th to 100th `Soft`, and rejected requests
+    /// `Hard`. Handlers only ever see `Hard` in advisory mode, as rejections
+    /// are otherwise answered with 429 before reaching them. Requests allowed
+    /// without a check, e.g. while disabled or unsampled, are `None`. With
+    /// subnet or global limits, the ratio applies to whichever level has the
+    /// fewest requests left.
+    #[serde(default)]
+    pub soft_throttle_ratio: Option<f64>,
+
+    /// Aggregate limit for each client subnet, enforced alongside the per-key
+    /// limit (disabled when unset)
+    #[serde(default)]
+    pub subnet_limit: Option<Quota>,
+
+    /// IPv4 prefix length grouping clients under `subnet_limit`
+    #[serde(default = "default_subnet_prefix_v4")]
+    pub subnet_prefix_v4: u8,
+
+    /// IPv6 prefix length grouping clients under `subnet_limit`
+    #[serde(default = "default_subnet_prefix_v6")]
+    pub subnet_prefix_v6: u8,
+
+    /// Limit across all requests, enforced alongside the per-key and subnet
+    /// limits (disabled when unset)
+    #[serde(default)]
+    pub global_limit: Option<Quota>,
+
+    /// Share `global_limit` evenly between keys once it's nearly used up,
+    /// instead of first come, first served (disabled when unset)
+    #[serde(default)]
+    pub global_fairness: Option<GlobalFairness>,
+
+    /// Admit requests without taking the limiter lock while a key is at least
+    /// this many requests below its limit (disabled when unset)
+    #[serde(default)]
+    pub fast_path_margin: Option<u32>,
+
+    /// Run the full check on only this fraction of requests, in `(0, 1)`,
+    /// allowing the rest without touching limiter state (every request is
+    /// checked when unset)
+    ///
+    /// Trades precision for throughput on extreme workloads. Each sampled
+    /// request is counted `1 / sample_rate` times, so a key's count estimates
+    /// its full traffic: for a key sending `n` requests per window the
+    /// estimate is off by about `sqrt(n * (1 - rate) / rate)`, e.g. ±30
+    /// requests at 100 requests and a 10% rate. Only sampled requests can be
+    /// rejected, so a key over its limit still gets roughly `1 - rate` of
+    /// its excess through, and its responses alternate between allowed and
+    /// rejected. Unsampled requests are reported with
+    /// `DecisionReason::Unsampled`.
+    #[serde(default)]
+    pub sample_rate: Option<f64>,
+
+    /// Sample whole keys rather than individual requests under `sample_rate`
+    ///
+    /// Each key is then either always checked, exactly and without
+    /// extrapolation, or never limited, which avoids flapping at the cost of
+    /// leaving the unsampled fraction of keys unlimited.
+    #[serde(default)]
+    pub sample_per_key: bool,
+
+    /// Refund part of a request's cost once its handler responds quickly or
+    /// successfully, so cheap well-behaved requests use up less of the limit
+    /// (no refunds when unset)
+    ///
+    /// A request's cost is the attempts recorded for it: one, or
+    /// `1 / sample_rate` for a request sampled under `sample_rate` when no
+    /// subnet or global limit is set. Rejected and unsampled requests record
+    /// nothing and are never refunded. The request's own key and any subnet
+    /// and global levels it was counted against are each refunded their
+    /// share, and fractions of an attempt carry over per key until a whole
+    /// one can be given back. A key is never refunded below zero attempts;
+    /// attempts counted in an external store aren't refunded.
+    #[serde(default)]
+    pub refund_policy: Option<RefundPolicy>,
+
     /// Maximum login attempts before lockout
     #[serde(default = "default_max_login_attempts")]
     pub max_login_attempts: u32,
@@ -24,22 +378,1014 @@ pub struct RateLimitConfig {
     /// Account lockout duration in seconds
     #[serde(default = "default_lockout_duration")]
     pub lockout_duration_secs: u64,
+
+    /// Failed attempts allowed for one `rate_window_secs` after a lockout
+    /// expires
+    ///
+    /// Defaults to none: an expired lockout restores the full
+    /// `max_login_attempts` budget. A small probation budget re-locks accounts
+    /// quickly when guessing resumes right after a lockout.
+    #[serde(default)]
+    pub post_lockout_attempts: Option<u32>,
+
+    /// Lockout thresholds for login actions other than password login, such
+    /// as `password_reset` or `mfa`, by action label
+    ///
+    /// Attempts checked with an action (e.g. `check_login_action`) are
+    /// counted and locked per identifier and action, so a locked password
+    /// login doesn't block MFA verification. Actions without an entry use
+    /// `max_login_attempts` and `lockout_duration_secs`.
+    #[serde(default)]
+    pub login_actions: HashMap<String, LoginActionLimit>,
+
+    /// Login attempts one identifier may have in flight at once (unlimited
+    /// when unset)
+    ///
+    /// Counted from `begin_login_attempt` until its guard drops, which the
+    /// login middleware holds until the handler responds. Independent of the
+    /// windowed attempt count, this stops parallel guessing from racing the
+    /// lockout.
+    #[serde(default)]
+    pub max_concurrent_login_attempts: Option<u32>,
+
+    /// Distinct login identifiers one source IP may try in `rate_window_secs`
+    /// before its further attempts are rejected, against credential stuffing
+    /// (unlimited when unset)
+    ///
+    /// Once an IP tries one identifier more, all its attempts are rejected
+    /// with `RateLimitError::TooManyLoginIdentifiers` until enough of its
+    /// identifiers age out of the window. The cap is checked before
+    /// the per-account limit and rejected attempts never reach it, so a
+    /// stuffing IP can't spend the lockout budget of more accounts than the
+    /// cap allows, while each account still locks out after
+    /// `max_login_attempts` from any number of IPs. Only attempts with a
+    /// known source are counted: the login middleware's, and
+    /// `check_login_attempt_from`. Clients behind a shared NAT or proxy share
+    /// the cap, so leave headroom above the identifiers one IP legitimately
+    /// uses.
+    #[serde(default)]
+    pub max_login_identifiers_per_ip: Option<u32>,
+
+    /// Reject attempts on locked accounts from a read-locked copy of the
+    /// lockouts, without taking the login limiter's lock
+    ///
+    /// Under an attack that keeps hitting a locked account, its attempts then
+    /// neither wait for nor hold the lock other logins need. Like the locked
+    /// path, rejections only read the lockout, so repeated hits never extend
+    /// it. Each lockout is kept twice, a few dozen bytes per locked account.
+    #[serde(default)]
+    pub login_lockout_fast_path: bool,
+
+    /// Body field holding the login identifier for the login middleware
+    #[serde(default = "default_login_identifier_field")]
+    pub login_identifier_field: String,
+
+    /// Body format for the login middleware (detected from `Content-Type` when unset)
+    #[serde(default)]
+    pub login_body_format: Option<LoginBodyFormat>,
+
+    /// Maximum login body size buffered while extracting the identifier
+    #[serde(default = "default_login_max_body_bytes")]
+    pub login_max_body_bytes: usize,
+
+    /// Rate limit rejections within the window before a key is banned (disabled when unset)
+    #[serde(default)]
+    pub ban_threshold: Option<u32>,
+
+    /// Key ban duration in seconds
+    #[serde(default = "default_ban_duration")]
+    pub ban_duration_secs: u64,
+
+    /// Distinct paths one client IP may request within `rate_window_secs`
+    /// before it's handled per `distinct_paths_action` (unlimited when unset)
+    ///
+    /// A crude crawler detector: scanners probe many paths while real clients
+    /// revisit a few. Paths are compared after `path_normalization`, as
+    /// 8-byte hashes. Each IP seen in the window keeps a 16-byte entry per
+    /// distinct path, and never more than one past the limit, so tracking
+    /// costs at most about `16 * (limit + 1)` bytes per active IP plus map
+    /// overhead; with a limit of 100 and 100,000 active IPs, about 160 MB.
+    #[serde(default)]
+    pub max_distinct_paths_per_window: Option<u32>,
+
+    /// Handling of IPs over `max_distinct_paths_per_window`
+    #[serde(default)]
+    pub distinct_paths_action: DistinctPathAction,
+
+    /// How `OPTIONS` requests, such as the CORS preflights browsers send
+    /// automatically, count against a key's budget
+    ///
+    /// Only exempt them when nothing behind the limiter does real work for
+    /// `OPTIONS`: exempt requests reach the router unmetered, so a handler
+    /// or layer that answers them expensively becomes an unlimited path for
+    /// any client. `Separate` keeps a ceiling on them without spending the
+    /// budget of real requests.
+    #[serde(default)]
+    pub options_requests: OptionsPolicy,
+
+    /// Consecutive limit rejections before a key's circuit opens (disabled
+    /// when unset)
+    ///
+    /// An open circuit rejects the key's requests without recording them or
+    /// taking the limiter lock. After `circuit_cooldown_secs` one probe
+    /// request is checked normally: if allowed the circuit closes, otherwise
+    /// it reopens.
+    #[serde(default)]
+    pub circuit_failure_threshold: Option<u32>,
+
+    /// How long an open circuit rejects requests, in seconds
+    #[serde(default = "default_circuit_cooldown")]
+    pub circuit_cooldown_secs: u64,
+
+    /// Responses with a `watched_statuses` status within the window before a
+    /// key is banned, e.g. to catch scrapers probing for endpoints (disabled
+    /// when unset)
+    ///
+    /// These are counted separately from requests; the ban lasts
+    /// `ban_duration_secs`.
+    #[serde(default)]
+    pub status_ban_threshold: Option<u32>,
+
+    /// Response statuses counted towards `status_ban_threshold`
+    #[serde(default = "default_watched_statuses")]
+    pub watched_statuses: Vec<u16>,
+
+    /// Paths whose requests are additionally limited per distinct body
+    ///
+    /// Matching requests are buffered (up to `body_hash_max_bytes`) and hashed,
+    /// and each body counts against `body_hash_quota` under the request's key,
+    /// so identical repeats of a mutation can be limited more tightly than
+    /// distinct requests. Bodies are hashed with 64-bit FNV-1a, so every
+    /// instance sharing a store agrees on their keys. Bodies without a declared `Content-Length` or larger
+    /// than the cap are streamed through with only the regular limit applied.
+    #[serde(default)]
+    pub body_hash_paths: Vec<String>,
+
+    /// Limit for repeats of the same body on `body_hash_paths` (disabled when unset)
+    #[serde(default)]
+    pub body_hash_quota: Option<Quota>,
+
+    /// Maximum body size buffered for hashing
+    #[serde(default = "default_body_hash_max_bytes")]
+    pub body_hash_max_bytes: usize,
+
+    /// Minimum tracked keys before background cleanup scans the map
+    #[serde(default)]
+    pub cleanup_min_keys: usize,
+
+    /// Release map capacity during cleanup once occupancy drops below a quarter
+    #[serde(default)]
+    pub shrink_on_cleanup: bool,
+
+    /// Keys the limiter tracks before new keys evict others per
+    /// `eviction_policy` (unbounded when unset)
+    #[serde(default)]
+    pub max_tracked_keys: Option<usize>,
+
+    /// Which keys make room once `max_tracked_keys` are tracked
+    #[serde(default)]
+    pub eviction_policy: EvictionPolicy,
+
+    /// Seconds a restored or merged attempt may lie ahead of this limiter's
+    /// clock before it's treated as clock skew
+    #[serde(default = "default_future_timestamp_tolerance")]
+    pub future_timestamp_tolerance_secs: u64,
+
+    /// Handling of restored or merged attempts further in the future than
+    /// `future_timestamp_tolerance_secs`, which would otherwise hold a key at
+    /// its limit until the skewed time passes
+    #[serde(default)]
+    pub future_timestamps: FutureTimestampPolicy,
+}
+
+/// Request limit and window applied to a key
+///
+/// Deserializes from either a map of the fields or a rate string such as
+/// `"100/min"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Quota {
+    /// Maximum requests per window
+    pub max_requests: u32,
+    /// Time window in seconds
+    pub window_secs: u64,
+}
+
+/// When and how much `RateLimitConfig::refund_policy` refunds
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RefundPolicy {
+    /// Fraction of the request's cost refunded, from 0 to 1
+    pub fraction: f64,
+    /// Only refund requests whose handler responded within this many
+    /// milliseconds
+    #[serde(default)]
+    pub max_latency_ms: Option<u64>,
+    /// Only refund requests answered with a 2xx status
+    #[serde(default = "default_refund_require_success")]
+    pub require_success: bool,
+}
+
+impl RefundPolicy {
+    /// Whether a response with `status` after `latency_ms` earns a refund
+    pub(crate) fn applies(&self, status: u16, latency_ms: u64) -> bool {
+        (!self.require_success || (200..300).contains(&status))
+            && self.max_latency_ms.is_none_or(|max| latency_ms <= max)
+    }
+}
+
+/// Traffic shaper applied per key, see `RateLimitConfig::leaky_bucket`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct LeakyBucket {
+    /// Requests let out of the bucket per second
+    pub leak_rate: f64,
+    /// Requests the bucket holds, counting the one being let out; requests
+    /// arriving at a full bucket are rejected
+    pub bucket_capacity: u32,
+}
+
+/// Cost limit applied per key, see `RateLimitConfig::cost_bucket`
+///
+/// Levels are `f64`, so a charge much smaller than the level it's added to
+/// (below about 1e-15 of it) is lost to rounding, and applying many tiny
+/// charges can drift from their exact sum. Keep costs in units where a
+/// typical charge is well above that, e.g. bytes rather than gigabytes.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CostBucket {
+    /// Cost leaked from the bucket per second
+    pub leak_rate_per_sec: f64,
+    /// Cost the bucket holds before charges are rejected
+    pub bucket_size: f64,
+}
+
+/// Fair sharing of the global limit, see `RateLimitConfig::global_fairness`
+///
+/// Requests are counted per key in fixed windows of the global window's
+/// length. Until `saturation` of the global limit is used in a window,
+/// admission is first come, first served; after that a key is admitted only
+/// while it has made fewer requests in the window than its share, the global
+/// limit divided by the keys admitted so far (a new key counts itself). A
+/// noisy key that took most of the window before saturation is then refused
+/// until the window ends, and the rest goes to the other keys, so no key is
+/// starved by another however fast it sends. Shares are equal; a key needing
+/// less than its share leaves the rest to whoever asks first.
+///
+/// Fairness applies to the in-process state checked by `check_levels`, which
+/// the middleware uses whenever `global_limit` is set; it's skipped with a
+/// store. It costs a lock and a map entry per key admitted in the window, at
+/// most `global_limit.max_requests` entries, cleared each window.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct GlobalFairness {
+    /// Fraction of the global limit used in a window before shares are
+    /// enforced, from 0 (always) to 1 (never)
+    #[serde(default = "default_fairness_saturation")]
+    pub saturation: f64,
+}
+
+impl Default for GlobalFairness {
+    fn default() -> Self {
+        Self { saturation: default_fairness_saturation() }
+    }
+}
+
+/// Thresholds for one login action, see `RateLimitConfig::login_actions`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LoginActionLimit {
+    /// Failed attempts before lockout
+    pub max_attempts: u32,
+    /// Lockout duration, `lockout_duration_secs` when unset
+    #[serde(default)]
+    pub lockout_duration_secs: Option<u64>,
+}
+
+/// Limit adjustment for requests whose `User-Agent` matches a regex
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserAgentRule {
+    /// Regex matched anywhere in the `User-Agent` header
+    pub pattern: String,
+    /// What happens to matching requests
+    pub action: UserAgentAction,
+}
+
+/// Treatment of requests matching a [`UserAgentRule`]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UserAgentAction {
+    /// Reject with `403 Forbidden`
+    Block,
+    /// Scale the request's limit, e.g. `0.1` for a tenth of it
+    Multiplier(f64),
+}
+
+/// Named set of paths sharing one limit, see `RateLimitConfig::endpoint_groups`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EndpointGroup {
+    /// Group name used in the key
+    pub name: String,
+    /// Exact paths, or prefixes ending in `*` such as `/api/v1/reports/*`
+    pub paths: Vec<String>,
+    /// Limit shared by all paths in the group
+    pub quota: Quota,
+}
+
+impl EndpointGroup {
+    /// Whether a request path belongs to the group
+    pub(crate) fn matches(&self, path: &str) -> bool {
+        self.paths.iter().any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == pattern,
+        })
+    }
+}
+
+/// Priority budgets read from a trusted header, see
+/// `RateLimitConfig::priority`
+///
+/// A request whose header value names one of `levels` is counted against
+/// the key `<key>:priority:<level>`, so prioritized traffic neither uses up
+/// nor is held back by the client's normal budget. Other values, and
+/// requests without the header, get the normal limit.
+///
+/// # Security
+/// Nothing about the request proves its priority: the limiter believes the
+/// header. Only enable this behind a proxy that removes the header from
+/// client requests and sets it itself, e.g. from an authenticated tenant's
+/// plan. Otherwise any client can send `X-Priority: high` to multiply its
+/// budget or, with `bypass_unless_saturated`, skip per-client limits.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RequestPriority {
+    /// Header carrying the priority, e.g. `X-Priority`
+    pub header: String,
+    /// Budget for each priority value, by the header's exact value
+    pub levels: HashMap<String, PriorityLevel>,
+}
+
+impl RequestPriority {
+    /// Level named by a header value, with its name
+    pub(crate) fn level(&self, value: &str) -> Option<(&str, PriorityLevel)> {
+        self.levels.get_key_value(value.trim())
+            .map(|(name, level)| (name.as_str(), *level))
+    }
+}
+
+/// Budget for one priority, see [`RequestPriority`]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PriorityLevel {
+    /// Factor applied to the priority budget's `max_requests`, e.g. `2.0`
+    /// for twice the normal limit
+    #[serde(default = "default_priority_multiplier")]
+    pub multiplier: f64,
+    /// Skip the per-key and subnet limits, so only `global_limit` applies
+    ///
+    /// Requests then pass freely until the global limit is saturated, when
+    /// they're rejected like any other. Without a `global_limit` the
+    /// priority is unlimited.
+    #[serde(default)]
+    pub bypass_unless_saturated: bool,
+}
+
+/// Path rewrites applied before keying, see
+/// `RateLimitConfig::path_normalization`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PathNormalization {
+    /// Lowercase the path, so `/API/Users` and `/api/users` match
+    pub lowercase: bool,
+    /// Drop trailing slashes other than the root's, so `/users/` matches
+    /// `/users`
+    pub strip_trailing_slash: bool,
+    /// Collapse runs of slashes, so `/api//users` matches `/api/users`
+    pub collapse_slashes: bool,
+}
+
+impl PathNormalization {
+    /// Whether any rewrite is enabled
+    pub fn is_enabled(&self) -> bool {
+        self.lowercase || self.strip_trailing_slash || self.collapse_slashes
+    }
+
+    /// Apply the enabled rewrites to `path`, borrowing it when none change it
+    pub fn normalize<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        let mut path = Cow::Borrowed(path);
+        if self.collapse_slashes && path.contains("//") {
+            let mut collapsed = String::with_capacity(path.len());
+            for c in path.chars() {
+                if c != '/' || !collapsed.ends_with('/') {
+                    collapsed.push(c);
+                }
+            }
+            path = Cow::Owned(collapsed);
+        }
+        if self.strip_trailing_slash && path.len() > 1 && path.ends_with('/') {
+            let trimmed = path.trim_end_matches('/');
+            path = Cow::Owned(if trimmed.is_empty() { "/" } else { trimmed }.to_string());
+        }
+        if self.lowercase && path.chars().any(char::is_uppercase) {
+            path = Cow::Owned(path.to_lowercase());
+        }
+        path
+    }
+}
+
+/// Part of the query string keyed on, see `RateLimitConfig::key_query`
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyQuery {
+    /// Leave the query out of the key
+    #[default]
+    Ignore,
+    /// The raw query string as sent
+    Full,
+    /// The decoded values of these parameters, in this order; parameters
+    /// missing from a request are left out
+    Params(Vec<String>),
+}
+
+impl KeyQuery {
+    /// Key part for a request's raw query, empty when nothing is keyed
+    pub(crate) fn key_part(&self, query: Option<&str>) -> String {
+        let query = query.unwrap_or_default();
+        match self {
+            Self::Ignore => String::new(),
+            Self::Full => query.to_string(),
+            Self::Params(names) => {
+                let pairs: Vec<(String, String)> = serde_urlencoded::from_str(query).unwrap_or_default();
+                let mut kept = Vec::new();
+                for name in names {
+                    if let Some((_, value)) = pairs.iter().find(|(key, _)| key == name) {
+                        kept.push((name.as_str(), value.as_str()));
+                    }
+                }
+                serde_urlencoded::to_string(kept).unwrap_or_default()
+            }
+        }
+    }
+}
+
+/// Handling of requests over the limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitMode {
+    /// Reject requests over the limit
+    #[default]
+    Enforce,
+    /// Hold requests over the limit until a slot frees up, rejecting only when
+    /// the wait would exceed `max_wait_secs` or `max_queue_depth` requests are
+    /// already waiting on the key
+    Delay {
+        max_wait_secs: u64,
+        max_queue_depth: u32,
+    },
+    /// Never reject; responses to requests over the limit carry an
+    /// `X-RateLimit-Advisory: over-limit` header and are counted as violations
+    ///
+    /// Meant for clients that can't handle 429s. The server keeps serving, so a
+    /// well-behaved client must back off on its own while it sees the header.
+    Advisory,
+}
+
+/// `Retry-After` header format
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryAfterFormat {
+    /// Delay in seconds, e.g. `Retry-After: 30`
+    #[default]
+    Seconds,
+    /// Absolute HTTP-date, e.g. `Retry-After: Sun, 06 Nov 1994 08:49:37 GMT`
+    HttpDate,
+}
+
+/// Handling of keys containing control characters
+///
+/// Keys are always logged with control characters escaped; this decides
+/// whether such keys are limited as usual or refused outright.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ControlCharPolicy {
+    /// Limit the key as usual, escaping it in logs
+    #[default]
+    Sanitize,
+    /// Reject checks for the key
+    Reject,
+}
+
+/// Rate limit headers describing a key's budget on responses
+///
+/// With subnet or global limits, the headers describe whichever level has
+/// the fewest requests left or rejected the request, so they may show the
+/// subnet or global quota rather than the key's own. Headers are omitted
+/// while limiting is disabled or draining.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitHeaders {
+    /// No rate limit headers
+    #[default]
+    Off,
+    /// De-facto `X-RateLimit-Limit`, `X-RateLimit-Remaining` and
+    /// `X-RateLimit-Reset` (seconds until reset)
+    Legacy,
+    /// `RateLimit-Policy` and `RateLimit` from the IETF httpapi draft, e.g.
+    /// `RateLimit-Policy: "default";q=100;w=60` and
+    /// `RateLimit: "default";r=42;t=30`
+    Draft,
+    /// Both the legacy and draft headers
+    Both,
+}
+
+/// Header describing the configured limit, see
+/// `RateLimitConfig::policy_header`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PolicyHeader {
+    /// Header name
+    #[serde(default = "default_policy_header_name")]
+    pub name: String,
+    /// Header value, with `{limit}` and `{window}` replaced by the request
+    /// limit and window seconds
+    #[serde(default = "default_policy_header_format")]
+    pub format: String,
+}
+
+impl Default for PolicyHeader {
+    /// `X-RateLimit-Policy: 100;w=60` style header
+    fn default() -> Self {
+        Self {
+            name: default_policy_header_name(),
+            format: default_policy_header_format(),
+        }
+    }
+}
+
+impl PolicyHeader {
+    /// Header value for `quota`
+    pub fn value(&self, quota: Quota) -> String {
+        self.format
+            .replace("{limit}", &quota.max_requests.to_string())
+            .replace("{window}", &quota.window_secs.to_string())
+    }
+}
+
+/// Handling of checks while the limiter is draining for shutdown
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DrainPolicy {
+    /// Allow requests without recording them
+    #[default]
+    Allow,
+    /// Reject every request
+    Reject,
+}
+
+/// Handling of skewed attempt timestamps, see
+/// `RateLimitConfig::future_timestamps`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FutureTimestampPolicy {
+    /// Count the attempt as made now
+    #[default]
+    Clamp,
+    /// Drop the attempt
+    Discard,
+}
+
+impl FutureTimestampPolicy {
+    /// Correct timestamps more than `tolerance` seconds past `now`, returning
+    /// how many were corrected
+    pub(crate) fn apply(self, timestamps: &mut Vec<u64>, now: u64, tolerance: u64) -> usize {
+        let limit = now.saturating_add(tolerance);
+        let skewed = timestamps.iter().filter(|&&t| t > limit).count();
+        if skewed > 0 {
+            match self {
+                Self::Clamp => timestamps.iter_mut().filter(|t| **t > limit).for_each(|t| *t = now),
+                Self::Discard => timestamps.retain(|&t| t <= limit),
+            }
+        }
+        skewed
+    }
+}
+
+/// Handling of an IP over `RateLimitConfig::max_distinct_paths_per_window`
+///
+/// Every action logs when an IP first goes over and lists it in
+/// `RateLimiter::path_scanners` while it stays there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DistinctPathAction {
+    /// Only flag the IP, allowing its requests
+    Flag,
+    /// Reject the IP's requests until older paths leave the window
+    #[default]
+    Reject,
+    /// Reject every request from the IP for `ban_duration_secs`
+    Ban,
+}
+
+/// Handling of `OPTIONS` requests, see `RateLimitConfig::options_requests`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OptionsPolicy {
+    /// Count them like any other request
+    #[default]
+    Count,
+    /// Allow them without a check, reported as `DecisionReason::Exempt`
+    Exempt,
+    /// Count them under the key `<key>:options`, with the key's quota, so
+    /// they have a budget of their own; subnet and global limits still
+    /// count them
+    Separate,
+}
+
+/// Keys evicted when a new key arrives with `max_tracked_keys` already
+/// tracked
+///
+/// Evicting a key forgets its attempts, so its next request starts a fresh
+/// window: a client evicted at its limit is admitted again. Banned keys and
+/// keys with a `set_key_quota` override are never evicted. LRU and LFU scan
+/// every tracked key when the limiter is full, then evict a sixteenth of the
+/// cap at once so the scan is rare; each check also stamps its key's access
+/// time and count. Requests admitted by `fast_path_margin` skip the lock and
+/// aren't counted as accesses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EvictionPolicy {
+    /// Keys leave only once their attempts expire, as in `cleanup`
+    ///
+    /// A full limiter drops expired keys, scanning at most once a second;
+    /// new keys are tracked even if none have expired, so the cap is soft but
+    /// no live count is ever reset. Keys flooding in all stay tracked until
+    /// their window passes.
+    #[default]
+    Ttl,
+    /// Evict the keys checked least recently
+    ///
+    /// Suits many one-off keys, e.g. scanners: idle keys go first, so an
+    /// active client is only reset once capacity is exhausted by keys newer
+    /// than its last request.
+    Lru,
+    /// Evict the keys checked least often
+    ///
+    /// Keeps heavy clients' counts intact under a flood of new keys, at the
+    /// cost of newly tracked keys being the first evicted.
+    Lfu,
+}
+
+/// Handling of `try_check` calls that would have to wait for the limiter's
+/// state
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContendedPolicy {
+    /// Allow the request without recording it
+    #[default]
+    Allow,
+    /// Reject the request
+    Reject,
+}
+
+/// Choice of client address from the `X-Forwarded-For` chain
+///
+/// The chain is every `X-Forwarded-For` entry in order followed by the peer
+/// address of the connection. Entries are appended by each proxy a request
+/// passes through, so only those added by proxies you run can be trusted;
+/// anything to their left was supplied by the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ForwardedIpStrategy {
+    /// Ignore `X-Forwarded-For` and use the peer address; the only safe
+    /// choice when not behind a proxy
+    #[default]
+    Peer,
+    /// Left-most entry, the address the first proxy saw
+    ///
+    /// Clients can prepend arbitrary entries, so this is spoofable and only
+    /// suited to grouping well-behaved traffic, never to abuse limits.
+    LeftMost,
+    /// Right-most address not in `trusted_proxies`, starting from the peer
+    ///
+    /// Not spoofable as long as every proxy in front of the service is listed.
+    RightMostUntrusted,
+    /// Address `n` hops before the peer, so `Nth(1)` is the right-most
+    /// `X-Forwarded-For` entry
+    ///
+    /// Safe when exactly `n` proxies you control sit in front of the service;
+    /// a larger `n` reaches into client-supplied entries.
+    Nth(usize),
+}
+
+/// Login request body format
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LoginBodyFormat {
+    /// `application/x-www-form-urlencoded`
+    Form,
+    /// `application/json`
+    Json,
 }
 
 fn default_enabled() -> bool { true }
+fn default_refund_require_success() -> bool { true }
+fn default_policy_header_name() -> String { "X-RateLimit-Policy".to_string() }
+fn default_policy_header_format() -> String { "{limit};w={window}".to_string() }
 fn default_max_requests() -> u32 { 100 }
 fn default_rate_window() -> u64 { 60 }
 fn default_max_login_attempts() -> u32 { 5 }
+fn default_fairness_saturation() -> f64 { 0.8 }
+fn default_priority_multiplier() -> f64 { 1.0 }
 fn default_lockout_duration() -> u64 { 300 }
+fn default_login_identifier_field() -> String { "username".to_string() }
+fn default_login_max_body_bytes() -> usize { 16 * 1024 }
+fn default_ban_duration() -> u64 { 300 }
+fn default_max_stored_attempts() -> usize { 100_000 }
+fn default_circuit_cooldown() -> u64 { 30 }
+fn default_rejection_log_level() -> String { "warn".to_string() }
+fn default_watched_statuses() -> Vec<u16> { vec![404] }
+fn default_subnet_prefix_v4() -> u8 { 24 }
+fn default_subnet_prefix_v6() -> u8 { 64 }
+fn default_body_hash_max_bytes() -> usize { 64 * 1024 }
+fn default_future_timestamp_tolerance() -> u64 { 30 }
 
 impl Default for RateLimitConfig {
     fn default() -> Self {
         Self {
             enabled: true,
+            warn_when_disabled: false,
+            disabled_header: false,
+            warm_up_secs: None,
+            fingerprint_keys: false,
             max_requests_per_window: 100,
             rate_window_secs: 60,
+            window_buckets: None,
+            storage_window_secs: None,
+            max_stored_attempts: default_max_stored_attempts(),
+            always_allow_first: false,
+            mode: RateLimitMode::Enforce,
+            min_interval_ms: None,
+            leaky_bucket: None,
+            cost_bucket: None,
+            retry_after_format: RetryAfterFormat::Seconds,
+            rate_limit_headers: RateLimitHeaders::Off,
+            rejection_detail_header: false,
+            debug_header: false,
+            policy_header: None,
+            json_rejection_body: false,
+            retry_after_jitter_secs: 0,
+            max_retry_after_secs: None,
+            rejection_log_level: default_rejection_log_level(),
+            control_chars: ControlCharPolicy::Sanitize,
+            drain_policy: DrainPolicy::Allow,
+            contended_policy: ContendedPolicy::Allow,
+            forwarded_ip: ForwardedIpStrategy::Peer,
+            trusted_proxies: Vec::new(),
+            region_limits: HashMap::new(),
+            user_agent_rules: Vec::new(),
+            endpoint_groups: Vec::new(),
+            priority: None,
+            path_normalization: PathNormalization::default(),
+            key_query: KeyQuery::Ignore,
+            subnet_limit: None,
+            subnet_prefix_v4: default_subnet_prefix_v4(),
+            subnet_prefix_v6: default_subnet_prefix_v6(),
+            global_limit: None,
+            global_fairness: None,
+            soft_limit: None,
+            soft_limit_once_per_window: false,
+            soft_throttle_ratio: None,
+            fast_path_margin: None,
+            sample_rate: None,
+            sample_per_key: false,
+            refund_policy: None,
             max_login_attempts: 5,
             lockout_duration_secs: 300,
+            post_lockout_attempts: None,
+            login_actions: HashMap::new(),
+            max_concurrent_login_attempts: None,
+            max_login_identifiers_per_ip: None,
+            login_lockout_fast_path: false,
+            login_identifier_field: default_login_identifier_field(),
+            login_body_format: None,
+            login_max_body_bytes: default_login_max_body_bytes(),
+            ban_threshold: None,
+            ban_duration_secs: 300,
+            max_distinct_paths_per_window: None,
+            distinct_paths_action: DistinctPathAction::Reject,
+            options_requests: OptionsPolicy::Count,
+            circuit_failure_threshold: None,
+            circuit_cooldown_secs: default_circuit_cooldown(),
+            status_ban_threshold: None,
+            watched_statuses: default_watched_statuses(),
+            body_hash_paths: Vec::new(),
+            body_hash_quota: None,
+            body_hash_max_bytes: default_body_hash_max_bytes(),
+            cleanup_min_keys: 0,
+            shrink_on_cleanup: false,
+            max_tracked_keys: None,
+            eviction_policy: EvictionPolicy::Ttl,
+            future_timestamp_tolerance_secs: default_future_timestamp_tolerance(),
+            future_timestamps: FutureTimestampPolicy::Clamp,
         }
     }
 }
+
+impl RateLimitConfig {
+    /// Copy of the config changed by `f`, for per-route variants of a base
+    /// config
+    ///
+    /// # Example
+    /// ```rust
+    /// use pleme_middleware_rate_limit::RateLimitConfig;
+    ///
+    /// let base = RateLimitConfig::default();
+    /// let uploads = base.with(|config| config.body_hash_max_bytes = 1024 * 1024).with_limit(10);
+    /// let search = base.with_limit(300).with_window(60);
+    /// assert_eq!(uploads.max_requests_per_window, 10);
+    /// assert_eq!(search.rate_window_secs, 60);
+    /// ```
+    pub fn with(&self, f: impl FnOnce(&mut Self)) -> Self {
+        let mut config = self.clone();
+        f(&mut config);
+        config
+    }
+
+    /// Copy of the config with `max_requests_per_window` set to `max_requests`
+    pub fn with_limit(&self, max_requests: u32) -> Self {
+        self.with(|config| config.max_requests_per_window = max_requests)
+    }
+
+    /// Copy of the config with `rate_window_secs` set to `window_secs`
+    pub fn with_window(&self, window_secs: u64) -> Self {
+        self.with(|config| config.rate_window_secs = window_secs)
+    }
+
+    /// Copy of the config with limiting enabled or disabled
+    pub fn with_enabled(&self, enabled: bool) -> Self {
+        self.with(|config| config.enabled = enabled)
+    }
+
+    /// Parsed `rejection_log_level`
+    pub fn rejection_level(&self) -> Level {
+        self.rejection_log_level.parse().unwrap_or(Level::WARN)
+    }
+
+    /// Log that `limiter` is disabled, per `warn_when_disabled`
+    pub(crate) fn warn_if_disabled(&self, limiter: &str) {
+        if !self.enabled && self.warn_when_disabled {
+            warn!("{} rate limiting is disabled; requests pass unchecked", limiter);
+        }
+    }
+
+    /// Default quota from `max_requests_per_window` and `rate_window_secs`
+    pub fn quota(&self) -> Quota {
+        Quota {
+            max_requests: self.max_requests_per_window,
+            window_secs: self.rate_window_secs,
+        }
+    }
+
+    /// A key as it should appear in logs, per `fingerprint_keys`
+    pub(crate) fn log_key<'a, T: ?Sized>(&self, key: &'a T) -> LogKey<'a, T> {
+        LogKey { key, fingerprint: self.fingerprint_keys }
+    }
+
+    /// `retry_after` seconds limited to `max_retry_after_secs`
+    pub(crate) fn capped_retry_after(&self, retry_after: u64) -> u64 {
+        self.max_retry_after_secs.map_or(retry_after, |max| retry_after.min(max))
+    }
+
+    /// Buckets to store a key's attempts in over a `window_secs` window, per
+    /// `storage_window_secs` or else `window_buckets` (exact log when `None`)
+    pub(crate) fn window_buckets_for(&self, window_secs: u64) -> Option<u32> {
+        match self.storage_window_secs.filter(|&width| width > 0) {
+            Some(width) => Some(u32::try_from(window_secs.div_ceil(width).max(1)).unwrap_or(u32::MAX)),
+            None => self.window_buckets,
+        }
+    }
+
+    /// Window algorithm name: `sliding_log`, `sliding_window` or
+    /// `fixed_window`
+    pub(crate) fn algorithm(&self) -> &'static str {
+        match self.window_buckets_for(self.rate_window_secs) {
+            None => "sliding_log",
+            Some(1) => "fixed_window",
+            Some(_) => "sliding_window",
+        }
+    }
+
+    /// FNV-1a digest of the config, equal for equal configs across processes
+    pub(crate) fn digest(&self) -> u64 {
+        // Through `Value`, whose maps are sorted, so `HashMap` fields don't
+        // depend on iteration order
+        let canonical = serde_json::to_value(self).map(|value| value.to_string()).unwrap_or_default();
+        canonical.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+    }
+}
+
+/// Fields accepted by `RateLimitConfig` deserialization on top of its own
+#[derive(Deserialize)]
+struct RateLimitConfigRepr {
+    rate: Option<Quota>,
+    max_requests_per_window: Option<u32>,
+    rate_window_secs: Option<u64>,
+    #[serde(flatten, with = "RateLimitConfig")]
+    config: RateLimitConfig,
+}
+
+impl<'de> Deserialize<'de> for RateLimitConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let repr = RateLimitConfigRepr::deserialize(deserializer)?;
+        let mut config = repr.config;
+        match (repr.rate, repr.max_requests_per_window, repr.rate_window_secs) {
+            (Some(_), Some(_), _) | (Some(_), _, Some(_)) => {
+                return Err(de::Error::custom(
+                    "`rate` can't be combined with `max_requests_per_window` or `rate_window_secs`",
+                ));
+            }
+            (Some(rate), None, None) => {
+                config.max_requests_per_window = rate.max_requests;
+                config.rate_window_secs = rate.window_secs;
+            }
+            (None, max_requests, window_secs) => {
+                config.max_requests_per_window = max_requests.unwrap_or_else(default_max_requests);
+                config.rate_window_secs = window_secs.unwrap_or_else(default_rate_window);
+            }
+        }
+        Ok(config)
+    }
+}
+
+impl Serialize for RateLimitConfig {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        RateLimitConfig::serialize(self, serializer)
+    }
+}
+
+impl FromStr for Quota {
+    type Err = ParseQuotaError;
+
+    /// Parse a rate such as `"100/min"`, `"10/s"` or `"1000/hour"`
+    ///
+    /// The unit is one of `s`/`sec`/`second`, `m`/`min`/`minute`,
+    /// `h`/`hour` or `d`/`day`, optionally plural and optionally preceded by
+    /// a count, e.g. `"500/15min"`.
+    fn from_str(rate: &str) -> Result<Self, Self::Err> {
+        let error = |reason| ParseQuotaError { rate: rate.to_string(), reason };
+
+        let (count, period) = rate.split_once('/')
+            .ok_or_else(|| error("expected <requests>/<unit>, e.g. \"100/min\""))?;
+        let max_requests = count.trim().parse::<u32>()
+            .map_err(|_| error("request count must be a whole number"))?;
+
+        let period = period.trim();
+        let (length, unit) = period.split_at(period.find(|c: char| !c.is_ascii_digit()).unwrap_or(period.len()));
+        let length = match length {
+            "" => 1,
+            length => length.parse::<u64>().map_err(|_| error("window is too long"))?,
+        };
+        let unit_secs = match unit {
+            "s" | "sec" | "secs" | "second" | "seconds" => 1,
+            "m" | "min" | "mins" | "minute" | "minutes" => 60,
+            "h" | "hour" | "hours" => 3600,
+            "d" | "day" | "days" => 86_400,
+            "" => return Err(error("missing time unit; use s, m, h or d")),
+            _ => return Err(error("unknown time unit; use s, m, h or d")),
+        };
+        let window_secs = length.checked_mul(unit_secs).ok_or_else(|| error("window is too long"))?;
+        if window_secs == 0 {
+            return Err(error("window must be longer than zero"));
+        }
+
+        Ok(Self { max_requests, window_secs })
+    }
+}
+
+/// Fields of a [`Quota`] given as a map
+#[derive(Deserialize)]
+struct QuotaFields {
+    max_requests: u32,
+    window_secs: u64,
+}
+
+impl<'de> Deserialize<'de> for Quota {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct QuotaVisitor;
+
+        impl<'de> Visitor<'de> for QuotaVisitor {
+            type Value = Quota;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a rate such as \"100/min\" or a map with max_requests and window_secs")
+            }
+
+            fn visit_str<E: de::Error>(self, rate: &str) -> Result<Quota, E> {
+                rate.parse().map_err(E::custom)
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Quota, A::Error> {
+                let fields = QuotaFields::deserialize(MapAccessDeserializer::new(map))?;
+                Ok(Quota {
+                    max_requests: fields.max_requests,
+                    window_secs: fields.window_secs,
+                })
+            }
+        }
+
+        deserializer.deserialize_any(QuotaVisitor)
+    }
+}
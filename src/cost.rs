@@ -0,0 +1,75 @@
+//! Per-key buckets of accumulated cost leaking at a steady rate
+
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Mutex, PoisonError};
+use tokio::time::Instant;
+
+use crate::config::CostBucket;
+
+/// Cost held by a key's bucket as of `updated`
+#[derive(Debug, Clone, Copy)]
+struct Level {
+    cost: f64,
+    updated: Instant,
+}
+
+/// Accumulated cost for every key whose bucket hasn't drained
+///
+/// Each key stores one float and the instant it was last updated; the cost
+/// leaked since then is subtracted on the next request.
+pub(crate) struct CostBuckets<K> {
+    levels: Mutex<HashMap<K, Level>>,
+    leak_rate: f64,
+    size: f64,
+}
+
+impl<K: Hash + Eq> CostBuckets<K> {
+    pub(crate) fn new(bucket: CostBucket) -> Self {
+        Self {
+            levels: Mutex::new(HashMap::new()),
+            leak_rate: bucket.leak_rate_per_sec.max(0.0),
+            size: bucket.bucket_size,
+        }
+    }
+
+    pub(crate) fn size(&self) -> f64 {
+        self.size
+    }
+
+    /// Add `cost` to `key`'s bucket, returning the room left, or the seconds
+    /// until it fits when it would overflow (`None` if it never will)
+    pub(crate) fn add<Q>(&self, key: &Q, cost: f64, now: Instant) -> Result<f64, Option<u64>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        let mut levels = self.levels.lock().unwrap_or_else(PoisonError::into_inner);
+        let level = levels.get(key).map_or(0.0, |level| self.drained(*level, now));
+        let filled = level + cost.max(0.0);
+
+        if filled > self.size {
+            if cost > self.size || self.leak_rate <= 0.0 {
+                return Err(None);
+            }
+            let secs = ((filled - self.size) / self.leak_rate).ceil().max(1.0);
+            return Err(Some(if secs < u64::MAX as f64 { secs as u64 } else { u64::MAX }));
+        }
+
+        levels.insert(key.to_owned(), Level { cost: filled, updated: now });
+        Ok(self.size - filled)
+    }
+
+    /// Forget keys whose buckets have leaked empty
+    pub(crate) fn cleanup(&self, now: Instant) {
+        self.levels.lock().unwrap_or_else(PoisonError::into_inner)
+            .retain(|_, level| self.drained(*level, now) > 0.0);
+    }
+
+    /// Cost left in a bucket after leaking until `now`
+    fn drained(&self, level: Level, now: Instant) -> f64 {
+        let leaked = now.saturating_duration_since(level.updated).as_secs_f64() * self.leak_rate;
+        (level.cost - leaked).max(0.0)
+    }
+}
@@ -0,0 +1,242 @@
+//! Rate limiting decisions
+
+use std::fmt;
+
+use crate::config::Quota;
+
+/// Reason behind a rate limit decision
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecisionReason {
+    /// Request is within the window limit
+    WithinLimit,
+    /// Rate limiting is disabled
+    Disabled,
+    /// Allowed without a check during `warm_up_secs` after startup
+    WarmingUp,
+    /// `OPTIONS` request allowed without a check by `options_requests`
+    Exempt,
+    /// Window limit exceeded
+    Exceeded,
+    /// Key is banned until the given unix timestamp
+    Banned(u64),
+    /// Limiter is quiesced for shutdown
+    Draining,
+    /// Key's circuit is open after repeated rejections
+    CircuitOpen,
+    /// User-Agent matched a blocking rule in `user_agent_rules`
+    Blocked,
+    /// Allowed without a check because `sample_rate` skipped the request
+    Unsampled,
+    /// Key contains control characters and `control_chars` is `Reject`
+    InvalidKey,
+    /// `try_check` couldn't check the key without waiting, and answered per
+    /// `contended_policy`
+    Contended,
+    /// The limit allowed the request but the `RecordHook` refused it
+    Vetoed,
+}
+
+/// Limit tier a [`RejectionDetail`] refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitTier {
+    /// The request's own key
+    Key,
+    /// The client's subnet under `subnet_limit`
+    Subnet,
+    /// All traffic under `global_limit`
+    Global,
+    /// The level at this index in the levels passed to `check_levels`
+    Level(usize),
+}
+
+impl fmt::Display for LimitTier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Key => f.write_str("key"),
+            Self::Subnet => f.write_str("subnet"),
+            Self::Global => f.write_str("global"),
+            Self::Level(index) => write!(f, "level {}", index),
+        }
+    }
+}
+
+/// Structured cause of a limit rejection, for diagnosing unexpected
+/// rejections in multi-level setups
+///
+/// Carries counts and limits that don't belong in production responses;
+/// `rejection_detail_header` exposes it to clients only when enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectionDetail {
+    /// A tier reached its window limit
+    Limit {
+        tier: LimitTier,
+        /// Requests counted in the window
+        count: u32,
+        limit: u32,
+        window_secs: u64,
+    },
+    /// A tier's key is banned until the given unix timestamp
+    Banned { tier: LimitTier, until: u64 },
+    /// The key's previous request was less than `min_interval_ms` ago
+    MinInterval {
+        interval_ms: u64,
+        /// Milliseconds until the interval has passed
+        wait_ms: u64,
+    },
+    /// The client IP requested more than `max_distinct_paths_per_window`
+    /// distinct paths
+    DistinctPaths {
+        /// Distinct paths counted in the window
+        paths: u32,
+        limit: u32,
+        window_secs: u64,
+    },
+    /// The key used its fair share of a saturated tier under
+    /// `global_fairness`
+    FairShare {
+        tier: LimitTier,
+        /// Requests each key may make in the fairness window
+        share: u32,
+        /// Keys admitted in the fairness window, counting this one
+        active_keys: u32,
+    },
+}
+
+impl RejectionDetail {
+    /// Tier that rejected the request
+    pub fn tier(&self) -> LimitTier {
+        match self {
+            Self::Limit { tier, .. } | Self::Banned { tier, .. } | Self::FairShare { tier, .. } => *tier,
+            Self::MinInterval { .. } | Self::DistinctPaths { .. } => LimitTier::Key,
+        }
+    }
+
+    /// Same detail attributed to `tier`
+    pub(crate) fn at_tier(mut self, new_tier: LimitTier) -> Self {
+        match &mut self {
+            Self::Limit { tier, .. } | Self::Banned { tier, .. } | Self::FairShare { tier, .. } => *tier = new_tier,
+            Self::MinInterval { .. } | Self::DistinctPaths { .. } => {}
+        }
+        self
+    }
+}
+
+impl fmt::Display for RejectionDetail {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Limit { tier, count, limit, window_secs } => {
+                write!(f, "{} limit reached: {} of {} requests in {} seconds", tier, count, limit, window_secs)
+            }
+            Self::Banned { tier, until } => write!(f, "{} banned until {}", tier, until),
+            Self::MinInterval { interval_ms, wait_ms } => {
+                write!(f, "minimum interval of {}ms not elapsed, {}ms left", interval_ms, wait_ms)
+            }
+            Self::DistinctPaths { paths, limit, window_secs } => {
+                write!(f, "distinct path limit reached: {} of {} paths in {} seconds", paths, limit, window_secs)
+            }
+            Self::FairShare { tier, share, active_keys } => {
+                write!(f, "{} fair share of {} requests used, {} keys active", tier, share, active_keys)
+            }
+        }
+    }
+}
+
+/// How close a request's key is to its limit, for handlers that degrade
+/// gracefully instead of failing, e.g. by returning fewer results
+///
+/// The middleware inserts this into request extensions when
+/// `soft_throttle_ratio` is set; see it for the thresholds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum ThrottleLevel {
+    /// Well within the limit
+    #[default]
+    None,
+    /// Near the limit, past `soft_throttle_ratio` of it
+    Soft,
+    /// Over the limit
+    Hard,
+}
+
+/// Outcome of a rate limit check
+///
+/// The middleware inserts this into both request and response extensions so
+/// handlers and outer layers (e.g. access logs) can record the outcome.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateLimitDecision<K = String> {
+    /// Key the request was counted against
+    pub key: K,
+    /// Whether the request was allowed
+    pub allowed: bool,
+    /// Requests remaining in the current window
+    pub remaining: u32,
+    /// Reason for the decision
+    pub reason: DecisionReason,
+    /// Seconds until the key may make another request, when rejected
+    pub retry_after: Option<u64>,
+    /// Which limit rejected the request, for limit and ban rejections
+    pub detail: Option<RejectionDetail>,
+    /// Enclosing level, such as the subnet or global limit, that `remaining`
+    /// was counted against and its quota, when it has fewer requests left
+    /// than the key itself or rejected the request (`None` when `remaining`
+    /// is the key's own)
+    pub limited_by: Option<(LimitTier, Quota)>,
+    /// Attempts recorded in memory against each key, the request's own and
+    /// any enclosing levels, so refunds give back exactly what was charged
+    pub(crate) charged: Vec<(K, u32)>,
+}
+
+impl<K> RateLimitDecision<K> {
+    pub(crate) fn allow(key: K, remaining: u32, reason: DecisionReason) -> Self {
+        Self {
+            key,
+            allowed: true,
+            remaining,
+            reason,
+            retry_after: None,
+            detail: None,
+            limited_by: None,
+            charged: Vec::new(),
+        }
+    }
+
+    pub(crate) fn reject(key: K, reason: DecisionReason, retry_after: Option<u64>) -> Self {
+        Self {
+            key,
+            allowed: false,
+            remaining: 0,
+            reason,
+            retry_after,
+            detail: None,
+            limited_by: None,
+            charged: Vec::new(),
+        }
+    }
+
+    /// Attach the rejection's cause
+    pub(crate) fn with_detail(mut self, detail: RejectionDetail) -> Self {
+        self.detail = Some(detail);
+        self
+    }
+
+    /// Note `attempts` recorded against `key`
+    pub(crate) fn with_charge(mut self, key: K, attempts: u32) -> Self {
+        self.charged.push((key, attempts));
+        self
+    }
+
+    /// Same decision for the request's own `key`, counted against the
+    /// enclosing level at `tier`
+    pub(crate) fn for_level(mut self, key: K, tier: LimitTier, quota: Quota) -> Self {
+        self.key = key;
+        self.detail = self.detail.map(|detail| detail.at_tier(tier));
+        self.limited_by = Some((tier, quota));
+        self
+    }
+
+    /// Decision with its enclosing level tiers renamed by `rename`
+    pub(crate) fn map_tier(mut self, rename: impl Fn(LimitTier) -> LimitTier) -> Self {
+        self.detail = self.detail.map(|detail| detail.at_tier(rename(detail.tier())));
+        self.limited_by = self.limited_by.map(|(tier, quota)| (rename(tier), quota));
+        self
+    }
+}
@@ -8,4 +8,52 @@ pub enum RateLimitError {
 
     #[error("Account locked until {0}")]
     AccountLocked(u64),
+
+    #[error("Key banned until {0}")]
+    Banned(u64),
+
+    #[error("Rate limit store error: {0}")]
+    Store(String),
+
+    #[error("Rate limiter is draining")]
+    Draining,
+
+    #[error("Rate limit key contains control characters")]
+    InvalidKey,
+
+    #[error("Request blocked by a User-Agent rule")]
+    Blocked,
+
+    #[error("Rate limiter state is contended")]
+    Contended,
+
+    #[error("Request refused by the record hook")]
+    Vetoed,
+
+    #[error("Too many login attempts in flight")]
+    TooManyConcurrentLogins,
+
+    #[error("Too many login identifiers tried from this IP")]
+    TooManyLoginIdentifiers,
+}
+
+/// Malformed rate string, such as `"100/fortnight"`, passed to
+/// `Quota::from_str`
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("invalid rate {rate:?}: {reason}")]
+pub struct ParseQuotaError {
+    pub(crate) rate: String,
+    pub(crate) reason: &'static str,
+}
+
+/// Snapshot bytes that [`SnapshotCodec::decode`] couldn't read
+///
+/// [`SnapshotCodec::decode`]: crate::SnapshotCodec::decode
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum SnapshotError {
+    #[error("snapshot format version {0} is not supported")]
+    UnsupportedVersion(u64),
+
+    #[error("malformed snapshot: {0}")]
+    Malformed(String),
 }
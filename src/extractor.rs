@@ -0,0 +1,537 @@
+//! Rate limit key extraction
+
+use std::fmt::Display;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use axum::{
+    body::Body,
+    extract::ConnectInfo,
+    http::{header::{AUTHORIZATION, COOKIE, USER_AGENT}, HeaderName, Request},
+};
+
+use crate::{
+    config::{ForwardedIpStrategy, KeyQuery, PathNormalization},
+    sanitize::fnv1a_bytes,
+};
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
+/// Key shared by requests without a user id under `user_id_extractor`
+const ANONYMOUS_KEY: &str = "anonymous";
+
+/// Builds the rate limit key for a request
+///
+/// Extractors see the whole request, so keys can draw on headers and on
+/// extensions set by earlier layers (e.g. a tenant or user id from an auth
+/// layer) as well as the client IP and path. Closures taking `&Request<Body>`
+/// implement this trait.
+///
+/// # Example
+/// ```rust
+/// use axum::{body::Body, http::Request};
+/// use pleme_middleware_rate_limit::{composite_key, IpPathKey, KeyExtractor, RateLimitConfig, RateLimiter};
+///
+/// #[derive(Clone)]
+/// struct TenantId(String);
+///
+/// // Isolate tenants' limits from each other
+/// let limiter = RateLimiter::new(RateLimitConfig::default())
+///     .with_key_extractor(|request: &Request<Body>| {
+///         let tenant = request.extensions()
+///             .get::<TenantId>()
+///             .map_or("anonymous", |tenant| tenant.0.as_str());
+///         composite_key(&[tenant, &IpPathKey.extract(request)])
+///     });
+/// ```
+pub trait KeyExtractor: Send + Sync {
+    /// Key the request is counted against
+    fn extract(&self, request: &Request<Body>) -> String;
+}
+
+impl<F> KeyExtractor for F
+where
+    F: Fn(&Request<Body>) -> String + Send + Sync,
+{
+    fn extract(&self, request: &Request<Body>) -> String {
+        self(request)
+    }
+}
+
+/// Join key parts with `:` so distinct part lists never produce the same key
+///
+/// `%` and `:` inside a part are percent-escaped, so parts containing the
+/// separator (IPv6 addresses, crafted paths) can't shift part boundaries.
+///
+/// # Example
+/// ```rust
+/// use pleme_middleware_rate_limit::composite_key;
+///
+/// assert_eq!(composite_key(&["1.2.3.4", "/x"]), "1.2.3.4:/x");
+/// assert_ne!(composite_key(&["a:b", "c"]), composite_key(&["a", "b:c"]));
+/// ```
+pub fn composite_key(parts: &[&str]) -> String {
+    let mut key = String::new();
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            key.push(':');
+        }
+        for c in part.chars() {
+            match c {
+                '%' => key.push_str("%25"),
+                ':' => key.push_str("%3A"),
+                c => key.push(c),
+            }
+        }
+    }
+    key
+}
+
+/// Default extractor keying on client IP and path
+///
+/// Keys are built with [`composite_key`], so IPv6 addresses appear with
+/// their colons escaped.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IpPathKey;
+
+impl KeyExtractor for IpPathKey {
+    fn extract(&self, request: &Request<Body>) -> String {
+        composite_key(&[&display_ip(client_ip(request)), request.uri().path()])
+    }
+}
+
+/// Extractor keying on client IP and path after [`PathNormalization`],
+/// optionally with part of the query string
+///
+/// Limiters use it in place of [`IpPathKey`] when
+/// `RateLimitConfig::path_normalization` enables any rewrite or
+/// `key_query` keys on the query. Keyed query parts are appended as a third
+/// key part, `<ip>:<path>:<query>`.
+#[derive(Debug, Clone, Default)]
+pub struct NormalizedIpPathKey {
+    normalization: PathNormalization,
+    query: KeyQuery,
+}
+
+impl NormalizedIpPathKey {
+    /// Key on paths rewritten by `normalization`
+    pub fn new(normalization: PathNormalization) -> Self {
+        Self { normalization, query: KeyQuery::Ignore }
+    }
+
+    /// Also key on the part of the query string selected by `query`
+    pub fn with_query(mut self, query: KeyQuery) -> Self {
+        self.query = query;
+        self
+    }
+}
+
+impl KeyExtractor for NormalizedIpPathKey {
+    fn extract(&self, request: &Request<Body>) -> String {
+        let ip = display_ip(client_ip(request));
+        let path = self.normalization.normalize(request.uri().path());
+        match self.query.key_part(request.uri().query()) {
+            query if query.is_empty() => composite_key(&[&ip, &path]),
+            query => composite_key(&[&ip, &path, &query]),
+        }
+    }
+}
+
+/// Extractor keying on a header value, such as an API client id or gRPC
+/// metadata entry (gRPC metadata travels as HTTP/2 headers)
+///
+/// Keys are `header:<name>:<value>`, built with [`composite_key`] so a value
+/// can't pose as another header's. Requests without the header, or with a
+/// non-UTF-8 value, fall back to client IP and path.
+#[derive(Debug, Clone)]
+pub struct HeaderKey {
+    name: HeaderName,
+}
+
+impl HeaderKey {
+    /// Key on the named header
+    pub fn new(name: HeaderName) -> Self {
+        Self { name }
+    }
+}
+
+impl KeyExtractor for HeaderKey {
+    fn extract(&self, request: &Request<Body>) -> String {
+        match request.headers().get(&self.name).and_then(|value| value.to_str().ok()) {
+            Some(value) => composite_key(&["header", self.name.as_str(), value]),
+            None => IpPathKey.extract(request),
+        }
+    }
+}
+
+/// Extractor keying on a session cookie, so users behind a shared NAT get
+/// separate limits
+///
+/// The cookie value is hashed into the key (`session:<hash>`) so session
+/// tokens never reach logs or metrics. The hash is 64-bit FNV-1a, unkeyed and
+/// stable across instances and releases, which keeps shared stores
+/// consistent. Requests without the
+/// cookie fall back to client IP and path.
+#[derive(Debug, Clone)]
+pub struct SessionCookieKey {
+    name: String,
+}
+
+impl SessionCookieKey {
+    /// Key on the named cookie, e.g. `session_id`
+    pub fn new(name: impl Into<String>) -> Self {
+        Self { name: name.into() }
+    }
+
+    /// Value of the cookie across all `Cookie` headers, if present
+    fn cookie<'a>(&self, request: &'a Request<Body>) -> Option<&'a str> {
+        request.headers()
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|header| header.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(name, _)| *name == self.name)
+            .map(|(_, value)| value.trim_matches('"'))
+            .filter(|value| !value.is_empty())
+    }
+}
+
+impl KeyExtractor for SessionCookieKey {
+    fn extract(&self, request: &Request<Body>) -> String {
+        match self.cookie(request) {
+            Some(value) => {
+                composite_key(&["session", &format!("{:016x}", fnv1a_bytes(value.as_bytes()))])
+            }
+            None => IpPathKey.extract(request),
+        }
+    }
+}
+
+/// Extractor keying on client IP together with the `User-Agent`
+///
+/// Keys are `<ip>:ua:<hash>`, hashing the header with the same FNV-1a hash
+/// as [`SessionCookieKey`] so long or crafted User-Agents can't inflate keys.
+/// Requests without a User-Agent share the IP's `ua:none` key. Each distinct
+/// User-Agent gets its own limit per IP, raising key cardinality, while
+/// clients behind one NAT sending identical User-Agents, such as a fleet
+/// running the same browser build, share a limit.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IpUserAgentKey;
+
+impl KeyExtractor for IpUserAgentKey {
+    fn extract(&self, request: &Request<Body>) -> String {
+        let user_agent = match request.headers().get(USER_AGENT) {
+            Some(value) => format!("{:016x}", fnv1a_bytes(value.as_bytes())),
+            None => "none".to_string(),
+        };
+        composite_key(&[&display_ip(client_ip(request)), "ua", &user_agent])
+    }
+}
+
+/// Extractor keying on the username of HTTP Basic credentials
+///
+/// The key is `basic:<username>`, decoded from an `Authorization: Basic`
+/// header. The password is discarded during extraction and never reaches the
+/// key, logs or metrics. Requests without Basic credentials, or whose header
+/// isn't valid base64 or UTF-8, fall back to client IP and path. The username
+/// isn't verified, so put an auth check ahead of anything relying on it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BasicAuthKey;
+
+impl BasicAuthKey {
+    /// Username from the request's Basic credentials, if well formed
+    fn username(request: &Request<Body>) -> Option<String> {
+        let value = request.headers().get(AUTHORIZATION)?.to_str().ok()?;
+        let (scheme, credentials) = value.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        let decoded = String::from_utf8(decode_base64(credentials.trim())?).ok()?;
+        let (username, _password) = decoded.split_once(':')?;
+        (!username.is_empty()).then(|| username.to_string())
+    }
+}
+
+impl KeyExtractor for BasicAuthKey {
+    fn extract(&self, request: &Request<Body>) -> String {
+        match Self::username(request) {
+            Some(username) => composite_key(&["basic", &username]),
+            None => IpPathKey.extract(request),
+        }
+    }
+}
+
+/// Decode standard padded or unpadded base64, `None` if malformed
+fn decode_base64(input: &str) -> Option<Vec<u8>> {
+    fn sextet(c: u8) -> Option<u32> {
+        match c {
+            b'A'..=b'Z' => Some(u32::from(c - b'A')),
+            b'a'..=b'z' => Some(u32::from(c - b'a') + 26),
+            b'0'..=b'9' => Some(u32::from(c - b'0') + 52),
+            b'+' => Some(62),
+            b'/' => Some(63),
+            _ => None,
+        }
+    }
+
+    let input = input.trim_end_matches('=').as_bytes();
+    if input.len() % 4 == 1 {
+        return None;
+    }
+    let mut out = Vec::with_capacity(input.len() * 3 / 4);
+    for chunk in input.chunks(4) {
+        let mut bits = 0u32;
+        for (i, &c) in chunk.iter().enumerate() {
+            bits |= sextet(c)? << (18 - 6 * i);
+        }
+        let bytes = bits.to_be_bytes();
+        out.extend_from_slice(&bytes[1..chunk.len()]);
+    }
+    Some(out)
+}
+
+/// Client certificate fingerprint set by a TLS-terminating layer, read by
+/// [`ClientCertKey`]
+///
+/// Insert it into the request extensions after verifying the client
+/// certificate, e.g. as the hex SHA-256 digest of the DER encoding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientCertFingerprint(pub String);
+
+/// Extractor keying on the client certificate, for mTLS APIs
+///
+/// The fingerprint comes from a [`ClientCertFingerprint`] extension, or from
+/// a header when the TLS terminator is a separate proxy (see
+/// [`ClientCertKey::with_header`]). Requests without a fingerprint fall back
+/// to client IP and path. Only read the header if the proxy strips it from
+/// client requests, or clients can pick their own key.
+#[derive(Debug, Clone, Default)]
+pub struct ClientCertKey {
+    header: Option<HeaderName>,
+}
+
+impl ClientCertKey {
+    /// Key on the [`ClientCertFingerprint`] extension
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also accept the fingerprint from a header set by a TLS-terminating
+    /// proxy, e.g. `x-client-cert-fingerprint`, when the extension is absent
+    pub fn with_header(mut self, name: HeaderName) -> Self {
+        self.header = Some(name);
+        self
+    }
+}
+
+impl KeyExtractor for ClientCertKey {
+    fn extract(&self, request: &Request<Body>) -> String {
+        let from_header = || {
+            self.header.as_ref()
+                .and_then(|name| request.headers().get(name))
+                .and_then(|value| value.to_str().ok())
+        };
+        let fingerprint = request.extensions()
+            .get::<ClientCertFingerprint>()
+            .map(|fingerprint| fingerprint.0.as_str())
+            .or_else(from_header)
+            .filter(|fingerprint| !fingerprint.is_empty());
+
+        match fingerprint {
+            Some(fingerprint) => composite_key(&["cert", fingerprint]),
+            None => IpPathKey.extract(request),
+        }
+    }
+}
+
+/// Extractor keying on an authenticated user id that an earlier auth layer
+/// inserted into the request extensions as a `T`
+///
+/// The key is `user:<id>` with the id formatted through `Display`. `T` is
+/// looked up by type, so use a dedicated newtype (e.g. `struct UserId(u64)`)
+/// rather than a bare `String` or `u64` another layer might also insert.
+/// Requests without the extension fall back to client IP and path when
+/// `fallback_to_ip` is set; otherwise they are all counted against the one
+/// `anonymous` key, so unauthenticated traffic shares a single limit.
+///
+/// # Example
+/// ```rust
+/// use std::fmt;
+/// use pleme_middleware_rate_limit::{user_id_extractor, RateLimitConfig, RateLimiter};
+///
+/// #[derive(Clone)]
+/// struct UserId(u64);
+///
+/// impl fmt::Display for UserId {
+///     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+///         self.0.fmt(f)
+///     }
+/// }
+///
+/// let limiter = RateLimiter::new(RateLimitConfig::default())
+///     .with_key_extractor(user_id_extractor::<UserId>(true));
+/// ```
+pub fn user_id_extractor<T>(fallback_to_ip: bool) -> impl KeyExtractor + Clone
+where
+    T: Display + Clone + Send + Sync + 'static,
+{
+    move |request: &Request<Body>| match request.extensions().get::<T>() {
+        Some(user_id) => composite_key(&["user", &user_id.to_string()]),
+        None if fallback_to_ip => IpPathKey.extract(request),
+        None => ANONYMOUS_KEY.to_string(),
+    }
+}
+
+/// Client IP resolved from the `X-Forwarded-For` chain per
+/// `RateLimitConfig::forwarded_ip`
+///
+/// `rate_limit_middleware` inserts this before extracting the key, so
+/// extractors calling [`client_ip`] see the forwarded address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+/// Client IP from the request's [`ClientIp`] extension, or else its
+/// `ConnectInfo<SocketAddr>` extension
+pub fn client_ip<B>(request: &Request<B>) -> Option<IpAddr> {
+    request.extensions()
+        .get::<ClientIp>()
+        .map(|ClientIp(ip)| *ip)
+        .or_else(|| peer_ip(request))
+}
+
+/// Peer address of the connection
+fn peer_ip<B>(request: &Request<B>) -> Option<IpAddr> {
+    request.extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip())
+}
+
+/// Client address picked from the `X-Forwarded-For` chain, falling back to
+/// the peer address when the chain is too short
+pub(crate) fn forwarded_ip<B>(
+    request: &Request<B>,
+    strategy: ForwardedIpStrategy,
+    trusted_proxies: &[IpAddr],
+) -> Option<IpAddr> {
+    let peer = peer_ip(request);
+    if strategy == ForwardedIpStrategy::Peer {
+        return peer;
+    }
+
+    // Unparseable entries can only come from the client end of the chain
+    let mut chain: Vec<IpAddr> = request.headers()
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|header| header.split(','))
+        .filter_map(|entry| {
+            let entry = entry.trim();
+            entry.parse::<IpAddr>().ok()
+                .or_else(|| entry.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        })
+        .collect();
+    chain.extend(peer);
+
+    match strategy {
+        ForwardedIpStrategy::Peer => peer,
+        ForwardedIpStrategy::LeftMost => chain.first().copied(),
+        ForwardedIpStrategy::RightMostUntrusted => chain.iter().rev()
+            .find(|ip| !trusted_proxies.contains(ip))
+            .or(chain.first())
+            .copied(),
+        ForwardedIpStrategy::Nth(n) => chain.len()
+            .checked_sub(n + 1)
+            .map_or(peer, |index| Some(chain[index])),
+    }
+}
+
+/// Key for the network containing `ip`, e.g. `subnet:203.0.113.0/24`
+///
+/// Prefixes longer than the address are clamped to it.
+pub(crate) fn subnet_key(ip: IpAddr, v4_prefix: u8, v6_prefix: u8) -> String {
+    let (network, prefix) = match ip {
+        IpAddr::V4(ip) => {
+            let prefix = u32::from(v4_prefix.min(32));
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            (IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask)), prefix)
+        }
+        IpAddr::V6(ip) => {
+            let prefix = u32::from(v6_prefix.min(128));
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            (IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask)), prefix)
+        }
+    };
+    composite_key(&["subnet", &format!("{}/{}", network, prefix)])
+}
+
+/// Render a client IP for use in a key
+pub(crate) fn display_ip(ip: Option<IpAddr>) -> String {
+    ip.map_or_else(|| "unknown".to_string(), |ip| ip.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn request(ip: &str, path: &str) -> Request<Body> {
+        let mut request = Request::get(path).body(Body::empty()).unwrap();
+        request.extensions_mut().insert(ClientIp(ip.parse().unwrap()));
+        request
+    }
+
+    #[test]
+    fn crafted_parts_never_collide() {
+        let pairs = [
+            [("1.2.3.4", ":x"), ("1.2.3.4:", "x")],
+            [("a:b", "c"), ("a", "b:c")],
+            [("a%3Ab", "c"), ("a:b", "c")],
+            [("", ":"), (":", "")],
+        ];
+        for [(ip, path), (other_ip, other_path)] in pairs {
+            assert_ne!(composite_key(&[ip, path]), composite_key(&[other_ip, other_path]));
+        }
+        assert_ne!(composite_key(&["a", "b", "c"]), composite_key(&["a", "b:c"]));
+    }
+
+    #[test]
+    fn ip_path_keys_are_distinct_for_distinct_pairs() {
+        let ips = ["1.2.3.4", "::1", "2001:db8::1", "2001:db8::", "::ffff:1.2.3.4"];
+        let paths = ["/x", "/:x", "/1:x", "/%3Ax", "/1.2.3.4:/x", "/db8::1:/x"];
+        let keys: HashSet<String> = ips.iter()
+            .flat_map(|ip| paths.iter().map(move |path| IpPathKey.extract(&request(ip, path))))
+            .collect();
+        assert_eq!(keys.len(), ips.len() * paths.len());
+    }
+
+    #[test]
+    fn session_keys_use_a_stable_hash() {
+        let extractor = SessionCookieKey::new("session_id");
+        let mut with_cookie = request("1.2.3.4", "/x");
+        with_cookie.headers_mut().insert(COOKIE, "theme=dark; session_id=token123".parse().unwrap());
+        assert_eq!(extractor.extract(&with_cookie), "session:4a7c94004dc245e6");
+        assert_eq!(extractor.extract(&request("1.2.3.4", "/x")), "1.2.3.4:/x");
+    }
+
+    #[test]
+    fn user_agent_keys_use_a_stable_hash() {
+        let mut with_agent = request("1.2.3.4", "/x");
+        with_agent.headers_mut().insert(USER_AGENT, "Mozilla/5.0".parse().unwrap());
+        assert_eq!(IpUserAgentKey.extract(&with_agent), "1.2.3.4:ua:e135538c723b5e39");
+        assert_eq!(IpUserAgentKey.extract(&request("1.2.3.4", "/x")), "1.2.3.4:ua:none");
+    }
+
+    #[test]
+    fn header_keys_are_composite() {
+        let extractor = HeaderKey::new(HeaderName::from_static("x-client-id"));
+        let mut with_id = request("1.2.3.4", "/x");
+        with_id.headers_mut().insert("x-client-id", "a:b".parse().unwrap());
+        assert_eq!(extractor.extract(&with_id), "header:x-client-id:a%3Ab");
+
+        // A value can't pose as another header's, or as an IP and path key
+        let mut forged = request("1.2.3.4", "/x");
+        forged.headers_mut().insert("x-client", "id:a%3Ab".parse().unwrap());
+        let other = HeaderKey::new(HeaderName::from_static("x-client"));
+        assert_ne!(other.extract(&forged), extractor.extract(&with_id));
+        assert_eq!(extractor.extract(&request("1.2.3.4", "/x")), "1.2.3.4:/x");
+    }
+}
@@ -0,0 +1,79 @@
+//! Fair sharing of the global limit between keys
+
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Mutex, PoisonError};
+
+use crate::{
+    config::{GlobalFairness, Quota},
+    decision::{LimitTier, RejectionDetail},
+};
+
+/// Admissions per key in the current fairness window, see
+/// `RateLimitConfig::global_fairness`
+pub(crate) struct FairShare<K> {
+    limit: u32,
+    window_secs: u64,
+    /// Admissions in a window after which shares are enforced
+    saturated_at: u32,
+    window: Mutex<FairWindow<K>>,
+}
+
+struct FairWindow<K> {
+    started_at: u64,
+    total: u32,
+    admitted: HashMap<K, u32>,
+}
+
+impl<K: Hash + Eq> FairShare<K> {
+    pub(crate) fn new(global: Quota, fairness: GlobalFairness) -> Self {
+        let saturation = fairness.saturation.clamp(0.0, 1.0);
+        Self {
+            limit: global.max_requests,
+            window_secs: global.window_secs.max(1),
+            saturated_at: (global.max_requests as f64 * saturation) as u32,
+            window: Mutex::new(FairWindow {
+                started_at: 0,
+                total: 0,
+                admitted: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Count an admission for `key` at `now`, or refuse it with the seconds
+    /// until the window ends when the window is saturated and the key has
+    /// used its share
+    pub(crate) fn admit<Q>(&self, key: &Q, now: u64) -> Result<(), (RejectionDetail, u64)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        let mut window = self.window.lock().unwrap_or_else(PoisonError::into_inner);
+        if now >= window.started_at.saturating_add(self.window_secs) {
+            window.started_at = now;
+            window.total = 0;
+            window.admitted.clear();
+        }
+
+        let used = window.admitted.get(key).copied().unwrap_or(0);
+        if window.total >= self.saturated_at {
+            // A key's first request makes it one of the active keys
+            let active_keys = window.admitted.len() as u32 + u32::from(used == 0);
+            let share = self.limit.div_ceil(active_keys.max(1));
+            if used >= share {
+                let detail = RejectionDetail::FairShare { tier: LimitTier::Global, share, active_keys };
+                return Err((detail, window.started_at + self.window_secs - now));
+            }
+        }
+
+        window.total += 1;
+        match window.admitted.get_mut(key) {
+            Some(admitted) => *admitted += 1,
+            None => {
+                window.admitted.insert(key.to_owned(), 1);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,116 @@
+//! Lock-free admission for keys well below their limit
+
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+/// Marks a slot as held by the slow path, which blocks fast admission
+const BLOCKED: u32 = u32::MAX;
+
+/// Per-key counters consulted before taking the limiter lock
+///
+/// `state` packs two counts: the high half is the key's attempt count (its
+/// in-window attempts at the last locked visit plus fast admissions since) and
+/// the low half is the fast admissions not yet written to the attempt window.
+/// Requests are admitted by a single compare-and-swap while the count is below
+/// `ceiling`, so fast admissions can never push a key past its limit. Expired
+/// attempts are only pruned on the locked path, which keeps the count
+/// conservative. Pending admissions are recorded at `last_admit`, which is never
+/// earlier than when they happened, so they never expire too soon.
+#[derive(Debug)]
+pub(crate) struct FastSlot {
+    state: AtomicU64,
+    ceiling: AtomicU32,
+    limit: AtomicU32,
+    last_admit: AtomicU64,
+}
+
+fn pack(count: u32, pending: u32) -> u64 {
+    ((count as u64) << 32) | pending as u64
+}
+
+fn unpack(state: u64) -> (u32, u32) {
+    ((state >> 32) as u32, state as u32)
+}
+
+impl FastSlot {
+    /// Create a slot publishing `count` attempts against `ceiling`, for a key
+    /// limited to `limit` requests
+    pub(crate) fn new(count: u32, ceiling: u32, limit: u32) -> Self {
+        Self {
+            state: AtomicU64::new(pack(count, 0)),
+            ceiling: AtomicU32::new(ceiling),
+            limit: AtomicU32::new(limit),
+            last_admit: AtomicU64::new(0),
+        }
+    }
+
+    /// Try to admit a request at `now`, returning the requests remaining
+    pub(crate) fn try_admit(&self, now: u64) -> Option<u32> {
+        // Stamp before admitting so a concurrent drain never records this
+        // admission earlier than it happened
+        self.last_admit.fetch_max(now, Ordering::AcqRel);
+
+        let previous = self.state
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |state| {
+                // Read after `state` so it pairs with the count published by `release`
+                let ceiling = self.ceiling.load(Ordering::Acquire);
+                let (count, pending) = unpack(state);
+                (count < ceiling).then(|| pack(count + 1, pending + 1))
+            })
+            .ok()?;
+
+        let count = unpack(previous).0 + 1;
+        Some(self.limit.load(Ordering::Acquire).saturating_sub(count))
+    }
+
+    /// Block fast admission and take the pending admissions
+    ///
+    /// Returns the number of pending admissions and the timestamp to record
+    /// them at. The slot stays blocked until [`FastSlot::release`].
+    pub(crate) fn drain(&self) -> (u32, u64) {
+        let previous = self.state.swap(pack(BLOCKED, 0), Ordering::AcqRel);
+        let (_, pending) = unpack(previous);
+        (pending, self.last_admit.load(Ordering::Acquire))
+    }
+
+    /// Publish the key's current attempt count and limit, and re-enable fast
+    /// admission below `ceiling` (zero keeps every request on the locked path)
+    pub(crate) fn release(&self, count: u32, ceiling: u32, limit: u32) {
+        self.ceiling.store(ceiling, Ordering::Release);
+        self.limit.store(limit, Ordering::Release);
+        self.state.store(pack(count, 0), Ordering::Release);
+    }
+
+    /// Pending admissions and the timestamp they'd be recorded at, without
+    /// taking them
+    pub(crate) fn peek(&self) -> (u32, u64) {
+        (self.pending(), self.last_admit.load(Ordering::Acquire))
+    }
+
+    /// Admissions not yet written to the attempt window
+    pub(crate) fn pending(&self) -> u32 {
+        let (count, pending) = unpack(self.state.load(Ordering::Acquire));
+        if count == BLOCKED { 0 } else { pending }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn concurrent_admissions_stop_at_the_ceiling() {
+        let slot = Arc::new(FastSlot::new(0, 1000, 1000));
+        let threads: Vec<_> = (0..16)
+            .map(|_| {
+                let slot = Arc::clone(&slot);
+                thread::spawn(move || (0..200).filter(|_| slot.try_admit(1).is_some()).count())
+            })
+            .collect();
+
+        let admitted: usize = threads.into_iter().map(|thread| thread.join().unwrap()).sum();
+        assert_eq!(admitted, 1000);
+        assert_eq!(slot.drain(), (1000, 1));
+    }
+}
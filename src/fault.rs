@@ -0,0 +1,112 @@
+//! Store failures on command, for testing
+
+use std::hash::{BuildHasher, RandomState};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::{
+    config::Quota,
+    error::RateLimitError,
+    store::{RateLimitStore, StoreFuture},
+};
+
+/// [`RateLimitStore`] wrapper failing some of its operations, to test how a
+/// limiter behaves when its store is unavailable
+///
+/// Operations fail with `RateLimitError::Store` while failing is switched on
+/// with [`set_failing`](Self::set_failing), once `fail_after` operations have
+/// been passed through, or at random with probability `failure_rate`;
+/// otherwise they go to the wrapped store. A limiter whose store fails allows
+/// the request and logs the error.
+///
+/// # Example
+/// ```rust
+/// use pleme_middleware_rate_limit::{FaultInjectingStore, MemoryStore, RateLimitConfig, RateLimiter};
+///
+/// # async fn example() {
+/// let config = RateLimitConfig {
+///     max_requests_per_window: 1,
+///     ..RateLimitConfig::default()
+/// };
+/// let limiter = RateLimiter::new(config).with_store(FaultInjectingStore::new(MemoryStore::new()).with_fail_after(1));
+///
+/// assert!(limiter.check("client").await.allowed);
+/// // Over the limit, but the store now fails and the request is let through
+/// assert!(limiter.check("client").await.allowed);
+/// # }
+/// ```
+pub struct FaultInjectingStore<S> {
+    inner: S,
+    failing: AtomicBool,
+    fail_after: Option<u64>,
+    failure_rate: f64,
+    calls: AtomicU64,
+    seed: RandomState,
+}
+
+impl<S: RateLimitStore> FaultInjectingStore<S> {
+    /// Wrap `inner`, passing every operation through until told otherwise
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            failing: AtomicBool::new(false),
+            fail_after: None,
+            failure_rate: 0.0,
+            calls: AtomicU64::new(0),
+            seed: RandomState::new(),
+        }
+    }
+
+    /// Fail every operation after the first `calls`
+    pub fn with_fail_after(mut self, calls: u64) -> Self {
+        self.fail_after = Some(calls);
+        self
+    }
+
+    /// Fail each operation with probability `rate`, from 0 to 1
+    pub fn with_failure_rate(mut self, rate: f64) -> Self {
+        self.failure_rate = rate;
+        self
+    }
+
+    /// Fail every operation while `failing` is set, e.g. to simulate an
+    /// outage part way through a test
+    pub fn set_failing(&self, failing: bool) {
+        self.failing.store(failing, Ordering::Release);
+    }
+
+    /// Operations attempted so far, failed or not
+    pub fn calls(&self) -> u64 {
+        self.calls.load(Ordering::Acquire)
+    }
+
+    /// Wrapped store
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Count an operation and decide whether it fails
+    fn fault(&self) -> Option<RateLimitError> {
+        let call = self.calls.fetch_add(1, Ordering::AcqRel);
+        let random = self.seed.hash_one(call) as f64 / u64::MAX as f64;
+        let fails = self.failing.load(Ordering::Acquire)
+            || self.fail_after.is_some_and(|after| call >= after)
+            || random < self.failure_rate;
+        fails.then(|| RateLimitError::Store("injected fault".to_string()))
+    }
+}
+
+impl<S: RateLimitStore> RateLimitStore for FaultInjectingStore<S> {
+    fn increment<'a>(&'a self, key: &'a str, hits: u32, quota: Quota) -> StoreFuture<'a, u32> {
+        match self.fault() {
+            Some(e) => Box::pin(async move { Err(e) }),
+            None => self.inner.increment(key, hits, quota),
+        }
+    }
+
+    fn flush(&self) -> StoreFuture<'_, ()> {
+        match self.fault() {
+            Some(e) => Box::pin(async move { Err(e) }),
+            None => self.inner.flush(),
+        }
+    }
+}
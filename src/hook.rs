@@ -0,0 +1,30 @@
+//! Hook deciding whether an allowed request's attempt is kept
+
+use crate::store::StoreFuture;
+
+/// Called with the key of each request the limit allows, before its attempt
+/// is kept, e.g. to charge an external quota meter
+///
+/// Register it with `RateLimiter::with_record_hook`. Returning `Ok(false)`
+/// vetoes the request: the attempts it was charged are given back at every
+/// level and the check is rejected with `DecisionReason::Vetoed`. Errors are logged and keep the attempt.
+///
+/// # Example
+/// ```rust
+/// use pleme_middleware_rate_limit::{RecordHook, StoreFuture};
+///
+/// struct Meter;
+///
+/// impl RecordHook for Meter {
+///     fn before_record<'a>(&'a self, key: &'a str) -> StoreFuture<'a, bool> {
+///         Box::pin(async move {
+///             // Charge the meter for `key` here, refusing when it's spent
+///             Ok(!key.starts_with("suspended-"))
+///         })
+///     }
+/// }
+/// ```
+pub trait RecordHook: Send + Sync {
+    /// Whether to keep the attempt the limit just allowed for `key`
+    fn before_record<'a>(&'a self, key: &'a str) -> StoreFuture<'a, bool>;
+}
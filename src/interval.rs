@@ -0,0 +1,55 @@
+//! Minimum spacing between a key's requests
+
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Time each key was last let through under `min_interval_ms`
+pub(crate) struct MinIntervals<K> {
+    last_request: Mutex<HashMap<K, Instant>>,
+    interval: Duration,
+}
+
+impl<K: Hash + Eq> MinIntervals<K> {
+    pub(crate) fn new(interval: Duration) -> Self {
+        Self {
+            last_request: Mutex::new(HashMap::new()),
+            interval,
+        }
+    }
+
+    pub(crate) fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Let a request for `key` through, returning how long it must first
+    /// wait, or how long until it could pass when that exceeds `max_wait`
+    ///
+    /// A request that waits takes the slot it waits for, so concurrent
+    /// waiters are spaced out rather than released together.
+    pub(crate) fn admit<Q>(&self, key: &Q, now: Instant, max_wait: Duration) -> Result<Duration, Duration>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        let mut last_request = self.last_request.lock().unwrap_or_else(PoisonError::into_inner);
+        let wait = last_request.get(key)
+            .and_then(|last| last.checked_add(self.interval))
+            .map_or(Duration::ZERO, |next| next.saturating_duration_since(now));
+        if wait > max_wait {
+            return Err(wait);
+        }
+        last_request.insert(key.to_owned(), now + wait);
+        Ok(wait)
+    }
+
+    /// Forget keys whose last request is more than an interval ago
+    pub(crate) fn cleanup(&self, now: Instant) {
+        let interval = self.interval;
+        self.last_request.lock().unwrap_or_else(PoisonError::into_inner)
+            .retain(|_, last| now.saturating_duration_since(*last) < interval);
+    }
+}
@@ -0,0 +1,63 @@
+//! Per-key leaky buckets delaying requests to a steady rate
+
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::config::LeakyBucket;
+
+/// Longest interval between requests, for leak rates near zero
+const MAX_INTERVAL: Duration = Duration::from_secs(365 * 86_400);
+
+/// Bucket state for every key that has requests in flight through its bucket
+///
+/// Each key stores only the instant its bucket drains: requests leave one
+/// `1 / leak_rate` interval apart, so the time until then gives the number of
+/// requests still in the bucket.
+pub(crate) struct LeakyBuckets<K> {
+    drained_at: Mutex<HashMap<K, Instant>>,
+    interval: Duration,
+    capacity: u32,
+}
+
+impl<K: Hash + Eq> LeakyBuckets<K> {
+    pub(crate) fn new(bucket: LeakyBucket) -> Self {
+        Self {
+            drained_at: Mutex::new(HashMap::new()),
+            interval: Duration::try_from_secs_f64(1.0 / bucket.leak_rate)
+                .map_or(MAX_INTERVAL, |interval| interval.min(MAX_INTERVAL)),
+            capacity: bucket.bucket_capacity,
+        }
+    }
+
+    /// Put a request for `key` in its bucket, returning how long it must wait
+    /// to leave and the room left behind it, or the seconds until the
+    /// overflowing bucket has room
+    pub(crate) fn enqueue<Q>(&self, key: &Q, now: Instant) -> Result<(Duration, u32), u64>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        let mut drained_at = self.drained_at.lock().unwrap_or_else(PoisonError::into_inner);
+        let ahead = drained_at.get(key).map_or(Duration::ZERO, |at| at.saturating_duration_since(now));
+        let depth = ahead.as_nanos().div_ceil(self.interval.as_nanos().max(1));
+        let depth = u32::try_from(depth).unwrap_or(u32::MAX);
+
+        if depth >= self.capacity {
+            let room_at = ahead.saturating_sub(self.interval.saturating_mul(self.capacity.saturating_sub(1)));
+            return Err(room_at.as_secs_f64().ceil().max(1.0) as u64);
+        }
+
+        let departs = now + ahead;
+        drained_at.insert(key.to_owned(), departs.checked_add(self.interval).unwrap_or(departs));
+        Ok((ahead, self.capacity - depth - 1))
+    }
+
+    /// Forget keys whose buckets have drained
+    pub(crate) fn cleanup(&self, now: Instant) {
+        self.drained_at.lock().unwrap_or_else(PoisonError::into_inner).retain(|_, at| now < *at);
+    }
+}
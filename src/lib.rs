@@ -3,34 +3,145 @@
 //! Provides flexible rate limiting for API endpoints to prevent abuse:
 //! - General API rate limiting (IP + path based)
 //! - Login-specific rate limiting with account lockout
+//! - Temporary bans for keys that repeatedly exceed limits
 //! - Configurable time windows and limits
 //! - Automatic cleanup of old entries
 //!
+//! # Time
+//!
+//! Windows are computed on a monotonic clock, so wall-clock adjustments don't
+//! distort them. Timestamps given to callers (`RateLimitError::AccountLocked`,
+//! `RateLimitError::Banned`, `RateLimitStatus::banned_until`) are unix
+//! wall-clock seconds.
+//!
 //! # Example
 //! ```rust
 //! use pleme_middleware_rate_limit::{RateLimiter, RateLimitConfig};
 //! use axum::{Router, routing::get};
 //!
+//! # async fn handler() {}
 //! let config = RateLimitConfig::default();
 //! let limiter = RateLimiter::new(config);
 //!
-//! let app = Router::new()
+//! let app: Router = Router::new()
 //!     .route("/api/endpoint", get(handler))
 //!     .layer(axum::middleware::from_fn_with_state(
 //!         limiter.clone(),
 //!         pleme_middleware_rate_limit::rate_limit_middleware
 //!     ));
 //! ```
+//!
+//! # Outside Axum
+//!
+//! The limiters only need a key, so they work with any framework through
+//! `RateLimiter::check_rate_limit`. tonic interceptors are synchronous, so a
+//! gRPC service keyed on an `x-client-id` metadata entry blocks on the check:
+//!
+//! ```rust,ignore
+//! use tonic::{Request, Status};
+//!
+//! let interceptor = move |request: Request<()>| {
+//!     let key = request.metadata()
+//!         .get("x-client-id")
+//!         .and_then(|value| value.to_str().ok())
+//!         .unwrap_or("anonymous")
+//!         .to_string();
+//!
+//!     // Requires the multi-threaded runtime
+//!     let result = tokio::task::block_in_place(|| {
+//!         tokio::runtime::Handle::current().block_on(limiter.check_rate_limit(&key))
+//!     });
+//!
+//!     match result {
+//!         Ok(()) => Ok(request),
+//!         Err(e) => Err(Status::resource_exhausted(e.to_string())),
+//!     }
+//! };
+//! ```
+//!
+//! tonic services served through an Axum router can instead use
+//! `rate_limit_middleware` with a [`HeaderKey`] extractor.
 
+#[macro_use]
+mod logging;
 mod limiter;
+mod circuit;
 mod login;
+mod audit;
 mod config;
 mod error;
+mod decision;
+mod clock;
+mod window;
+mod fast_path;
+mod sampler;
+mod leaky;
+mod cost;
+mod interval;
+mod paths;
+mod fair;
+mod metrics;
+mod stats;
+mod region;
+mod resolver;
+mod hook;
+mod store;
+mod memory;
+mod snapshot;
+mod extractor;
+mod sanitize;
+mod user_agent;
+mod telemetry;
+mod combined;
+mod service;
+mod registry;
+#[cfg(feature = "calendar-windows")]
+mod calendar;
+#[cfg(feature = "status-handler")]
+mod status;
+#[cfg(feature = "test-util")]
+mod fault;
 
-pub use limiter::RateLimiter;
-pub use login::LoginRateLimiter;
-pub use config::RateLimitConfig;
-pub use error::RateLimitError;
+pub use limiter::{KeyedRateLimiter, RateLimitKey, RateLimiter, RateLimitPermit, RateLimitStatus};
+pub use login::{LoginAttemptGuard, LoginCheckResult, LoginIdentifier, LoginRateLimiter, LoginSnapshot};
+pub use audit::{LoginAuditEvent, LoginAuditSink, LoginEventKind};
+pub use config::{
+    ContendedPolicy, ControlCharPolicy, CostBucket, DistinctPathAction, DrainPolicy, EndpointGroup, EvictionPolicy,
+    ForwardedIpStrategy, FutureTimestampPolicy, GlobalFairness, KeyQuery, LeakyBucket, LoginActionLimit,
+    LoginBodyFormat, OptionsPolicy, PathNormalization, PolicyHeader, PriorityLevel, Quota, RateLimitConfig,
+    RateLimitHeaders, RateLimitMode, RefundPolicy, RequestPriority, RetryAfterFormat, UserAgentAction, UserAgentRule,
+};
+pub use region::RegionResolver;
+pub use resolver::LimitResolver;
+pub use hook::RecordHook;
+pub use extractor::{
+    client_ip, composite_key, user_id_extractor, BasicAuthKey, ClientCertFingerprint, ClientCertKey, ClientIp,
+    HeaderKey, IpPathKey, IpUserAgentKey, KeyExtractor, NormalizedIpPathKey, SessionCookieKey,
+};
+pub use sanitize::key_fingerprint;
+pub use store::{LayeredStore, MemoryStore, RateLimitStore, StoreFuture};
+pub use memory::{InMemoryStore, MutexMap, ShardedMap};
+#[cfg(feature = "dashmap")]
+pub use memory::ConcurrentMap;
+pub use error::{ParseQuotaError, RateLimitError, SnapshotError};
+pub use snapshot::{BinaryCodec, JsonCodec, Snapshot, SnapshotCodec};
+pub use circuit::CircuitState;
+pub use stats::RateLimitStats;
+pub use decision::{DecisionReason, LimitTier, RateLimitDecision, RejectionDetail, ThrottleLevel};
+pub use combined::CombinedRateLimiter;
+pub use service::{BoxError, RateLimitLayer, RateLimitRejection, RateLimitService};
+pub use registry::{RateLimiterRegistry, RegistryLayer};
+#[cfg(feature = "calendar-windows")]
+pub use calendar::{CalendarPeriod, CalendarQuota, CalendarRateLimiter};
+#[cfg(feature = "status-handler")]
+pub use status::{
+    rate_limit_status_handler, registry_status_handler, LimiterSummary, LoginLimiterSummary, RateLimitSummary,
+    RegistrySummary,
+};
+#[cfg(feature = "test-util")]
+pub use fault::FaultInjectingStore;
 
-// Re-export middleware function
+// Re-export middleware functions
 pub use limiter::rate_limit_middleware;
+pub use login::login_rate_limit_middleware;
+pub use combined::combined_rate_limit_middleware;
@@ -1,116 +1,2913 @@
 //! General API rate limiter
 
-use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::borrow::Borrow;
+use std::cmp::Reverse;
+use std::fmt::{Display, Write};
+use std::future::Future;
+use std::hash::{BuildHasher, Hash, RandomState};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex as StdMutex, PoisonError, RwLock};
+use std::time::Duration;
 use std::collections::HashMap;
 use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
 use axum::{
     extract::{ConnectInfo, State},
-    http::{Request, StatusCode},
+    http::{header, Extensions, HeaderMap, HeaderName, HeaderValue, Method, Request, StatusCode},
     middleware::Next,
-    response::Response,
-    body::Body,
+    response::{IntoResponse, Response},
+    body::{self, Body},
 };
-use tracing::warn;
+use tracing::{debug, info, warn, Level};
 
-use crate::{config::RateLimitConfig, error::RateLimitError};
+use crate::{
+    circuit::{CircuitBreakers, CircuitState},
+    clock::{self, Clock},
+    config::{
+        ContendedPolicy, ControlCharPolicy, DrainPolicy, EvictionPolicy, KeyQuery, OptionsPolicy, Quota,
+        RateLimitConfig, RateLimitHeaders, RateLimitMode, RetryAfterFormat, UserAgentAction,
+    },
+    decision::{DecisionReason, LimitTier, RateLimitDecision, RejectionDetail, ThrottleLevel},
+    error::{RateLimitError, SnapshotError},
+    extractor::{client_ip, composite_key, display_ip, forwarded_ip, subnet_key, ClientIp, IpPathKey, KeyExtractor,
+        NormalizedIpPathKey},
+    fast_path::FastSlot,
+    cost::CostBuckets,
+    fair::FairShare,
+    interval::MinIntervals,
+    paths::{PathScans, PathVerdict},
+    leaky::LeakyBuckets,
+    stats::{RateLimitStats, StatsWindow},
+    metrics,
+    region::RegionResolver,
+    hook::RecordHook,
+    resolver::LimitResolver,
+    sampler::Sampler,
+    service::RateLimitRejection,
+    snapshot::{BinaryCodec, SnapshotCodec},
+    sanitize::{fnv1a_bytes, has_control_chars},
+    store::RateLimitStore,
+    telemetry,
+    user_agent::UserAgentRules,
+    window::AttemptWindow,
+};
+
+/// Key types a [`KeyedRateLimiter`] can track
+///
+/// Implemented for every hashable key that can be displayed in logs and
+/// metrics, such as `String`, `u64` or `IpAddr`.
+pub trait RateLimitKey: Hash + Eq + Clone + Display + Send + Sync + 'static {}
+
+impl<K> RateLimitKey for K where K: Hash + Eq + Clone + Display + Send + Sync + 'static {}
+
+/// Key every request counts against under `global_limit`
+const GLOBAL_KEY: &str = "global";
+
+/// Debug header carrying a rejection's [`RejectionDetail`]
+const X_RATELIMIT_DETAIL: HeaderName = HeaderName::from_static("x-ratelimit-detail");
+
+/// Debug header naming the algorithm and config digest, per `debug_header`
+const X_RATELIMIT_DEBUG: HeaderName = HeaderName::from_static("x-ratelimit-debug");
+
+/// Header marking responses passed through by a disabled limiter, per
+/// `disabled_header`
+pub(crate) const X_RATELIMIT_DISABLED: HeaderName = HeaderName::from_static("x-ratelimit-disabled");
+
+/// Buckets used for keys switched off the exact log by `max_stored_attempts`
+/// when `window_buckets` is unset
+const CAPPED_WINDOW_BUCKETS: u32 = 64;
 
-/// Rate limiter state tracking
+/// Rate limiter keyed by strings, as used by [`rate_limit_middleware`]
+pub type RateLimiter = KeyedRateLimiter<String>;
+
+/// Rate limiter state tracking, keyed by `K`
+///
+/// Keys are stored as given, so limiting on `u64` ids or `IpAddr`s directly
+/// avoids formatting them into strings. Methods take any borrowed form of the
+/// key (e.g. `&str` for `String` keys). Store-backed limiting formats keys
+/// with `Display` for the store.
+///
+/// Tracked keys are hashed with `S`, the standard library's SipHash-based
+/// `RandomState` by default. Keys usually come from clients, so a fast
+/// unkeyed hasher lets an attacker pick keys that collide and turn every
+/// check into a linear scan under the limiter lock. Use
+/// [`KeyedRateLimiter::with_hasher`] with a faster hasher only when keys are
+/// trusted, e.g. internal service ids.
+///
+/// # Example
+/// ```rust
+/// use pleme_middleware_rate_limit::{KeyedRateLimiter, RateLimitConfig, RateLimiter};
+///
+/// # async fn example() {
+/// let by_user = KeyedRateLimiter::<u64>::new(RateLimitConfig::default());
+/// assert!(by_user.check(&42).await.allowed);
+///
+/// let by_name = RateLimiter::new(RateLimitConfig::default());
+/// assert!(by_name.check("alice").await.allowed);
+/// # }
+/// ```
 #[derive(Clone)]
-pub struct RateLimiter {
+pub struct KeyedRateLimiter<K, S = RandomState> {
     config: RateLimitConfig,
-    attempts: Arc<Mutex<HashMap<String, Vec<u64>>>>,
+    attempts: Arc<Mutex<HashMap<K, KeyState, S>>>,
+    fast_slots: Arc<RwLock<HashMap<K, Arc<FastSlot>, S>>>,
+    clock: Clock,
+    region_resolver: Option<Arc<dyn RegionResolver>>,
+    limit_resolver: Option<(Arc<dyn LimitResolver>, u64)>,
+    record_hook: Option<Arc<dyn RecordHook>>,
+    limit_cache: Arc<StdMutex<HashMap<K, CachedLimit>>>,
+    circuits: Option<Arc<CircuitBreakers<K>>>,
+    user_agent_rules: Arc<UserAgentRules>,
+    sampler: Option<Arc<Sampler>>,
+    leaky_buckets: Option<Arc<LeakyBuckets<K>>>,
+    cost_buckets: Option<Arc<CostBuckets<K>>>,
+    min_intervals: Option<Arc<MinIntervals<K>>>,
+    path_scans: Option<Arc<PathScans>>,
+    fair_share: Option<Arc<FairShare<K>>>,
+    queued: Arc<StdMutex<HashMap<K, u32>>>,
+    initial_capacity: usize,
+    /// Time of the last expiry scan by a full limiter under
+    /// `EvictionPolicy::Ttl`
+    expiry_scanned_at: Arc<AtomicU64>,
+    advisory_violations: Arc<AtomicU64>,
+    soft_limit_warnings: Arc<AtomicU64>,
+    stats: Arc<StatsWindow>,
+    rejection_level: Level,
+    /// Built `policy_header`, if configured and valid
+    policy_header: Option<(HeaderName, HeaderValue)>,
+    /// Built `debug_header` value, if enabled
+    debug_header: Option<HeaderValue>,
+    draining: Arc<AtomicBool>,
+    /// Clock reading when the limiter was created, for `warm_up_secs`
+    created_at: u64,
+    /// Cleared by the first check after `warm_up_secs` has passed
+    warming_up: Arc<AtomicBool>,
+    store: Option<Arc<dyn RateLimitStore>>,
+    key_extractor: Arc<dyn KeyExtractor>,
 }
 
-impl RateLimiter {
+/// Limit resolver answer for a key
+#[derive(Debug, Clone, Copy)]
+struct CachedLimit {
+    /// Resolved quota, `None` when the resolver had none for the key
+    quota: Option<Quota>,
+    expires_at: u64,
+}
+
+#[derive(Debug)]
+struct KeyState {
+    attempts: AttemptWindow,
+    rejections: Vec<u64>,
+    /// Times of responses with a watched status
+    status_hits: Vec<u64>,
+    banned_until: Option<u64>,
+    /// Quota most recently applied to the key, used when pruning outside a check
+    quota: Quota,
+    /// Quota set for this key through `set_key_quota`
+    override_quota: Option<Quota>,
+    /// Time of the uncounted first request under `always_allow_first`
+    free_request_at: Option<u64>,
+    /// Fraction of an attempt owed back by `refund_policy`, below one
+    refund_credit: f64,
+    /// Time of the latest locked check, for `EvictionPolicy::Lru`
+    last_access: u64,
+    /// Locked checks so far, for `EvictionPolicy::Lfu`
+    accesses: u64,
+}
+
+impl KeyState {
+    fn new(config: &RateLimitConfig, quota: Quota) -> Self {
+        Self {
+            attempts: AttemptWindow::new(quota.window_secs, config.window_buckets_for(quota.window_secs)),
+            rejections: Vec::new(),
+            status_hits: Vec::new(),
+            banned_until: None,
+            quota,
+            override_quota: None,
+            free_request_at: None,
+            refund_credit: 0.0,
+            last_access: 0,
+            accesses: 0,
+        }
+    }
+
+    /// Note a check of the key at `now` for eviction
+    fn touch(&mut self, now: u64) {
+        self.last_access = now;
+        self.accesses = self.accesses.saturating_add(1);
+    }
+
+    /// Whether eviction must leave the key alone: bans and overrides would
+    /// otherwise be lifted by evicting it
+    fn pinned(&self, now: u64) -> bool {
+        self.banned_until.is_some_and(|until| now < until) || self.override_quota.is_some()
+    }
+}
+
+/// Write a fast slot's pending admissions into the key's attempt window
+///
+/// The slot stays blocked until it's released with the updated count.
+fn flush_fast_slot(slot: &FastSlot, state: &mut KeyState) {
+    let (pending, at) = slot.drain();
+    for _ in 0..pending {
+        state.attempts.record(at);
+    }
+}
+
+/// Decisions made by `check_once` during a request, stored in its extensions
+#[derive(Clone)]
+struct RequestDecisions<K>(HashMap<K, RateLimitDecision<K>>);
+
+impl<K> Default for RequestDecisions<K> {
+    fn default() -> Self {
+        Self(HashMap::new())
+    }
+}
+
+/// Place in a key's delay queue, released on drop
+struct QueueGuard<K: Hash + Eq> {
+    queued: Arc<StdMutex<HashMap<K, u32>>>,
+    key: K,
+}
+
+impl<K: Hash + Eq + Clone> QueueGuard<K> {
+    /// Join the key's queue unless `max_depth` callers are already waiting
+    fn enter(queued: &Arc<StdMutex<HashMap<K, u32>>>, key: K, max_depth: u32) -> Option<Self> {
+        let mut depths = queued.lock().unwrap_or_else(PoisonError::into_inner);
+        // Refused callers leave no entry behind, even with a depth of 0
+        if depths.get(&key).copied().unwrap_or(0) >= max_depth {
+            return None;
+        }
+        *depths.entry(key.clone()).or_insert(0) += 1;
+
+        Some(Self {
+            queued: Arc::clone(queued),
+            key,
+        })
+    }
+}
+
+impl<K: Hash + Eq> Drop for QueueGuard<K> {
+    fn drop(&mut self) {
+        let mut depths = self.queued.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(depth) = depths.get_mut(&self.key) {
+            *depth -= 1;
+            if *depth == 0 {
+                depths.remove(&self.key);
+            }
+        }
+    }
+}
+
+/// Current rate limit state for a key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateLimitStatus {
+    /// Requests recorded in the current window
+    pub attempts: u32,
+    /// Requests remaining in the current window
+    pub remaining: u32,
+    /// Unix timestamp until which the key is banned
+    pub banned_until: Option<u64>,
+    /// State of the key's circuit breaker, `Closed` when none is configured
+    pub circuit: CircuitState,
+}
+
+/// Slot granted by [`KeyedRateLimiter::acquire`]
+///
+/// The attempt is recorded when the permit is granted, so dropping the permit
+/// doesn't give the slot back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[must_use]
+pub struct RateLimitPermit<K = String> {
+    /// Key the slot was taken from
+    pub key: K,
+    /// Requests remaining in the current window
+    pub remaining: u32,
+}
+
+impl<K: RateLimitKey> KeyedRateLimiter<K> {
     /// Create new rate limiter
     pub fn new(config: RateLimitConfig) -> Self {
+        Self::with_capacity(config, 0)
+    }
+
+    /// Create new rate limiter with room for `capacity` keys before rehashing
+    ///
+    /// With `shrink_on_cleanup`, cleanup never shrinks below this capacity.
+    pub fn with_capacity(config: RateLimitConfig, capacity: usize) -> Self {
+        Self::with_capacity_and_hasher(config, capacity, RandomState::new())
+    }
+}
+
+impl<K, S> KeyedRateLimiter<K, S>
+where
+    K: RateLimitKey,
+    S: BuildHasher + Clone + Send + Sync + 'static,
+{
+    /// Create new rate limiter hashing keys with `hasher`
+    ///
+    /// See the type-level docs before replacing the default hasher.
+    pub fn with_hasher(config: RateLimitConfig, hasher: S) -> Self {
+        Self::with_capacity_and_hasher(config, 0, hasher)
+    }
+
+    /// Create new rate limiter with room for `capacity` keys, hashing them
+    /// with `hasher`
+    pub fn with_capacity_and_hasher(config: RateLimitConfig, capacity: usize, hasher: S) -> Self {
+        let circuits = config.circuit_failure_threshold
+            .map(|threshold| {
+                Arc::new(CircuitBreakers::new(threshold, config.circuit_cooldown_secs, config.fingerprint_keys))
+            });
+        let user_agent_rules = Arc::new(UserAgentRules::new(&config.user_agent_rules));
+        let sampler = config.sample_rate
+            .and_then(|rate| Sampler::new(rate, config.sample_per_key))
+            .map(Arc::new);
+        let leaky_buckets = config.leaky_bucket.map(|bucket| Arc::new(LeakyBuckets::new(bucket)));
+        let cost_buckets = config.cost_bucket.map(|bucket| Arc::new(CostBuckets::new(bucket)));
+        let min_intervals = config.min_interval_ms
+            .map(|interval_ms| Arc::new(MinIntervals::new(Duration::from_millis(interval_ms))));
+        let path_scans = config.max_distinct_paths_per_window.map(|limit| {
+            Arc::new(PathScans::new("paths", limit, config.rate_window_secs, config.distinct_paths_action,
+                config.ban_duration_secs))
+        });
+        let clock = Clock::new();
+        let warm_up = config.warm_up_secs.is_some();
+        let fair_share = config.global_limit.zip(config.global_fairness)
+            .map(|(quota, fairness)| Arc::new(FairShare::new(quota, fairness)));
+        let key_extractor = default_key_extractor(&config);
+        config.warn_if_disabled("API");
+        let policy_header = config.policy_header.as_ref().and_then(|policy| {
+            let header = HeaderName::try_from(policy.name.as_str()).ok()
+                .zip(HeaderValue::try_from(policy.value(config.quota())).ok());
+            if header.is_none() {
+                warn!("Ignoring invalid rate limit policy header {}", policy.name);
+            }
+            header
+        });
+        let debug_header = config.debug_header
+            .then(|| format!("algo={};cfg={:016x}", config.algorithm(), config.digest()))
+            .and_then(|value| HeaderValue::try_from(value).ok());
         Self {
+            rejection_level: config.rejection_level(),
+            policy_header,
+            debug_header,
+            circuits,
+            user_agent_rules,
+            sampler,
+            leaky_buckets,
+            cost_buckets,
+            min_intervals,
+            path_scans,
+            fair_share,
+            stats: Arc::new(StatsWindow::new(config.rate_window_secs)),
             config,
-            attempts: Arc::new(Mutex::new(HashMap::new())),
+            attempts: Arc::new(Mutex::new(HashMap::with_capacity_and_hasher(capacity, hasher.clone()))),
+            fast_slots: Arc::new(RwLock::new(HashMap::with_hasher(hasher))),
+            clock,
+            region_resolver: None,
+            limit_resolver: None,
+            record_hook: None,
+            limit_cache: Arc::new(StdMutex::new(HashMap::new())),
+            queued: Arc::new(StdMutex::new(HashMap::new())),
+            initial_capacity: capacity,
+            expiry_scanned_at: Arc::new(AtomicU64::new(0)),
+            advisory_violations: Arc::new(AtomicU64::new(0)),
+            soft_limit_warnings: Arc::new(AtomicU64::new(0)),
+            draining: Arc::new(AtomicBool::new(false)),
+            created_at: clock.now(),
+            warming_up: Arc::new(AtomicBool::new(warm_up)),
+            store: None,
+            key_extractor,
         }
     }
 
+    /// Count attempts in an external store instead of in process memory
+    ///
+    /// Bans, per-key quota overrides and the lock-free fast path only apply to
+    /// in-memory limiting. If the store fails, the request is allowed and the
+    /// error logged.
+    pub fn with_store(mut self, store: impl RateLimitStore + 'static) -> Self {
+        self.store = Some(Arc::new(store));
+        self
+    }
+
+    /// Apply per-region limits from `region_limits` using the given resolver
+    ///
+    /// Requests whose IP resolves to no region, or to a region without a
+    /// configured limit, use the default limit.
+    pub fn with_region_resolver(mut self, resolver: impl RegionResolver + 'static) -> Self {
+        self.region_resolver = Some(Arc::new(resolver));
+        self
+    }
+
+    /// Look up each key's quota with `resolver`, caching answers for `ttl`
+    ///
+    /// The resolver is called when a key isn't cached or its entry has
+    /// expired, and `None` answers are cached too, so each key costs at most
+    /// one lookup per `ttl`. Concurrent misses on one key may each call the
+    /// resolver. If it fails, the check uses its default quota and the miss
+    /// isn't cached. After changing a key's plan, call
+    /// [`KeyedRateLimiter::invalidate_limit`] for it to apply before the entry
+    /// expires. Quotas set with `set_key_quota` take precedence.
+    pub fn with_limit_resolver(mut self, resolver: impl LimitResolver + 'static, ttl: Duration) -> Self {
+        self.limit_resolver = Some((Arc::new(resolver), ttl.as_secs()));
+        self
+    }
+
+    /// Consult `hook` for each request the limit allows, after its attempt is
+    /// recorded and the state lock released
+    ///
+    /// The hook runs once the limit has decided, so it never sees rejected
+    /// requests, and the recorded attempt counts against concurrent checks
+    /// while it runs. A veto gives back every attempt the request was
+    /// charged, at its key and at subnet, global or other enclosing levels;
+    /// only attempts counted in an external store are kept. The hook runs
+    /// before circuit breakers count the decision, so vetoes count as
+    /// neither successes nor rejections. `try_check` never calls it.
+    pub fn with_record_hook(mut self, hook: impl RecordHook + 'static) -> Self {
+        self.record_hook = Some(Arc::new(hook));
+        self
+    }
+
     /// Check if request should be rate limited
-    pub async fn check_rate_limit(&self, key: &str) -> Result<(), RateLimitError> {
-        if !self.config.enabled {
+    pub async fn check_rate_limit<Q>(&self, key: &Q) -> Result<(), RateLimitError>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        let decision = self.check(key).await;
+        self.decision_result(&decision)
+    }
+
+    /// [`KeyedRateLimiter::check_rate_limit`] reporting which limit rejected
+    /// the key, for debugging unexpected rejections
+    ///
+    /// The rejection's `detail` is set for limit and ban rejections, and its
+    /// `Display` includes it.
+    pub async fn check_rate_limit_detailed<Q>(&self, key: &Q) -> Result<(), RateLimitRejection>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        let decision = self.check(key).await;
+        if decision.allowed {
             return Ok(());
         }
+        Err(decision.into())
+    }
+
+    /// Check a key at most once per request, reusing an earlier decision for
+    /// it from the request's extensions
+    ///
+    /// `rate_limit_middleware` and [`RateLimitLayer`](crate::RateLimitLayer)
+    /// insert their [`RateLimitDecision`] into the request extensions, so a
+    /// handler checking the key the middleware already counted gets that
+    /// decision back instead of recording a second attempt. Decisions made
+    /// here are stored in the extensions too, so repeated calls for one key
+    /// within a request count once. Decisions are matched by key alone, so
+    /// limiters sharing a key type in one request should use distinct keys.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum::{body::Body, http::Request};
+    /// use pleme_middleware_rate_limit::{RateLimitConfig, RateLimiter};
+    ///
+    /// # async fn example(limiter: RateLimiter, mut request: Request<Body>) {
+    /// let first = limiter.check_once(request.extensions_mut(), "export").await;
+    /// let second = limiter.check_once(request.extensions_mut(), "export").await;
+    /// assert_eq!(first, second);
+    /// # }
+    /// ```
+    pub async fn check_once<Q>(&self, extensions: &mut Extensions, key: &Q) -> RateLimitDecision<K>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        let earlier = extensions.get::<RateLimitDecision<K>>()
+            .filter(|decision| decision.key.borrow() == key)
+            .or_else(|| extensions.get::<RequestDecisions<K>>().and_then(|decisions| decisions.0.get(key)));
+        if let Some(decision) = earlier {
+            return decision.clone();
+        }
+
+        let decision = self.check(key).await;
+        extensions.get_or_insert_default::<RequestDecisions<K>>().0.insert(key.to_owned(), decision.clone());
+        decision
+    }
+
+    /// [`KeyedRateLimiter::check_rate_limit`] counting a key at most once per
+    /// request, see [`KeyedRateLimiter::check_once`]
+    pub async fn check_rate_limit_once<Q>(&self, extensions: &mut Extensions, key: &Q) -> Result<(), RateLimitError>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        let decision = self.check_once(extensions, key).await;
+        self.decision_result(&decision)
+    }
+
+    /// Wait up to `timeout` for a free slot on a key and take it
+    ///
+    /// This is a client-side throttle independent of Axum, e.g. for outbound
+    /// calls to a third-party API: configure the limiter with the partner's
+    /// documented rate and acquire before each call. Waiters poll in whole
+    /// seconds, so the wait can overrun a slot by up to a second.
+    ///
+    /// # Example
+    /// ```rust
+    /// use std::time::Duration;
+    /// use pleme_middleware_rate_limit::{RateLimitConfig, RateLimiter};
+    ///
+    /// # async fn example() -> Result<(), pleme_middleware_rate_limit::RateLimitError> {
+    /// // Partner allows 600 requests per minute
+    /// let throttle = RateLimiter::new(RateLimitConfig {
+    ///     max_requests_per_window: 600,
+    ///     rate_window_secs: 60,
+    ///     ..Default::default()
+    /// });
+    ///
+    /// let _permit = throttle.acquire("partner-api", Duration::from_secs(30)).await?;
+    /// // call the partner API
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn acquire<Q>(&self, key: &Q, timeout: Duration) -> Result<RateLimitPermit<K>, RateLimitError>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        let quota = self.resolved_quota(key, self.config.quota()).await;
+        let decision = self.check_with_delay(key, quota, timeout, u32::MAX).await;
+        self.decision_result(&decision)?;
+        Ok(RateLimitPermit {
+            key: decision.key,
+            remaining: decision.remaining,
+        })
+    }
+
+    /// Convert a decision into the error reported for it, if rejected
+    ///
+    /// Limit rejections describe the quota that rejected the request, such as
+    /// a key override or an enclosing level, not the configured default.
+    fn decision_result(&self, decision: &RateLimitDecision<K>) -> Result<(), RateLimitError> {
+        match decision.reason {
+            DecisionReason::Exceeded => {
+                let message = match (decision.detail, decision.limited_by) {
+                    (Some(RejectionDetail::Limit { limit, window_secs, .. }), _) => {
+                        exceeded_message(Quota { max_requests: limit, window_secs })
+                    }
+                    (Some(detail), _) => detail.to_string(),
+                    (None, Some((_, quota))) => exceeded_message(quota),
+                    (None, None) => exceeded_message(self.config.quota()),
+                };
+                Err(RateLimitError::Exceeded(message))
+            }
+            DecisionReason::Banned(banned_until) => Err(RateLimitError::Banned(banned_until)),
+            DecisionReason::CircuitOpen => Err(RateLimitError::Exceeded(
+                "Too many rejected requests; retry after the cooldown".to_string()
+            )),
+            DecisionReason::Draining if !decision.allowed => Err(RateLimitError::Draining),
+            DecisionReason::InvalidKey => Err(RateLimitError::InvalidKey),
+            DecisionReason::Blocked => Err(RateLimitError::Blocked),
+            DecisionReason::Contended if !decision.allowed => Err(RateLimitError::Contended),
+            DecisionReason::Vetoed => Err(RateLimitError::Vetoed),
+            DecisionReason::WithinLimit | DecisionReason::Disabled | DecisionReason::WarmingUp
+                | DecisionReason::Exempt | DecisionReason::Draining | DecisionReason::Unsampled
+                | DecisionReason::Contended => Ok(()),
+        }
+    }
+
+    /// Check a key and record the attempt if allowed, returning the full decision
+    pub async fn check<Q>(&self, key: &Q) -> RateLimitDecision<K>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        let quota = self.resolved_quota(key, self.config.quota()).await;
+        self.check_with_quota(key, quota).await
+    }
+
+    /// Quota from the limit resolver for a key, or `default` without one
+    async fn resolved_quota<Q>(&self, key: &Q, default: Quota) -> Quota
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        let Some((resolver, ttl)) = &self.limit_resolver else {
+            return default;
+        };
+
+        let now = self.clock.now();
+        let cached = self.limit_cache.lock().unwrap_or_else(PoisonError::into_inner)
+            .get(key)
+            .filter(|cached| now < cached.expires_at)
+            .map(|cached| cached.quota);
+        if let Some(quota) = cached {
+            return quota.unwrap_or(default);
+        }
+
+        match resolver.resolve(&key.to_string()).await {
+            Ok(quota) => {
+                self.limit_cache.lock().unwrap_or_else(PoisonError::into_inner)
+                    .insert(key.to_owned(), CachedLimit { quota, expires_at: now.saturating_add(*ttl) });
+                quota.unwrap_or(default)
+            }
+            Err(e) => {
+                warn!("Limit resolver error for key {}: {}", self.config.log_key(key), e);
+                default
+            }
+        }
+    }
+
+    /// Drop a key's cached resolver answer, e.g. after its plan changes
+    pub fn invalidate_limit<Q>(&self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.limit_cache.lock().unwrap_or_else(PoisonError::into_inner).remove(key);
+    }
+
+    /// Check a key against an explicit quota instead of the configured default
+    pub async fn check_with_quota<Q>(&self, key: &Q, quota: Quota) -> RateLimitDecision<K>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        self.with_circuit(key, self.with_min_interval(key, self.with_veto(key, self.check_key(key, quota)))).await
+    }
+
+    /// Run a check through the key's circuit breaker, if one is configured
+    async fn with_circuit<Q, F>(&self, key: &Q, check: F) -> RateLimitDecision<K>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+        F: Future<Output = RateLimitDecision<K>>,
+    {
+        let Some(circuits) = &self.circuits else {
+            return self.counted(key, check.await);
+        };
+
+        let now = self.clock.now();
+        if let Err(retry_after) = circuits.admit(key, now) {
+            let decision = RateLimitDecision::reject(key.to_owned(), DecisionReason::CircuitOpen, Some(retry_after));
+            return self.counted(key, decision);
+        }
+
+        let decision = check.await;
+        record_circuit(circuits, key, &decision, now);
+        self.counted(key, decision)
+    }
+
+    /// Check a key against the default quota without ever waiting, returning
+    /// a `contended_policy` decision when that isn't possible
+    ///
+    /// The check is made if the state lock is free (or the fast path admits
+    /// the key without it); otherwise the request is allowed unrecorded or
+    /// rejected with [`DecisionReason::Contended`], so latency stays bounded
+    /// at the cost of an imprecise decision under heavy contention.
+    /// Store-backed limiters and keys whose quota would come from the limit
+    /// resolver can't be checked without awaiting, so they always get the
+    /// `contended_policy` decision. Circuit breakers and `min_interval_ms`
+    /// still apply, but a request under `min_interval_ms` is rejected rather
+    /// than delayed.
+    pub fn try_check<Q>(&self, key: &Q) -> RateLimitDecision<K>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        let now = self.clock.now();
+        if let Some(circuits) = &self.circuits {
+            if let Err(retry_after) = circuits.admit(key, now) {
+                let decision = RateLimitDecision::reject(key.to_owned(), DecisionReason::CircuitOpen, Some(retry_after));
+                return self.counted(key, decision);
+            }
+        }
+
+        let decision = self.try_check_key(key).unwrap_or_else(|| self.contended(key));
+        if let Some(circuits) = &self.circuits {
+            record_circuit(circuits, key, &decision, now);
+        }
+        self.counted(key, decision)
+    }
+
+    /// `check_rate_limit` without waiting, see [`try_check`](Self::try_check)
+    pub fn try_check_rate_limit<Q>(&self, key: &Q) -> Result<(), RateLimitError>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        self.decision_result(&self.try_check(key))
+    }
+
+    /// Key check behind `try_check`, `None` if it would have to wait
+    fn try_check_key<Q>(&self, key: &Q) -> Option<RateLimitDecision<K>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        let quota = self.config.quota();
+        if let Some(decision) = self.bypass(key, quota.max_requests) {
+            return Some(decision);
+        }
+        if self.store.is_some() || self.limit_resolver.is_some() {
+            return None;
+        }
+        if let Some(intervals) = &self.min_intervals {
+            if let Err(wait) = intervals.admit(key, Instant::now(), Duration::ZERO) {
+                return Some(self.min_interval_rejection(key, intervals, wait));
+            }
+        }
+
+        let weight = match &self.sampler {
+            Some(sampler) if !sampler.sample(key) => {
+                return Some(RateLimitDecision::allow(key.to_owned(), quota.max_requests, DecisionReason::Unsampled));
+            }
+            Some(sampler) => sampler.weight(),
+            None => 1,
+        };
+
+        let now = self.clock.now();
+        if let Some(remaining) = (weight == 1).then(|| self.try_fast_admit(key, now)).flatten() {
+            return Some(RateLimitDecision::allow(key.to_owned(), remaining, DecisionReason::WithinLimit));
+        }
+
+        let mut attempts = self.attempts.try_lock().ok()?;
+        Some(self.check_locked(&mut attempts, key, quota, weight, now))
+    }
+
+    /// Decision for a `try_check` that couldn't run without waiting
+    fn contended<Q>(&self, key: &Q) -> RateLimitDecision<K>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        debug!("Rate limit check contended for key: {}", self.config.log_key(key));
+        match self.config.contended_policy {
+            ContendedPolicy::Allow => {
+                RateLimitDecision::allow(key.to_owned(), self.config.max_requests_per_window, DecisionReason::Contended)
+            }
+            ContendedPolicy::Reject => RateLimitDecision::reject(key.to_owned(), DecisionReason::Contended, None),
+        }
+    }
+
+    /// Count a decision towards [`stats`](Self::stats)
+    fn counted<Q>(&self, key: &Q, decision: RateLimitDecision<K>) -> RateLimitDecision<K>
+    where
+        Q: Hash + ?Sized,
+    {
+        self.stats.record(key, !decision.allowed, self.clock.now());
+        decision
+    }
+
+    /// Requests, rejections and unique keys over roughly the last
+    /// `rate_window_secs`
+    ///
+    /// Counted from every check without scanning tracked keys, so reading
+    /// stats is cheap at any request rate. The counters use relaxed atomics
+    /// and are approximate under concurrency, and `unique_keys` is an
+    /// estimate. In `Delay` mode each recheck of a waiting request counts.
+    pub fn stats(&self) -> RateLimitStats {
+        self.stats.snapshot(self.clock.now())
+    }
+
+    /// Run a check, then let the `RecordHook` veto an allowed request
+    async fn with_veto<Q, F>(&self, key: &Q, check: F) -> RateLimitDecision<K>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+        F: Future<Output = RateLimitDecision<K>>,
+    {
+        let decision = check.await;
+        let Some(hook) = self.record_hook.as_ref().filter(|_| decision.reason == DecisionReason::WithinLimit) else {
+            return decision;
+        };
+
+        match hook.before_record(&key.to_string()).await {
+            Ok(true) => decision,
+            Ok(false) => {
+                self.refund_charged(&decision, 1.0).await;
+                log_at!(self.rejection_level, "Record hook refused request for key: {}", self.config.log_key(key));
+                RateLimitDecision::reject(key.to_owned(), DecisionReason::Vetoed, None)
+            }
+            Err(e) => {
+                warn!("Record hook error for key {}: {}", self.config.log_key(key), e);
+                decision
+            }
+        }
+    }
+
+    /// Run a check once the key's `min_interval_ms` has passed, waiting for it
+    /// in `Delay` mode
+    async fn with_min_interval<Q, F>(&self, key: &Q, check: F) -> RateLimitDecision<K>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+        F: Future<Output = RateLimitDecision<K>>,
+    {
+        let Some(intervals) = self.min_intervals.as_ref().filter(|_| self.config.enabled && !self.is_draining()) else {
+            return check.await;
+        };
+
+        let max_wait = match self.config.mode {
+            RateLimitMode::Delay { max_wait_secs, .. } => Duration::from_secs(max_wait_secs),
+            RateLimitMode::Enforce | RateLimitMode::Advisory => Duration::ZERO,
+        };
+        match intervals.admit(key, Instant::now(), max_wait) {
+            Ok(wait) => {
+                if !wait.is_zero() {
+                    tokio::time::sleep(wait).await;
+                }
+                check.await
+            }
+            Err(wait) => self.min_interval_rejection(key, intervals, wait),
+        }
+    }
+
+    /// Rejection for a request `wait` short of the key's `min_interval_ms`
+    fn min_interval_rejection<Q>(&self, key: &Q, intervals: &MinIntervals<K>, wait: Duration) -> RateLimitDecision<K>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        let interval_ms = u64::try_from(intervals.interval().as_millis()).unwrap_or(u64::MAX);
+        let wait_ms = u64::try_from(wait.as_millis()).unwrap_or(u64::MAX);
+        log_at!(self.rejection_level, key = %self.config.log_key(key), interval_ms, wait_ms,
+            "Minimum request interval not elapsed");
+        RateLimitDecision::reject(key.to_owned(), DecisionReason::Exceeded, Some(wait.as_secs_f64().ceil() as u64))
+            .with_detail(RejectionDetail::MinInterval { interval_ms, wait_ms })
+    }
 
+    /// Check a key against a quota, bypassing its circuit breaker
+    async fn check_key<Q>(&self, key: &Q, quota: Quota) -> RateLimitDecision<K>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        if let Some(decision) = self.bypass(key, quota.max_requests) {
+            return decision;
+        }
+
+        let weight = match &self.sampler {
+            Some(sampler) if !sampler.sample(key) => {
+                return RateLimitDecision::allow(key.to_owned(), quota.max_requests, DecisionReason::Unsampled);
+            }
+            Some(sampler) => sampler.weight(),
+            None => 1,
+        };
+
+        if let Some(store) = &self.store {
+            return self.check_store(store.as_ref(), key, quota, weight).await;
+        }
+
+        let now = self.clock.now();
+
+        // Admit without the lock while the key is well below its limit; the
+        // fast path counts single attempts, so weighted samples skip it
+        if let Some(remaining) = (weight == 1).then(|| self.try_fast_admit(key, now)).flatten() {
+            return RateLimitDecision::allow(key.to_owned(), remaining, DecisionReason::WithinLimit)
+                .with_charge(key.to_owned(), 1);
+        }
+
+        let mut attempts = self.attempts.lock().await;
+        self.check_locked(&mut attempts, key, quota, weight, now)
+    }
+
+    /// Record `weight` attempts against a key in the locked state map
+    fn check_locked<Q>(
+        &self,
+        attempts: &mut HashMap<K, KeyState, S>,
+        key: &Q,
+        quota: Quota,
+        weight: u32,
+        now: u64,
+    ) -> RateLimitDecision<K>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        // Get or create state for this key
+        let state = self.key_state(attempts, key, quota, now);
+        state.quota = state.override_quota.unwrap_or(quota);
+
+        let slot = self.fast_slot(key);
+        if let Some(slot) = &slot {
+            flush_fast_slot(slot, state);
+        }
+
+        let mut decision = self.evaluate(key, state, now);
+        if decision.allowed && weight > 1 {
+            // Count the unsampled requests this one stands in for
+            for _ in 1..weight {
+                state.attempts.record(now);
+            }
+            self.cap_stored_attempts(key, state);
+            decision.remaining = decision.remaining.saturating_sub(weight - 1);
+            match decision.charged.first_mut() {
+                Some((_, charged)) => *charged = charged.saturating_add(weight - 1),
+                None => decision.charged.push((key.to_owned(), weight - 1)),
+            }
+        }
+        self.publish_fast_slot(key, state, slot);
+        decision
+    }
+
+    /// State for a key checked at `now`, first making room for it if it's new
+    fn key_state<'a, Q>(
+        &self,
+        attempts: &'a mut HashMap<K, KeyState, S>,
+        key: &Q,
+        quota: Quota,
+        now: u64,
+    ) -> &'a mut KeyState
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        if !attempts.contains_key(key) {
+            self.make_room(attempts, 1, now);
+        }
+        let state = attempts.entry(key.to_owned())
+            .or_insert_with(|| KeyState::new(&self.config, quota));
+        state.touch(now);
+        state
+    }
+
+    /// Evict keys per `eviction_policy` if tracking `incoming` new keys would
+    /// exceed `max_tracked_keys`
+    fn make_room(&self, attempts: &mut HashMap<K, KeyState, S>, incoming: usize, now: u64) {
+        let Some(max) = self.config.max_tracked_keys else {
+            return;
+        };
+        if incoming == 0 || attempts.len() + incoming <= max {
+            return;
+        }
+
+        let mut slots = self.fast_slots.write().unwrap_or_else(PoisonError::into_inner);
+        let score: fn(&KeyState) -> u64 = match self.config.eviction_policy {
+            EvictionPolicy::Ttl => {
+                // Only scan once a second while nothing has expired
+                if self.expiry_scanned_at.swap(now, Ordering::AcqRel) == now {
+                    return;
+                }
+                attempts.retain(|key, state| self.retain_live(state, slots.get(key), now));
+                slots.retain(|key, _| attempts.contains_key(key));
+                return;
+            }
+            EvictionPolicy::Lru => |state| state.last_access,
+            EvictionPolicy::Lfu => |state| state.accesses,
+        };
+
+        // Evict a sixteenth of the cap beyond what's needed so full limiters
+        // don't scan on every new key
+        let excess = (attempts.len() + incoming).saturating_sub(max) + max / 16;
+        let mut victims: Vec<(u64, K)> = attempts.iter()
+            .filter(|(_, state)| !state.pinned(now))
+            .map(|(key, state)| (score(state), key.clone()))
+            .collect();
+        if excess < victims.len() {
+            victims.select_nth_unstable_by_key(excess, |(score, _)| *score);
+            victims.truncate(excess);
+        }
+
+        debug!("Evicting {} of {} tracked rate limit keys", victims.len(), attempts.len());
+        for (_, key) in victims {
+            attempts.remove(&key);
+            // Leave the slot blocked for anyone still holding it
+            if let Some(slot) = slots.remove(&key) {
+                slot.drain();
+            }
+        }
+    }
+
+    /// Decision for a check made while limiting is disabled, draining or
+    /// warming up, or for a key refused by `control_chars`
+    fn bypass<Q>(&self, key: &Q, max_requests: u32) -> Option<RateLimitDecision<K>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        if !self.config.enabled {
+            return Some(RateLimitDecision::allow(key.to_owned(), max_requests, DecisionReason::Disabled));
+        }
+
+        if self.config.control_chars == ControlCharPolicy::Reject && has_control_chars(key) {
+            warn!("Rejected rate limit key with control characters: {}", self.config.log_key(key));
+            return Some(RateLimitDecision::reject(key.to_owned(), DecisionReason::InvalidKey, None));
+        }
+
+        if self.draining.load(Ordering::Acquire) {
+            return Some(match self.config.drain_policy {
+                DrainPolicy::Allow => RateLimitDecision::allow(key.to_owned(), max_requests, DecisionReason::Draining),
+                DrainPolicy::Reject => RateLimitDecision::reject(key.to_owned(), DecisionReason::Draining, None),
+            });
+        }
+
+        self.is_warming_up()
+            .then(|| RateLimitDecision::allow(key.to_owned(), max_requests, DecisionReason::WarmingUp))
+    }
+
+    /// Check a key against the default quota and record the attempt only if
+    /// `reserve` then succeeds, for `CombinedRateLimiter::check_and_reserve`
+    ///
+    /// `reserve` runs while this limiter's lock is held, so it must never
+    /// wait on anything that takes this lock. Circuit breakers, minimum
+    /// intervals and the record hook aren't consulted. A store-backed limiter
+    /// can't undo an increment, so there `reserve` runs first and the store
+    /// is only incremented once it has succeeded.
+    pub(crate) async fn check_and_reserve<Q, T, F, Fut>(
+        &self,
+        key: &Q,
+        reserve: F,
+    ) -> Result<(RateLimitDecision<K>, T), RateLimitError>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, RateLimitError>>,
+    {
+        let quota = self.config.quota();
+        if let Some(decision) = self.bypass(key, quota.max_requests) {
+            self.decision_result(&decision)?;
+            return Ok((decision, reserve().await?));
+        }
+
+        if let Some(store) = &self.store {
+            let reserved = reserve().await?;
+            let decision = self.counted(key, self.check_store(store.as_ref(), key, quota, 1).await);
+            self.decision_result(&decision)?;
+            return Ok((decision, reserved));
+        }
+
+        let now = self.clock.now();
+        let mut attempts = self.attempts.lock().await;
+        let decision = self.counted(key, self.check_locked(&mut attempts, key, quota, 1, now));
+        self.decision_result(&decision)?;
+        match reserve().await {
+            Ok(reserved) => Ok((decision, reserved)),
+            Err(e) => {
+                // Take back the attempt just recorded, before anyone else can
+                // see it
+                if let Some(state) = attempts.get_mut(key) {
+                    let slot = self.fast_slot(key);
+                    if let Some(slot) = &slot {
+                        flush_fast_slot(slot, state);
+                    }
+                    state.attempts.remove_latest(1);
+                    self.publish_fast_slot(key, state, slot);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Record `n` attempts against a key only if all of them fit under its
+    /// limit, returning whether they were recorded
+    ///
+    /// Either all `n` attempts are recorded or none are, so a batch can reserve
+    /// its calls up front. Banned keys and reservations larger than the limit
+    /// always fail. Store-backed limiters read the key's count before
+    /// incrementing it, so a reservation that doesn't fit is never counted,
+    /// though concurrent reservations from other instances can still both
+    /// pass the read and overshoot the limit together.
+    pub async fn try_consume<Q>(&self, key: &Q, n: u32) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        let quota = self.config.quota();
+        if let Some(decision) = self.bypass(key, quota.max_requests) {
+            return decision.allowed;
+        }
+        if n > quota.max_requests {
+            return false;
+        }
+
+        if let Some(store) = &self.store {
+            let store_key = key.to_string();
+            let counted = async {
+                let count = store.increment(&store_key, 0, quota).await?;
+                if count.saturating_add(n) > quota.max_requests {
+                    return Ok::<_, RateLimitError>(false);
+                }
+                Ok(store.increment(&store_key, n, quota).await? <= quota.max_requests)
+            };
+            return match counted.await {
+                Ok(consumed) => consumed,
+                Err(e) => {
+                    // Store errors shouldn't take the service down, allow but log
+                    warn!("Rate limit store error for key {}: {}", self.config.log_key(key), e);
+                    true
+                }
+            };
+        }
+
+        let now = self.clock.now();
+        let mut attempts = self.attempts.lock().await;
+        let state = self.key_state(&mut *attempts, key, quota, now);
+        state.quota = state.override_quota.unwrap_or(quota);
+
+        let slot = self.fast_slot(key);
+        if let Some(slot) = &slot {
+            flush_fast_slot(slot, state);
+        }
+
+        let consumed = self.ban_rejection(key, state, now).is_none() && {
+            state.attempts.prune(now.saturating_sub(state.quota.window_secs));
+            let fits = state.attempts.count()
+                .checked_add(n)
+                .is_some_and(|total| total <= state.quota.max_requests);
+            if fits {
+                for _ in 0..n {
+                    state.attempts.record(now);
+                }
+                self.cap_stored_attempts(key, state);
+            }
+            fits
+        };
+
+        self.publish_fast_slot(key, state, slot);
+        consumed
+    }
+
+    /// Override the limit and window for a single key
+    ///
+    /// The override takes precedence over the default, region and any other
+    /// quota passed to a check, and keeps the key tracked until it's cleared.
+    /// Existing attempt timestamps are reinterpreted against the new window on
+    /// the next check: shrinking the window drops attempts that fall outside
+    /// it, while growing it only counts attempts still retained, so history
+    /// already pruned under the shorter window is not recovered. Bucketed
+    /// windows keep the bucket width chosen when the key was first tracked.
+    pub async fn set_key_quota<Q>(&self, key: &Q, quota: Quota)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
         let mut attempts = self.attempts.lock().await;
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+        let state = attempts.entry(key.to_owned())
+            .or_insert_with(|| KeyState::new(&self.config, quota));
+
+        let slot = self.fast_slot(key);
+        if let Some(slot) = &slot {
+            flush_fast_slot(slot, state);
+        }
+
+        state.override_quota = Some(quota);
+        state.quota = quota;
+        self.publish_fast_slot(key, state, slot);
+    }
 
-        // Get or create attempt list for this key
-        let attempt_list = attempts.entry(key.to_string()).or_insert_with(Vec::new);
+    /// Remove a key's quota override, reverting it to the quota of its next check
+    pub async fn clear_key_quota<Q>(&self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        let mut attempts = self.attempts.lock().await;
+        if let Some(state) = attempts.get_mut(key) {
+            let slot = self.fast_slot(key);
+            if let Some(slot) = &slot {
+                flush_fast_slot(slot, state);
+            }
+
+            state.override_quota = None;
+            state.quota = self.config.quota();
+            self.publish_fast_slot(key, state, slot);
+        }
+    }
+
+    /// Give a key a fresh budget by forgetting its recorded attempts, e.g.
+    /// after verifying a client
+    ///
+    /// Bans and quota overrides are kept. A no-op for untracked keys. Holds
+    /// the limiter lock only while clearing the key; attempts counted in an
+    /// external store aren't affected.
+    pub async fn reset_key<Q>(&self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        let mut attempts = self.attempts.lock().await;
+        if let Some(state) = attempts.get_mut(key) {
+            let slot = self.fast_slot(key);
+            if let Some(slot) = &slot {
+                // Pending fast-path admissions are discarded with the rest
+                slot.drain();
+            }
+
+            state.attempts = AttemptWindow::new(state.quota.window_secs,
+                self.config.window_buckets_for(state.quota.window_secs));
+            state.free_request_at = None;
+            self.publish_fast_slot(key, state, slot);
+            debug!("Rate limit attempts reset for key: {}", self.config.log_key(key));
+        }
+    }
+
+    /// Give back up to `n` of a key's most recent attempts, e.g. for a request
+    /// that turned out cheaper than expected, returning how many were
+    /// refunded
+    ///
+    /// A key is never refunded below zero attempts. A no-op for untracked
+    /// keys; attempts counted in an external store aren't affected.
+    pub async fn refund<Q>(&self, key: &Q, n: u32) -> u32
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        self.refund_fraction(key, f64::from(n)).await
+    }
+
+    /// Refund `amount` attempts, carrying any fraction over to later refunds
+    async fn refund_fraction<Q>(&self, key: &Q, amount: f64) -> u32
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        let mut attempts = self.attempts.lock().await;
+        let Some(state) = attempts.get_mut(key) else {
+            return 0;
+        };
+        let slot = self.fast_slot(key);
+        if let Some(slot) = &slot {
+            flush_fast_slot(slot, state);
+        }
+
+        let owed = state.refund_credit + amount.max(0.0);
+        let whole = owed.floor().min(f64::from(u32::MAX)) as u32;
+        let refunded = state.attempts.remove_latest(whole);
+        // Credit only carries over while there are attempts to refund
+        state.refund_credit = if refunded == whole { owed - f64::from(whole) } else { 0.0 };
+
+        self.publish_fast_slot(key, state, slot);
+        refunded
+    }
+
+    /// Refund `fraction` of the attempts a decision charged to each key
+    async fn refund_charged(&self, decision: &RateLimitDecision<K>, fraction: f64) {
+        for (key, attempts) in &decision.charged {
+            self.refund_fraction::<K>(key, fraction * f64::from(*attempts)).await;
+        }
+    }
+
+    /// Check a key against an external store
+    ///
+    /// `weight` is the number of attempts the request counts as.
+    async fn check_store<Q>(&self, store: &dyn RateLimitStore, key: &Q, quota: Quota, weight: u32) -> RateLimitDecision<K>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        match store.increment(&key.to_string(), weight, quota).await {
+            Ok(count) if count <= quota.max_requests => {
+                self.note_soft_limit(key, count);
+                RateLimitDecision::allow(key.to_owned(), quota.max_requests - count, DecisionReason::WithinLimit)
+            }
+            Ok(count) => {
+                log_at!(self.rejection_level, key = %self.config.log_key(key), count, limit = quota.max_requests,
+                    window_secs = quota.window_secs, "Rate limit exceeded");
+                RateLimitDecision::reject(key.to_owned(), DecisionReason::Exceeded, None)
+                    .with_detail(RejectionDetail::Limit {
+                        tier: LimitTier::Key,
+                        count,
+                        limit: quota.max_requests,
+                        window_secs: quota.window_secs,
+                    })
+            }
+            Err(e) => {
+                // Store errors shouldn't take the service down, allow but log
+                warn!("Rate limit store error for key {}: {}", self.config.log_key(key), e);
+                RateLimitDecision::allow(key.to_owned(), quota.max_requests, DecisionReason::WithinLimit)
+            }
+        }
+    }
+
+    /// Apply the limit to a key's state under the lock
+    fn evaluate<Q>(&self, key: &Q, state: &mut KeyState, now: u64) -> RateLimitDecision<K>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        let max_requests = state.quota.max_requests;
+
+        if let Some(rejection) = self.ban_rejection(key, state, now) {
+            return rejection;
+        }
 
         // Remove old attempts outside the window
-        let window_start = now.saturating_sub(self.config.rate_window_secs);
-        attempt_list.retain(|&timestamp| timestamp > window_start);
+        let window_start = now.saturating_sub(state.quota.window_secs);
+        state.attempts.prune(window_start);
+
+        // Let the first request of a fresh window through uncounted
+        if self.config.always_allow_first
+            && state.attempts.is_empty()
+            && state.free_request_at.is_none_or(|at| at <= window_start)
+        {
+            state.free_request_at = Some(now);
+            return RateLimitDecision::allow(key.to_owned(), max_requests, DecisionReason::WithinLimit);
+        }
 
-        // Check if we've exceeded the limit
-        if attempt_list.len() >= self.config.max_requests_per_window as usize {
-            warn!("Rate limit exceeded for key: {}", key);
-            return Err(RateLimitError::Exceeded(format!(
-                "Maximum {} requests per {} seconds exceeded",
-                self.config.max_requests_per_window,
-                self.config.rate_window_secs
-            )));
+        if let Some(rejection) = self.limit_rejection(key, state, now, window_start) {
+            return rejection;
         }
 
         // Record this attempt
-        attempt_list.push(now);
+        state.attempts.record(now);
+        self.cap_stored_attempts(key, state);
+        let count = state.attempts.count();
+        self.note_soft_limit(key, count);
 
-        Ok(())
+        RateLimitDecision::allow(key.to_owned(), max_requests.saturating_sub(count), DecisionReason::WithinLimit)
+            .with_charge(key.to_owned(), 1)
+    }
+
+    /// Reject a banned key, clearing the ban once it has expired
+    fn ban_rejection<Q>(&self, key: &Q, state: &mut KeyState, now: u64) -> Option<RateLimitDecision<K>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        let banned_until = state.banned_until?;
+        if now < banned_until {
+            warn!("Request from banned key: {} ({} seconds remaining)",
+                self.config.log_key(key), banned_until - now);
+            let until = self.clock.wall_time(banned_until);
+            return Some(RateLimitDecision::reject(key.to_owned(), DecisionReason::Banned(until), Some(banned_until - now))
+                .with_detail(RejectionDetail::Banned { tier: LimitTier::Key, until }));
+        }
+
+        // Ban expired, clear it
+        state.banned_until = None;
+        state.rejections.clear();
+        state.status_hits.clear();
+        None
+    }
+
+    /// Switch a key to bucketed counters once it stores more than
+    /// `max_stored_attempts` timestamps
+    fn cap_stored_attempts<Q>(&self, key: &Q, state: &mut KeyState)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        let buckets = self.config.window_buckets_for(state.quota.window_secs).unwrap_or(CAPPED_WINDOW_BUCKETS);
+        if state.attempts.coarsen(self.config.max_stored_attempts, state.quota.window_secs, buckets) {
+            warn!("Key stored over {} attempts, counting it approximately: {}",
+                self.config.max_stored_attempts, self.config.log_key(key));
+        }
+    }
+
+    /// Reject a key that has reached its limit in the already-pruned window,
+    /// banning it if it keeps hitting the limit
+    fn limit_rejection<Q>(
+        &self,
+        key: &Q,
+        state: &mut KeyState,
+        now: u64,
+        window_start: u64,
+    ) -> Option<RateLimitDecision<K>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        let max_requests = state.quota.max_requests;
+        if state.attempts.count() < max_requests {
+            return None;
+        }
+
+        let retry_after = state.attempts
+            .available_at(state.quota.window_secs, max_requests)
+            .map(|at| at.saturating_sub(now));
+        log_at!(self.rejection_level, key = %self.config.log_key(key), count = state.attempts.count(),
+            limit = max_requests, window_secs = state.quota.window_secs, retry_after = ?retry_after,
+            "Rate limit exceeded");
+
+        // Ban keys that keep hitting the limit
+        if let Some(threshold) = self.config.ban_threshold {
+            state.rejections.retain(|&t| t > window_start);
+            state.rejections.push(now);
+
+            if state.rejections.len() >= threshold as usize {
+                let banned_until = now.saturating_add(self.config.ban_duration_secs);
+                state.banned_until = Some(banned_until);
+                warn!("Key banned due to repeated rate limit violations: {}", self.config.log_key(key));
+                let until = self.clock.wall_time(banned_until);
+                return Some(RateLimitDecision::reject(key.to_owned(), DecisionReason::Banned(until),
+                    Some(self.config.ban_duration_secs))
+                    .with_detail(RejectionDetail::Banned { tier: LimitTier::Key, until }));
+            }
+        }
+
+        Some(RateLimitDecision::reject(key.to_owned(), DecisionReason::Exceeded, retry_after)
+            .with_detail(RejectionDetail::Limit {
+                tier: LimitTier::Key,
+                count: state.attempts.count(),
+                limit: max_requests,
+                window_secs: state.quota.window_secs,
+            }))
+    }
+
+    /// Count a response status against a key, banning the key once
+    /// `status_ban_threshold` watched statuses land within its window
+    pub async fn record_response_status<Q>(&self, key: &Q, status: u16)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        let Some(threshold) = self.config.status_ban_threshold else {
+            return;
+        };
+        if !self.config.enabled || !self.config.watched_statuses.contains(&status) {
+            return;
+        }
+
+        let now = self.clock.now();
+        let mut attempts = self.attempts.lock().await;
+        let state = self.key_state(&mut *attempts, key, self.config.quota(), now);
+
+        let window_start = now.saturating_sub(state.quota.window_secs);
+        state.status_hits.retain(|&t| t > window_start);
+        state.status_hits.push(now);
+
+        let banned = state.banned_until.is_some_and(|until| now < until);
+        if banned || state.status_hits.len() < threshold as usize {
+            return;
+        }
+
+        let slot = self.fast_slot(key);
+        if let Some(slot) = &slot {
+            flush_fast_slot(slot, state);
+        }
+        state.banned_until = Some(now.saturating_add(self.config.ban_duration_secs));
+        warn!("Key banned after {} responses with status {}: {}",
+            state.status_hits.len(), status, self.config.log_key(key));
+        self.publish_fast_slot(key, state, slot);
+    }
+
+    /// Check a request against a key and several enclosing keys at once, e.g.
+    /// its IP, its subnet and a global key, recording it against every key
+    /// only if all of them allow it
+    ///
+    /// Decisions are always for `key`. An allowed decision reports the fewest
+    /// requests remaining at any level, with `limited_by` naming the level
+    /// when it isn't `key` itself. A rejection is for the violated level that
+    /// frees up last, as the request can't pass before every violated level
+    /// has room. Per-key overrides apply
+    /// to each key, while `always_allow_first` and the fast path's lock-free
+    /// admission don't. Store-backed limiters check the keys one at a time,
+    /// counting the request against each key checked.
+    pub async fn check_levels<Q>(&self, key: &Q, quota: Quota, levels: &[(K, Quota)]) -> RateLimitDecision<K>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        let check = self.with_veto(key, self.check_levels_unguarded(key, quota, levels));
+        self.with_circuit(key, self.with_min_interval(key, check)).await
+    }
+
+    /// `check_levels` bypassing the key's circuit breaker
+    async fn check_levels_unguarded<Q>(&self, key: &Q, quota: Quota, levels: &[(K, Quota)]) -> RateLimitDecision<K>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        if levels.is_empty() {
+            return self.check_key(key, quota).await;
+        }
+        if let Some(decision) = self.bypass(key, quota.max_requests) {
+            return decision;
+        }
+
+        if let Some(store) = &self.store {
+            let mut decision = self.check_store(store.as_ref(), key, quota, 1).await;
+            for (i, (level, level_quota)) in levels.iter().enumerate() {
+                if !decision.allowed {
+                    break;
+                }
+                let level_decision = self.check_store::<K>(store.as_ref(), level, *level_quota, 1).await;
+                if !level_decision.allowed || level_decision.remaining < decision.remaining {
+                    decision = level_decision.for_level(key.to_owned(), LimitTier::Level(i), *level_quota);
+                }
+            }
+            return decision;
+        }
+
+        let keys: Vec<(K, Quota)> = std::iter::once((key.to_owned(), quota))
+            .chain(levels.iter().cloned())
+            .collect();
+        let now = self.clock.now();
+        let mut attempts = self.attempts.lock().await;
+        // Make room up front so no level is evicted part way through
+        let incoming = keys.iter().filter(|(key, _)| !attempts.contains_key::<K>(key)).count();
+        self.make_room(&mut attempts, incoming, now);
+
+        // Check every key before recording against any of them
+        let mut rejection: Option<RateLimitDecision<K>> = None;
+        let mut slots = Vec::with_capacity(keys.len());
+        for (i, (key, quota)) in keys.iter().enumerate() {
+            let state = attempts.entry(key.clone())
+                .or_insert_with(|| KeyState::new(&self.config, *quota));
+            state.touch(now);
+            state.quota = state.override_quota.unwrap_or(*quota);
+
+            let slot = self.fast_slot::<K>(key);
+            if let Some(slot) = &slot {
+                flush_fast_slot(slot, state);
+            }
+            slots.push(slot);
+
+            let level_rejection = self.ban_rejection::<K>(key, state, now).or_else(|| {
+                let window_start = now.saturating_sub(state.quota.window_secs);
+                state.attempts.prune(window_start);
+                self.limit_rejection::<K>(key, state, now, window_start)
+            });
+            if let Some(mut level_rejection) = level_rejection {
+                if i > 0 {
+                    let tier = LimitTier::Level(i - 1);
+                    level_rejection = level_rejection.for_level(keys[0].0.clone(), tier, state.quota);
+                }
+                let frees_at = |decision: &RateLimitDecision<K>| decision.retry_after.unwrap_or(u64::MAX);
+                if rejection.as_ref().is_none_or(|current| frees_at(&level_rejection) > frees_at(current)) {
+                    rejection = Some(level_rejection);
+                }
+            }
+        }
+
+        // Hold the key to its share once the global limit is saturated
+        if let (None, Some(fair_share)) = (&rejection, &self.fair_share) {
+            let key = &keys[0].0;
+            if let Err((detail, retry_after)) = fair_share.admit::<K>(key, now) {
+                log_at!(self.rejection_level, key = %self.config.log_key(key), %detail,
+                    "Fair share of global limit used");
+                rejection = Some(RateLimitDecision::reject(key.clone(), DecisionReason::Exceeded, Some(retry_after))
+                    .with_detail(detail));
+            }
+        }
+
+        let mut allowed: Option<RateLimitDecision<K>> = None;
+        let mut charged = Vec::new();
+        for (i, ((key, _), slot)) in keys.iter().zip(slots).enumerate() {
+            let Some(state) = attempts.get_mut::<K>(key) else {
+                continue;
+            };
+
+            if rejection.is_none() {
+                state.attempts.record(now);
+                charged.push((key.clone(), 1));
+                self.cap_stored_attempts::<K>(key, state);
+                let count = state.attempts.count();
+                // The soft limit is per key, not per enclosing level
+                if i == 0 {
+                    self.note_soft_limit(key, count);
+                }
+
+                let remaining = state.quota.max_requests.saturating_sub(count);
+                if allowed.as_ref().is_none_or(|current| remaining < current.remaining) {
+                    let decision = RateLimitDecision::allow(keys[0].0.clone(), remaining, DecisionReason::WithinLimit);
+                    allowed = Some(match i {
+                        0 => decision,
+                        _ => decision.for_level(keys[0].0.clone(), LimitTier::Level(i - 1), state.quota),
+                    });
+                }
+            }
+
+            if slot.is_some() {
+                self.publish_fast_slot::<K>(key, state, slot);
+            }
+        }
+
+        if let Some(allowed) = &mut allowed {
+            allowed.charged = charged;
+        }
+        rejection.or(allowed)
+            .unwrap_or_else(|| RateLimitDecision::allow(key.to_owned(), quota.max_requests, DecisionReason::WithinLimit))
+    }
+
+    /// Replay `(key, timestamp)` events through the limiting algorithm against
+    /// throwaway state, returning the decision for each event
+    ///
+    /// Timestamps are unix seconds and should be in order. The limiter's own
+    /// state, overrides and counters are untouched, and `enabled`, draining and
+    /// any store are ignored, so a recorded traffic log can be run against a
+    /// candidate config offline, e.g.
+    /// `RateLimiter::new(candidate).simulate(events)`. Rejections are logged as
+    /// they would be live.
+    pub fn simulate<I>(&self, events: I) -> Vec<RateLimitDecision<K>>
+    where
+        I: IntoIterator<Item = (K, u64)>,
+    {
+        let replay = Self {
+            soft_limit_warnings: Arc::new(AtomicU64::new(0)),
+            ..self.clone()
+        };
+        let quota = self.config.quota();
+        let mut states: HashMap<K, KeyState> = HashMap::new();
+
+        events.into_iter()
+            .map(|(key, timestamp)| {
+                let state = states.entry(key.clone())
+                    .or_insert_with(|| KeyState::new(&self.config, quota));
+                replay.evaluate(&key, state, timestamp)
+            })
+            .collect()
+    }
+
+    /// Warn about an allowed request if it puts the key over the soft limit
+    fn note_soft_limit<Q: Display + ?Sized>(&self, key: &Q, count: u32) {
+        let Some(soft_limit) = self.config.soft_limit else {
+            return;
+        };
+
+        let warn = if self.config.soft_limit_once_per_window {
+            count == soft_limit.saturating_add(1)
+        } else {
+            count > soft_limit
+        };
+        if warn {
+            self.soft_limit_warnings.fetch_add(1, Ordering::Relaxed);
+            warn!("Soft rate limit of {} exceeded for key: {} ({} requests in window)",
+                soft_limit, self.config.log_key(key), count);
+        }
+    }
+
+    /// Check a key, waiting for a free slot for up to `max_wait` instead of
+    /// rejecting
+    ///
+    /// At most `max_queue_depth` callers wait on a key at once; beyond that, and
+    /// whenever the next free slot is further off than the remaining wait
+    /// budget, the rejection is returned immediately. Bans are never waited out.
+    pub async fn check_with_delay<Q>(
+        &self,
+        key: &Q,
+        quota: Quota,
+        max_wait: Duration,
+        max_queue_depth: u32,
+    ) -> RateLimitDecision<K>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        self.wait_for_slot(key, max_wait, max_queue_depth, || self.check_with_quota(key, quota)).await
+    }
+
+    /// Pass a request through the key's leaky bucket, waiting until it leaks
+    /// out
+    ///
+    /// Rejected with [`DecisionReason::Exceeded`] when the bucket is full.
+    /// While the limiter is disabled or `leaky_bucket` is unset, requests
+    /// pass without waiting. A caller that stops waiting still holds its
+    /// place, so later requests wait as if it had been let out.
+    pub async fn shape<Q>(&self, key: &Q) -> RateLimitDecision<K>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        let max_requests = self.config.leaky_bucket.map_or(0, |bucket| bucket.bucket_capacity);
+        if let Some(decision) = self.bypass(key, max_requests) {
+            return decision;
+        }
+        let Some(buckets) = &self.leaky_buckets else {
+            return RateLimitDecision::allow(key.to_owned(), max_requests, DecisionReason::WithinLimit);
+        };
+
+        let decision = match buckets.enqueue(key, Instant::now()) {
+            Ok((wait, remaining)) => {
+                if !wait.is_zero() {
+                    tokio::time::sleep(wait).await;
+                }
+                RateLimitDecision::allow(key.to_owned(), remaining, DecisionReason::WithinLimit)
+            }
+            Err(retry_after) => {
+                log_at!(self.rejection_level, key = %self.config.log_key(key), capacity = max_requests,
+                    "Leaky bucket overflowed");
+                RateLimitDecision::reject(key.to_owned(), DecisionReason::Exceeded, Some(retry_after))
+            }
+        };
+        self.counted(key, decision)
+    }
+
+    /// Charge `cost` to the key's `cost_bucket`, rejecting the charge if it
+    /// would overflow the bucket
+    ///
+    /// A rejected charge isn't recorded. `remaining` is the whole units of
+    /// cost the bucket still has room for, and `retry_after` is `None` for a
+    /// charge larger than the bucket. While the limiter is disabled or
+    /// `cost_bucket` is unset, every charge is allowed.
+    pub async fn check_cost<Q>(&self, key: &Q, cost: f64) -> RateLimitDecision<K>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        let size = self.config.cost_bucket.map_or(0, |bucket| whole_units(bucket.bucket_size));
+        if let Some(decision) = self.bypass(key, size) {
+            return decision;
+        }
+        let Some(buckets) = &self.cost_buckets else {
+            return RateLimitDecision::allow(key.to_owned(), size, DecisionReason::WithinLimit);
+        };
+
+        let decision = match buckets.add(key, cost, Instant::now()) {
+            Ok(room) => RateLimitDecision::allow(key.to_owned(), whole_units(room), DecisionReason::WithinLimit),
+            Err(retry_after) => {
+                log_at!(self.rejection_level, key = %self.config.log_key(key), cost, bucket_size = buckets.size(),
+                    "Cost bucket overflowed");
+                RateLimitDecision::reject(key.to_owned(), DecisionReason::Exceeded, retry_after)
+            }
+        };
+        self.counted(key, decision)
+    }
+
+    /// Repeat `check` until it allows the request or the wait budget or
+    /// `key`'s delay queue runs out
+    async fn wait_for_slot<Q, F, Fut>(
+        &self,
+        key: &Q,
+        max_wait: Duration,
+        max_queue_depth: u32,
+        check: F,
+    ) -> RateLimitDecision<K>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+        F: Fn() -> Fut,
+        Fut: Future<Output = RateLimitDecision<K>>,
+    {
+        let mut waited = Duration::ZERO;
+        let mut queued = None;
+
+        loop {
+            let decision = check().await;
+            if decision.allowed || decision.reason != DecisionReason::Exceeded {
+                return decision;
+            }
+
+            // Sleep at most `max_retry_after_secs` before checking again
+            let wait = match decision.retry_after {
+                Some(secs) => Duration::from_secs(self.config.capped_retry_after(secs).max(1)),
+                None => return decision,
+            };
+            match waited.checked_add(wait) {
+                Some(total) if total <= max_wait => {}
+                _ => return decision,
+            }
+
+            if queued.is_none() {
+                match QueueGuard::enter(&self.queued, key.to_owned(), max_queue_depth) {
+                    Some(guard) => queued = Some(guard),
+                    None => {
+                        warn!("Delay queue full for key: {}", self.config.log_key(key));
+                        return decision;
+                    }
+                }
+            }
+
+            tokio::time::sleep(wait).await;
+            waited += wait;
+        }
+    }
+
+    /// Admit a request through the key's fast slot if it's below the ceiling
+    fn try_fast_admit<Q>(&self, key: &Q, now: u64) -> Option<u32>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        self.config.fast_path_margin?;
+
+        // Admit under the read lock so cleanup can't remove the slot mid-admission
+        let slots = self.fast_slots.read().unwrap_or_else(PoisonError::into_inner);
+        slots.get(key)?.try_admit(now)
+    }
+
+    /// Get the fast slot for a key, if the fast path is tracking it
+    fn fast_slot<Q>(&self, key: &Q) -> Option<Arc<FastSlot>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        self.config.fast_path_margin?;
+
+        self.fast_slots.read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(key)
+            .cloned()
+    }
+
+    /// Publish a key's locked state to its fast slot, creating the slot if needed
+    fn publish_fast_slot<Q>(&self, key: &Q, state: &KeyState, slot: Option<Arc<FastSlot>>)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        let Some(ceiling) = self.fast_ceiling(state) else {
+            return;
+        };
+
+        let count = state.attempts.count();
+        let limit = state.quota.max_requests;
+        match slot {
+            Some(slot) => slot.release(count, ceiling, limit),
+            None => {
+                self.fast_slots.write()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .insert(key.to_owned(), Arc::new(FastSlot::new(count, ceiling, limit)));
+            }
+        }
+    }
+
+    /// Count below which a key may be admitted without the lock
+    ///
+    /// Banned keys stay on the locked path so the ban is enforced, as do keys
+    /// past the soft limit so it's reported.
+    fn fast_ceiling(&self, state: &KeyState) -> Option<u32> {
+        let margin = self.config.fast_path_margin?;
+        if state.banned_until.is_some() {
+            return Some(0);
+        }
+        let ceiling = state.quota.max_requests.saturating_sub(margin);
+        Some(self.config.soft_limit.map_or(ceiling, |soft_limit| ceiling.min(soft_limit)))
+    }
+
+    /// Resolve the quota for a client IP from its region
+    fn quota_for_ip(&self, ip: Option<IpAddr>) -> Quota {
+        ip.zip(self.region_resolver.as_ref())
+            .and_then(|(ip, resolver)| resolver.resolve(ip))
+            .and_then(|region| self.config.region_limits.get(&region).copied())
+            .unwrap_or_else(|| self.config.quota())
+    }
+
+    /// Quota that applies to a key right now, for diagnosing unexpected limits
+    ///
+    /// A key's override takes precedence; otherwise a tracked key reports the
+    /// quota of its last check (which reflects its region, if any) and an
+    /// untracked key the configured default. Store-backed limiters ignore
+    /// overrides and always report the default. Reading the quota has no side
+    /// effects and ignores whether limiting is enabled.
+    pub async fn effective_limit_for<Q>(&self, key: &Q) -> Quota
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        if self.store.is_some() {
+            return self.config.quota();
+        }
+
+        let attempts = self.attempts.lock().await;
+        attempts.get(key)
+            .map(|state| state.override_quota.unwrap_or(state.quota))
+            .unwrap_or_else(|| self.config.quota())
+    }
+
+    /// Get current rate limit state for a key without recording an attempt
+    pub async fn status<Q>(&self, key: &Q) -> RateLimitStatus
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        let attempts = self.attempts.lock().await;
+        let now = self.clock.now();
+
+        let (count, quota, banned_until) = attempts.get(key)
+            .map(|state| {
+                let window_start = now.saturating_sub(state.quota.window_secs);
+                let pending = self.fast_slot(key).map_or(0, |slot| slot.pending());
+                let count = state.attempts.count_since(window_start).saturating_add(pending);
+                let banned_until = state.banned_until
+                    .filter(|&until| now < until)
+                    .map(|until| self.clock.wall_time(until));
+                (count, state.quota, banned_until)
+            })
+            .unwrap_or((0, self.config.quota(), None));
+
+        let circuit = match self.circuits.as_ref().map(|circuits| circuits.state(key, now)) {
+            Some(CircuitState::Open { until }) => CircuitState::Open { until: self.clock.wall_time(until) },
+            Some(state) => state,
+            None => CircuitState::Closed,
+        };
+
+        RateLimitStatus {
+            attempts: count,
+            remaining: quota.max_requests.saturating_sub(count),
+            banned_until,
+            circuit,
+        }
+    }
+
+    /// Time until a key may make another request, or `None` if it may now
+    ///
+    /// Computed from the key's oldest attempts still in the window (for
+    /// bucketed windows, from their bucket boundaries) or from its ban, without
+    /// recording anything. Keys with a zero limit report `Duration::MAX`.
+    pub async fn time_until_available<Q>(&self, key: &Q) -> Option<Duration>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + Display + ToOwned<Owned = K> + Sync + ?Sized,
+    {
+        let attempts = self.attempts.lock().await;
+        let now = self.clock.now();
+
+        let Some(state) = attempts.get(key) else {
+            let quota = self.config.quota();
+            return (quota.max_requests == 0).then_some(Duration::MAX);
+        };
+
+        if let Some(banned_until) = state.banned_until.filter(|&until| now < until) {
+            return Some(Duration::from_secs(banned_until - now));
+        }
+
+        let mut window = state.attempts.clone();
+        if let Some(slot) = self.fast_slot(key) {
+            let (pending, at) = slot.peek();
+            for _ in 0..pending {
+                window.record(at);
+            }
+        }
+
+        match window.available_at(state.quota.window_secs, state.quota.max_requests) {
+            Some(at) if at <= now => None,
+            Some(at) => Some(Duration::from_secs(at - now)),
+            None => Some(Duration::MAX),
+        }
+    }
+
+    /// Copy every key's recorded attempt timestamps under a single lock
+    ///
+    /// The snapshot is a point-in-time copy for inspection or for moving state
+    /// to another limiter with [`RateLimiter::restore`]; it may be stale as
+    /// soon as it's returned. Timestamps are the limiter's monotonic seconds,
+    /// which track unix time from when the limiter was created, and bucketed
+    /// attempts are stamped at the start of their bucket.
+    pub async fn snapshot(&self) -> HashMap<K, Vec<u64>> {
+        let attempts = self.attempts.lock().await;
+        let slots = self.fast_slots.read().unwrap_or_else(PoisonError::into_inner);
+
+        attempts.iter()
+            .map(|(key, state)| {
+                let mut timestamps = state.attempts.timestamps();
+                if let Some(slot) = slots.get(key) {
+                    let (pending, at) = slot.peek();
+                    timestamps.extend(std::iter::repeat_n(at, pending as usize));
+                }
+                (key.clone(), timestamps)
+            })
+            .collect()
+    }
+
+    /// Replace all tracked state with a snapshot
+    ///
+    /// Restored keys use the default quota and bans are not carried over.
+    /// Attempts too far in the future are handled per `future_timestamps`.
+    pub async fn restore(&self, snapshot: HashMap<K, Vec<u64>>) {
+        let mut attempts = self.attempts.lock().await;
+        let mut slots = self.fast_slots.write().unwrap_or_else(PoisonError::into_inner);
+
+        // Block existing slots so nothing admits against replaced state
+        for slot in slots.values() {
+            slot.drain();
+        }
+        slots.clear();
+
+        let now = self.clock.now();
+        let quota = self.config.quota();
+        attempts.clear();
+        for (key, mut timestamps) in snapshot {
+            self.correct_skew::<K>(&key, &mut timestamps, now);
+            timestamps.sort_unstable();
+            let mut state = KeyState::new(&self.config, quota);
+            for timestamp in timestamps {
+                state.attempts.record(timestamp);
+            }
+            self.cap_stored_attempts::<K>(&key, &mut state);
+            attempts.insert(key, state);
+        }
+    }
+
+    /// Merge a snapshot taken from another limiter into this one, e.g. when
+    /// consolidating instances during a deployment
+    ///
+    /// A key's timestamps from the snapshot are added to the ones already
+    /// recorded rather than deduplicated, since each limiter counted separate
+    /// requests; merged counts are therefore never lower than either side's.
+    /// Attempts outside the key's window are dropped. Keys already tracked
+    /// keep their quota and bans, and new keys use the default quota.
+    /// Timestamps are compared as-is, so snapshots should come from limiters
+    /// whose clocks agree, as instances' clocks do once NTP-synced; attempts
+    /// further ahead than `future_timestamp_tolerance_secs` are handled per
+    /// `future_timestamps`.
+    pub async fn merge(&self, snapshot: HashMap<K, Vec<u64>>) {
+        let now = self.clock.now();
+        let quota = self.config.quota();
+        let mut attempts = self.attempts.lock().await;
+
+        for (key, mut timestamps) in snapshot {
+            let state = attempts.entry(key.clone())
+                .or_insert_with(|| KeyState::new(&self.config, quota));
+            let slot = self.fast_slot::<K>(&key);
+            if let Some(slot) = &slot {
+                flush_fast_slot(slot, state);
+            }
+
+            self.correct_skew::<K>(&key, &mut timestamps, now);
+            timestamps.sort_unstable();
+            for timestamp in timestamps {
+                state.attempts.record(timestamp);
+            }
+            state.attempts.prune(now.saturating_sub(state.quota.window_secs));
+            self.cap_stored_attempts::<K>(&key, state);
+            self.publish_fast_slot::<K>(&key, state, slot);
+        }
+    }
+
+    /// Apply `future_timestamps` to a key's restored or merged attempts
+    fn correct_skew<Q>(&self, key: &Q, timestamps: &mut Vec<u64>, now: u64)
+    where
+        Q: Display + ?Sized,
+    {
+        let tolerance = self.config.future_timestamp_tolerance_secs;
+        let skewed = self.config.future_timestamps.apply(timestamps, now, tolerance);
+        if skewed > 0 {
+            warn!("{} attempts for key {} are more than {} seconds ahead of the clock; policy {:?}",
+                skewed, self.config.log_key(key), tolerance, self.config.future_timestamps);
+        }
+    }
+
+    /// Export aggregate limiter state in Prometheus text format
+    ///
+    /// Emits tracked key, recorded attempt and banned key counts. No per-key
+    /// series are emitted, keeping cardinality bounded.
+    pub async fn metrics_text(&self) -> String {
+        self.metrics_text_with_top_keys(0).await
+    }
+
+    /// Configuration in use
+    pub fn config(&self) -> &RateLimitConfig {
+        &self.config
+    }
+
+    /// Number of tracked keys and of currently banned keys
+    #[cfg_attr(not(feature = "status-handler"), allow(dead_code))]
+    pub(crate) async fn key_counts(&self) -> (usize, usize) {
+        let attempts = self.attempts.lock().await;
+        let now = self.clock.now();
+        let banned = attempts.values()
+            .filter(|state| state.banned_until.is_some_and(|until| now < until))
+            .count();
+        (attempts.len(), banned)
+    }
+
+    /// Export limiter state in Prometheus text format, including the
+    /// `top_keys` keys with the most attempts in the current window
+    pub async fn metrics_text_with_top_keys(&self, top_keys: usize) -> String {
+        let attempts = self.attempts.lock().await;
+        let slots = self.fast_slots.read().unwrap_or_else(PoisonError::into_inner);
+        let now = self.clock.now();
+
+        let mut counts: Vec<(&K, u32)> = attempts.iter()
+            .map(|(key, state)| {
+                let window_start = now.saturating_sub(state.quota.window_secs);
+                let pending = slots.get(key).map_or(0, |slot| slot.pending());
+                (key, state.attempts.count_since(window_start).saturating_add(pending))
+            })
+            .collect();
+        let banned = attempts.values()
+            .filter(|state| state.banned_until.is_some_and(|until| now < until))
+            .count();
+        let total: u64 = counts.iter().map(|&(_, count)| count as u64).sum();
+
+        let mut out = String::new();
+        metrics::write_gauge(&mut out, "rate_limit_tracked_keys",
+            "Keys currently tracked by the rate limiter", attempts.len() as u64);
+        metrics::write_gauge(&mut out, "rate_limit_tracked_attempts",
+            "Attempts recorded in the current window across all keys", total);
+        metrics::write_gauge(&mut out, "rate_limit_banned_keys",
+            "Keys currently banned", banned as u64);
+        metrics::write_counter(&mut out, "rate_limit_advisory_violations_total",
+            "Requests served over the limit in advisory mode",
+            self.advisory_violations.load(Ordering::Relaxed));
+        metrics::write_counter(&mut out, "rate_limit_soft_limit_warnings_total",
+            "Allowed requests that exceeded the soft limit",
+            self.soft_limit_warnings.load(Ordering::Relaxed));
+
+        if top_keys > 0 {
+            counts.sort_unstable_by_key(|&(_, count)| Reverse(count));
+            out.push_str("# HELP rate_limit_key_attempts Attempts in the current window for the busiest keys\n");
+            out.push_str("# TYPE rate_limit_key_attempts gauge\n");
+            for (key, count) in counts.into_iter().take(top_keys) {
+                let _ = writeln!(out, "rate_limit_key_attempts{{key=\"{}\"}} {}",
+                    metrics::escape_label(&key.to_string()), count);
+            }
+        }
+
+        out
     }
 
     /// Clean up old entries periodically
     pub async fn cleanup(&self) {
         let mut attempts = self.attempts.lock().await;
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+        let now = self.clock.now();
 
-        let window_start = now.saturating_sub(self.config.rate_window_secs);
+        let mut slots = self.fast_slots.write().unwrap_or_else(PoisonError::into_inner);
 
         // Remove entries with no recent attempts
-        attempts.retain(|_, timestamps| {
-            timestamps.retain(|&t| t > window_start);
-            !timestamps.is_empty()
+        attempts.retain(|key, state| self.retain_live(state, slots.get(key), now));
+
+        // Slots of removed keys stay blocked, so nothing admits through them
+        slots.retain(|key, _| attempts.contains_key(key));
+        if let Some(circuits) = &self.circuits {
+            circuits.retain(now, |key| attempts.contains_key(key));
+        }
+
+        self.limit_cache.lock().unwrap_or_else(PoisonError::into_inner)
+            .retain(|_, cached| now < cached.expires_at);
+        if let Some(buckets) = &self.leaky_buckets {
+            buckets.cleanup(Instant::now());
+        }
+        if let Some(intervals) = &self.min_intervals {
+            intervals.cleanup(Instant::now());
+        }
+        if let Some(scans) = &self.path_scans {
+            scans.cleanup(now);
+        }
+        if let Some(buckets) = &self.cost_buckets {
+            buckets.cleanup(Instant::now());
+        }
+
+        // Give back capacity left over from traffic spikes
+        if self.config.shrink_on_cleanup && attempts.len() < attempts.capacity() / 4 {
+            let target = self.initial_capacity.max(attempts.len() * 2);
+            attempts.shrink_to(target);
+            slots.shrink_to(target);
+        }
+    }
+
+    /// Prune a key's expired state, returning whether anything keeps it
+    /// tracked
+    fn retain_live(&self, state: &mut KeyState, slot: Option<&Arc<FastSlot>>, now: u64) -> bool {
+        if let Some(slot) = slot {
+            flush_fast_slot(slot, state);
+        }
+
+        // Keep if banned
+        let banned = state.banned_until.is_some_and(|banned_until| now < banned_until);
+        if !banned {
+            let window_start = now.saturating_sub(state.quota.window_secs);
+            state.attempts.prune(window_start);
+            state.rejections.retain(|&t| t > window_start);
+            state.status_hits.retain(|&t| t > window_start);
+        }
+
+        let keep = banned
+            || state.override_quota.is_some()
+            || state.free_request_at.is_some_and(|at| now < at.saturating_add(state.quota.window_secs))
+            || !state.attempts.is_empty()
+            || !state.rejections.is_empty()
+            || !state.status_hits.is_empty();
+        if let (true, Some(slot), Some(ceiling)) = (keep, slot, self.fast_ceiling(state)) {
+            slot.release(state.attempts.count(), ceiling, state.quota.max_requests);
+        }
+        keep
+    }
+
+    /// Stop limiting ahead of shutdown
+    ///
+    /// Later checks are handled per `drain_policy` without recording attempts,
+    /// and the cleanup task exits at its next tick. The intended shutdown
+    /// sequence is:
+    ///
+    /// 1. `quiesce()` once the server stops accepting connections
+    /// 2. `abort()` (or await) the handle from `spawn_cleanup`
+    /// 3. `flush().await` to persist pending state to the store
+    pub fn quiesce(&self) {
+        self.draining.store(true, Ordering::Release);
+    }
+
+    /// Whether the limiter is still within `warm_up_secs` of its creation,
+    /// logging the switch to enforcement the first time it has passed
+    pub fn is_warming_up(&self) -> bool {
+        if !self.warming_up.load(Ordering::Acquire) {
+            return false;
+        }
+        let warm_up_secs = self.config.warm_up_secs.unwrap_or(0);
+        if self.clock.now().saturating_sub(self.created_at) < warm_up_secs {
+            return true;
+        }
+        if self.warming_up.swap(false, Ordering::AcqRel) {
+            info!("Rate limiter warm-up of {} seconds over, enforcing limits", warm_up_secs);
+        }
+        false
+    }
+
+    /// Whether `quiesce` has been called
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Acquire)
+    }
+
+    /// Record pending fast-path admissions and flush the store, if any
+    pub async fn flush(&self) -> Result<(), RateLimitError> {
+        {
+            let mut attempts = self.attempts.lock().await;
+            let slots = self.fast_slots.read().unwrap_or_else(PoisonError::into_inner);
+            for (key, slot) in slots.iter() {
+                if let Some(state) = attempts.get_mut(key) {
+                    flush_fast_slot(slot, state);
+                    if let Some(ceiling) = self.fast_ceiling(state) {
+                        slot.release(state.attempts.count(), ceiling, state.quota.max_requests);
+                    }
+                }
+            }
+        }
+
+        match &self.store {
+            Some(store) => store.flush().await,
+            None => Ok(()),
+        }
+    }
+
+    /// Spawn a background task that cleans up old entries every `interval`
+    ///
+    /// Scans are skipped while fewer than `cleanup_min_keys` keys are tracked,
+    /// so the next tick after the map grows past the threshold cleans promptly.
+    /// The task exits once the limiter is quiesced.
+    pub fn spawn_cleanup(&self, interval: Duration) -> JoinHandle<()> {
+        let limiter = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                if limiter.is_draining() {
+                    break;
+                }
+
+                limiter.cleanup_if_due().await;
+            }
+        })
+    }
+
+    /// Run `cleanup` unless fewer than `cleanup_min_keys` keys are tracked
+    pub(crate) async fn cleanup_if_due(&self) {
+        let tracked = self.attempts.lock().await.len();
+        if tracked >= self.config.cleanup_min_keys {
+            self.cleanup().await;
+        }
+    }
+}
+
+impl<S> KeyedRateLimiter<String, S>
+where
+    S: BuildHasher + Clone + Send + Sync + 'static,
+{
+    /// Build request keys with a custom extractor instead of client IP and path
+    pub fn with_key_extractor(mut self, extractor: impl KeyExtractor + 'static) -> Self {
+        self.key_extractor = Arc::new(extractor);
+        self
+    }
+
+    /// [`snapshot`](Self::snapshot) encoded with [`BinaryCodec`], e.g. to
+    /// write to disk before a restart
+    pub async fn save_snapshot(&self) -> Vec<u8> {
+        self.save_snapshot_with::<BinaryCodec>().await
+    }
+
+    /// [`snapshot`](Self::snapshot) encoded with `C`, such as
+    /// [`JsonCodec`](crate::JsonCodec) for snapshots meant to be read
+    pub async fn save_snapshot_with<C: SnapshotCodec>(&self) -> Vec<u8> {
+        C::encode(&self.snapshot().await)
+    }
+
+    /// [`restore`](Self::restore) from bytes written by `save_snapshot`
+    pub async fn load_snapshot(&self, bytes: &[u8]) -> Result<(), SnapshotError> {
+        self.load_snapshot_with::<BinaryCodec>(bytes).await
+    }
+
+    /// [`restore`](Self::restore) from bytes written by
+    /// `save_snapshot_with::<C>`
+    ///
+    /// State is only replaced once the bytes decode, so a corrupt or
+    /// unsupported snapshot leaves the limiter as it was.
+    pub async fn load_snapshot_with<C: SnapshotCodec>(&self, bytes: &[u8]) -> Result<(), SnapshotError> {
+        self.restore(C::decode(bytes)?).await;
+        Ok(())
+    }
+
+    /// Decide whether a request is allowed, recording the attempt if it is
+    ///
+    /// This is the middleware's decision separated from response construction,
+    /// so it can be asserted on in tests or reused outside Axum. The key comes
+    /// from the configured [`KeyExtractor`] and the client IP from
+    /// [`client_ip`]. The middleware resolves `forwarded_ip` into a
+    /// [`ClientIp`](crate::ClientIp) extension first; callers using a
+    /// forwarding strategy outside the middleware insert it themselves.
+    pub fn decide(&self, request: &Request<Body>) -> impl Future<Output = RateLimitDecision> + Send + '_ {
+        // Build the key up front so the future doesn't borrow the request
+        let ip = client_ip(request);
+        let path = self.config.path_normalization.normalize(request.uri().path());
+        let group = self.config.endpoint_groups.iter()
+            .find(|group| group.matches(&path));
+        let (key, quota) = match group {
+            Some(group) => (composite_key(&[&display_ip(ip), "group", &group.name]), group.quota),
+            None => (self.key_extractor.extract(request), self.quota_for_ip(ip)),
+        };
+        let options = match request.method() {
+            &Method::OPTIONS => self.config.options_requests,
+            _ => OptionsPolicy::Count,
+        };
+        let key = match options {
+            OptionsPolicy::Separate => composite_key(&[&key, "options"]),
+            _ => key,
+        };
+        let user_agent_action = self.user_agent_rules.action(request);
+        let now = self.clock.now();
+        let checked = self.config.enabled && !self.is_draining() && options != OptionsPolicy::Exempt;
+        let path_verdict = match (&self.path_scans, ip) {
+            (Some(scans), Some(ip)) if checked => scans.observe(ip, &path, now),
+            _ => PathVerdict::Allow,
+        };
+        let mut levels = self.enclosing_levels(ip);
+        let priority = self.config.priority.as_ref().and_then(|priority| {
+            let value = request.headers().get(priority.header.as_str())?.to_str().ok()?;
+            priority.level(value)
         });
+        let key = match priority {
+            Some((name, level)) => {
+                if level.bypass_unless_saturated {
+                    levels.retain(|(level_key, _)| level_key == GLOBAL_KEY);
+                }
+                composite_key(&[&key, "priority", name])
+            }
+            None => key,
+        };
+        let priority = priority.map(|(_, level)| level);
+        let span = telemetry::decision_span(self.config.log_key(&key));
+
+        telemetry::traced(span.clone(), async move {
+            let (key, levels) = (&key, &levels);
+            if options == OptionsPolicy::Exempt {
+                return RateLimitDecision::allow(key.clone(), quota.max_requests, DecisionReason::Exempt);
+            }
+            let mut quota = self.resolved_quota(key.as_str(), quota).await;
+            match priority {
+                Some(level) if level.bypass_unless_saturated => quota.max_requests = u32::MAX,
+                Some(level) => {
+                    quota.max_requests = (f64::from(quota.max_requests) * level.multiplier.max(0.0)) as u32;
+                }
+                None => {}
+            }
+            match user_agent_action {
+                Some(UserAgentAction::Block) => {
+                    return RateLimitDecision::reject(key.clone(), DecisionReason::Blocked, None);
+                }
+                Some(UserAgentAction::Multiplier(factor)) => {
+                    quota.max_requests = (f64::from(quota.max_requests) * factor.max(0.0)) as u32;
+                }
+                None => {}
+            }
+            if let Some(decision) = self.path_rejection(key, path_verdict, now) {
+                return decision;
+            }
+            if self.leaky_buckets.is_some() {
+                return self.shape(key.as_str()).await;
+            }
+            telemetry::record_quota(&span, quota);
+            let check = move || async move { enclosing_tiers(self.check_levels(key, quota, levels).await, levels) };
+            match self.config.mode {
+                RateLimitMode::Enforce | RateLimitMode::Advisory => check().await,
+                RateLimitMode::Delay { max_wait_secs, max_queue_depth } => {
+                    let max_wait = Duration::from_secs(max_wait_secs);
+                    self.wait_for_slot(key, max_wait, max_queue_depth, check).await
+                }
+            }
+        })
+    }
+
+    /// Rejection for a request whose IP is over
+    /// `max_distinct_paths_per_window`, if it is
+    fn path_rejection(&self, key: &str, verdict: PathVerdict, now: u64) -> Option<RateLimitDecision> {
+        let decision = match verdict {
+            PathVerdict::Allow => return None,
+            PathVerdict::Over(paths) => {
+                let limit = self.path_scans.as_ref().map_or(0, |scans| scans.limit());
+                RateLimitDecision::reject(key.to_string(), DecisionReason::Exceeded, None)
+                    .with_detail(RejectionDetail::DistinctPaths {
+                        paths,
+                        limit,
+                        window_secs: self.config.rate_window_secs,
+                    })
+            }
+            PathVerdict::Banned(until) => {
+                let until_wall = self.clock.wall_time(until);
+                RateLimitDecision::reject(key.to_string(), DecisionReason::Banned(until_wall), Some(until - now))
+                    .with_detail(RejectionDetail::Banned { tier: LimitTier::Key, until: until_wall })
+            }
+        };
+        log_at!(self.rejection_level, key = %self.config.log_key(key), reason = ?decision.reason,
+            "Distinct path limit exceeded");
+        Some(decision)
+    }
+
+    /// Client IPs currently over `max_distinct_paths_per_window`, or banned
+    /// for it, in no particular order
+    pub fn path_scanners(&self) -> Vec<IpAddr> {
+        self.path_scans.as_ref()
+            .map_or_else(Vec::new, |scans| scans.flagged(self.clock.now()))
+    }
+
+    /// Subnet and global keys a request is also limited under, per
+    /// `subnet_limit` and `global_limit`, in the order `enclosing_tiers`
+    /// expects
+    fn enclosing_levels(&self, ip: Option<IpAddr>) -> Vec<(String, Quota)> {
+        let mut levels = Vec::new();
+        if let (Some(ip), Some(quota)) = (ip, self.config.subnet_limit) {
+            let key = subnet_key(ip, self.config.subnet_prefix_v4, self.config.subnet_prefix_v6);
+            levels.push((key, quota));
+        }
+        if let Some(quota) = self.config.global_limit {
+            levels.push((GLOBAL_KEY.to_string(), quota));
+        }
+        levels
+    }
+
+    /// Count a request's body against `body_hash_quota` if its path is one of
+    /// `body_hash_paths`, returning the request with its body rebuilt
+    ///
+    /// Returns no decision when body limiting doesn't apply to the request.
+    /// A body shorter than its declared length fails with `400 Bad Request`.
+    async fn check_body_hash(
+        &self,
+        key: &str,
+        request: Request<Body>,
+    ) -> Result<(Request<Body>, Option<RateLimitDecision>), StatusCode> {
+        let Some(quota) = self.config.body_hash_quota else {
+            return Ok((request, None));
+        };
+        if !self.config.body_hash_paths.iter().any(|path| path == request.uri().path()) {
+            return Ok((request, None));
+        }
+
+        // Streaming and oversized bodies only get the regular limit
+        let declared_len = request.headers().get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<usize>().ok());
+        if declared_len.is_none_or(|len| len > self.config.body_hash_max_bytes) {
+            return Ok((request, None));
+        }
+
+        let (parts, body) = request.into_parts();
+        let bytes = body::to_bytes(body, self.config.body_hash_max_bytes).await
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+        let body_key = composite_key(&[key, "body", &format!("{:016x}", fnv1a_bytes(&bytes))]);
+
+        let decision = self.check_with_quota(&body_key, quota).await;
+        Ok((Request::from_parts(parts, Body::from(bytes)), Some(decision)))
+    }
+
+    /// Build the response for a rejected request
+    ///
+    /// Requests rejected while draining get `503 Service Unavailable` and keys
+    /// refused for control characters `400 Bad Request`. A
+    /// `Retry-After` header is set when the decision knows when to retry.
+    async fn rejection_response(&self, decision: RateLimitDecision) -> Response {
+        let status = match decision.reason {
+            DecisionReason::Draining => StatusCode::SERVICE_UNAVAILABLE,
+            DecisionReason::InvalidKey => StatusCode::BAD_REQUEST,
+            DecisionReason::Blocked => StatusCode::FORBIDDEN,
+            _ => StatusCode::TOO_MANY_REQUESTS,
+        };
+        let mut response = status.into_response();
+        response.headers_mut().extend(self.limit_headers(&decision).await);
+        if let Some((name, value)) = &self.policy_header {
+            response.headers_mut().insert(name, value.clone());
+        }
+        if let Some(value) = &self.debug_header {
+            response.headers_mut().insert(X_RATELIMIT_DEBUG, value.clone());
+        }
+        if let Some(detail) = decision.detail.filter(|_| self.config.rejection_detail_header) {
+            if let Ok(value) = HeaderValue::from_str(&detail.to_string()) {
+                response.headers_mut().insert(X_RATELIMIT_DETAIL, value);
+            }
+        }
+
+        let retry_after = decision.retry_after
+            .map(|retry_after| retry_after.saturating_add(jitter(self.config.retry_after_jitter_secs)))
+            .map(|retry_after| self.config.capped_retry_after(retry_after));
+        if self.config.json_rejection_body {
+            let (quota, _) = self.reported_quota(&decision).await;
+            *response.body_mut() = Body::from(rejection_body(&decision, quota, retry_after));
+            response.headers_mut()
+                .insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        }
+        if let Some(retry_after) = retry_after {
+            let value = match self.config.retry_after_format {
+                RetryAfterFormat::Seconds => retry_after.to_string(),
+                RetryAfterFormat::HttpDate => {
+                    let now = self.clock.wall_time(self.clock.now());
+                    clock::http_date(now.saturating_add(retry_after))
+                }
+            };
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                response.headers_mut().insert(header::RETRY_AFTER, value);
+            }
+        }
+
+        response.extensions_mut().insert(decision);
+        response
+    }
+
+    /// Throttle level for a request per `soft_throttle_ratio`, if set, against
+    /// the tightest level's limit as in `reported_quota`
+    pub(crate) async fn throttle_level(&self, decision: &RateLimitDecision) -> Option<ThrottleLevel> {
+        let ratio = self.config.soft_throttle_ratio?;
+        if !decision.allowed {
+            return Some(ThrottleLevel::Hard);
+        }
+        if decision.reason != DecisionReason::WithinLimit {
+            return Some(ThrottleLevel::None);
+        }
+        let limit = self.reported_quota(decision).await.0.max_requests;
+        let used = limit.saturating_sub(decision.remaining);
+        Some(if f64::from(used) >= ratio * f64::from(limit) { ThrottleLevel::Soft } else { ThrottleLevel::None })
+    }
+
+    /// Quota a decision's `remaining` counts against, with the oldest attempt
+    /// in its window when known
+    ///
+    /// This is the tightest level's: the enclosing level in `limited_by` when
+    /// set, else the quota last applied to the key. Enclosing levels and
+    /// store-backed keys don't report an oldest attempt.
+    async fn reported_quota(&self, decision: &RateLimitDecision) -> (Quota, Option<u64>) {
+        if let Some((_, quota)) = decision.limited_by {
+            return (quota, None);
+        }
+        self.attempts.lock().await
+            .get(decision.key.as_str())
+            .map_or((self.config.quota(), None), |state| (state.quota, state.attempts.oldest()))
+    }
+
+    /// `rate_limit_headers` for a decision
+    ///
+    /// The headers describe the tightest level, as in
+    /// `reported_quota`, so the limit and remaining
+    /// always belong together. The reset is the time until the key's oldest
+    /// attempt leaves the window, or the retry delay for a rejection; an
+    /// enclosing level or store-backed key that allowed the request reports
+    /// a full window.
+    async fn limit_headers(&self, decision: &RateLimitDecision) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let (legacy, draft) = match self.config.rate_limit_headers {
+            RateLimitHeaders::Off => return headers,
+            RateLimitHeaders::Legacy => (true, false),
+            RateLimitHeaders::Draft => (false, true),
+            RateLimitHeaders::Both => (true, true),
+        };
+        if matches!(decision.reason,
+            DecisionReason::Disabled | DecisionReason::WarmingUp | DecisionReason::Exempt | DecisionReason::Draining
+                | DecisionReason::InvalidKey | DecisionReason::Blocked | DecisionReason::Unsampled
+                | DecisionReason::Contended)
+        {
+            return headers;
+        }
+
+        let now = self.clock.now();
+        let (quota, oldest) = self.reported_quota(decision).await;
+        let reset = match decision.retry_after {
+            Some(retry_after) => self.config.capped_retry_after(retry_after),
+            None => oldest.map_or(quota.window_secs, |oldest| {
+                oldest.saturating_add(quota.window_secs).saturating_sub(now)
+            }),
+        };
+
+        let mut insert = |name: &'static str, value: String| {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(name, value);
+            }
+        };
+        if legacy {
+            insert("x-ratelimit-limit", quota.max_requests.to_string());
+            insert("x-ratelimit-remaining", decision.remaining.to_string());
+            insert("x-ratelimit-reset", reset.to_string());
+        }
+        if draft {
+            insert("ratelimit-policy", format!("\"default\";q={};w={}", quota.max_requests, quota.window_secs));
+            insert("ratelimit", format!("\"default\";r={};t={}", decision.remaining, reset));
+        }
+        headers
+    }
+}
+
+/// Attribute a decision limited by one of `enclosing_levels` to the subnet
+/// or global tier
+fn enclosing_tiers(decision: RateLimitDecision, levels: &[(String, Quota)]) -> RateLimitDecision {
+    decision.map_tier(|tier| match tier {
+        LimitTier::Level(i) if levels.get(i).is_some_and(|(key, _)| key == GLOBAL_KEY) => LimitTier::Global,
+        LimitTier::Level(_) => LimitTier::Subnet,
+        tier => tier,
+    })
+}
+
+/// `json_rejection_body` for a rejection
+fn rejection_body(decision: &RateLimitDecision, quota: Quota, retry_after: Option<u64>) -> String {
+    let error = match decision.reason {
+        DecisionReason::Banned(_) => "banned",
+        DecisionReason::CircuitOpen => "circuit_open",
+        DecisionReason::Draining => "draining",
+        DecisionReason::InvalidKey => "invalid_key",
+        DecisionReason::Blocked => "blocked",
+        DecisionReason::Contended => "contended",
+        DecisionReason::Vetoed => "vetoed",
+        DecisionReason::Exceeded | DecisionReason::WithinLimit | DecisionReason::Disabled
+            | DecisionReason::WarmingUp | DecisionReason::Exempt | DecisionReason::Unsampled => "rate_limited",
+    };
+    serde_json::json!({
+        "error": error,
+        "limit": quota.max_requests,
+        "window": quota.window_secs,
+        "remaining": decision.remaining,
+        "retry_after": retry_after,
+    }).to_string()
+}
+
+/// Count a finished check towards a key's circuit breaker
+fn record_circuit<K, Q>(circuits: &CircuitBreakers<K>, key: &Q, decision: &RateLimitDecision<K>, now: u64)
+where
+    K: Borrow<Q> + Hash + Eq + Clone,
+    Q: Hash + Eq + Display + ToOwned<Owned = K> + ?Sized,
+{
+    match decision.reason {
+        _ if decision.allowed => circuits.record(key, false, now),
+        DecisionReason::Exceeded | DecisionReason::Banned(_) => circuits.record(key, true, now),
+        _ => {}
+    }
+}
+
+/// Whole units in a non-negative cost, saturating at `u32::MAX`
+fn whole_units(cost: f64) -> u32 {
+    // float to int casts saturate, and NaN becomes 0
+    cost.floor() as u32
+}
+
+/// Extractor used until `with_key_extractor` replaces it
+fn default_key_extractor(config: &RateLimitConfig) -> Arc<dyn KeyExtractor> {
+    if config.path_normalization.is_enabled() || config.key_query != KeyQuery::Ignore {
+        Arc::new(NormalizedIpPathKey::new(config.path_normalization).with_query(config.key_query.clone()))
+    } else {
+        Arc::new(IpPathKey)
+    }
+}
+
+/// Random delay of up to `max` seconds
+fn jitter(max: u64) -> u64 {
+    if max == 0 {
+        return 0;
+    }
+    // Each `RandomState` is freshly keyed, which is random enough to spread retries
+    let random = RandomState::new().hash_one(());
+    max.checked_add(1).map_or(random, |range| random % range)
+}
+
+/// Request admitted by the API limiter, pending the handler's response
+pub(crate) struct Admission {
+    decision: RateLimitDecision,
+    started: Instant,
+    over_limit: bool,
+    /// `rate_limit_headers` for the response
+    headers: HeaderMap,
+}
+
+impl Admission {
+    /// Attach the decision, and the advisory header if served over the limit
+    fn finish(self, mut response: Response) -> Response {
+        response.headers_mut().extend(self.headers);
+        response.extensions_mut().insert(self.decision);
+        if self.over_limit {
+            response.headers_mut()
+                .insert("x-ratelimit-advisory", HeaderValue::from_static("over-limit"));
+        }
+        response
+    }
+}
+
+impl RateLimiter {
+    /// Apply the limit to a request, returning it for the handler along with
+    /// its admission, or the rejection response
+    pub(crate) async fn admit(
+        &self,
+        addr: SocketAddr,
+        mut request: Request<Body>,
+    ) -> Result<(Request<Body>, Admission), Response> {
+        if let Some(ip) = forwarded_ip(&request, self.config.forwarded_ip, &self.config.trusted_proxies) {
+            request.extensions_mut().insert(ClientIp(ip));
+        }
+        let decision = self.decide(&request).await;
+        let advisory = self.config.mode == RateLimitMode::Advisory;
+        let mut over_limit = !decision.allowed;
+
+        if over_limit {
+            log_at!(self.rejection_level, "Rate limit exceeded for IP {} on path {}",
+                addr.ip(), request.uri().path());
+            if !advisory {
+                return Err(self.rejection_response(decision).await);
+            }
+        }
+
+        let (mut request, body_decision) = self.check_body_hash(&decision.key, request).await
+            .map_err(IntoResponse::into_response)?;
+        if let Some(body_decision) = body_decision.filter(|body_decision| !body_decision.allowed) {
+            warn!("Repeated body limit exceeded for IP {} on path {}", addr.ip(), request.uri().path());
+            if !advisory {
+                return Err(self.rejection_response(body_decision).await);
+            }
+            over_limit = true;
+        }
+
+        if over_limit {
+            self.advisory_violations.fetch_add(1, Ordering::Relaxed);
+        }
+
+        // Request is within limits (or advisory), proceed
+        if let Some(level) = self.throttle_level(&decision).await {
+            request.extensions_mut().insert(level);
+        }
+        request.extensions_mut().insert(decision.clone());
+        let mut headers = self.limit_headers(&decision).await;
+        if let Some((name, value)) = &self.policy_header {
+            headers.insert(name, value.clone());
+        }
+        if let Some(value) = &self.debug_header {
+            headers.insert(X_RATELIMIT_DEBUG, value.clone());
+        }
+        if self.config.disabled_header && decision.reason == DecisionReason::Disabled {
+            headers.insert(X_RATELIMIT_DISABLED, HeaderValue::from_static("true"));
+        }
+        Ok((request, Admission { decision, started: Instant::now(), over_limit, headers }))
+    }
+
+    /// Count the handler's response status and complete the response
+    pub(crate) async fn finish(&self, admission: Admission, response: Response) -> Response {
+        let status = response.status().as_u16();
+        self.record_response_status(&admission.decision.key, status).await;
+        if let Some(policy) = self.config.refund_policy.filter(|_| !admission.decision.charged.is_empty()) {
+            let latency_ms = u64::try_from(admission.started.elapsed().as_millis()).unwrap_or(u64::MAX);
+            if policy.applies(status, latency_ms) {
+                // Refund every level the request was counted against, not just its own key
+                self.refund_charged(&admission.decision, policy.fraction.clamp(0.0, 1.0)).await;
+            }
+        }
+        admission.finish(response)
+    }
+
+    /// Give back an admitted request's attempts after a later check rejected
+    /// it, completing that check's rejection with this limiter's headers
+    ///
+    /// The headers report the headroom the refund restored.
+    pub(crate) async fn abandon(&self, mut admission: Admission, rejection: Response) -> Response {
+        self.refund_charged(&admission.decision, 1.0).await;
+        // Levels are charged one attempt each, a lone key its whole weight
+        if let Some(refunded) = admission.decision.charged.iter().map(|(_, attempts)| *attempts).min() {
+            admission.decision.remaining = admission.decision.remaining.saturating_add(refunded);
+            admission.decision.charged.clear();
+            admission.headers.extend(self.limit_headers(&admission.decision).await);
+        }
+        admission.finish(rejection)
     }
 }
 
 /// Rate limiting middleware for Axum
+///
+/// The [`RateLimitDecision`] is inserted into the request extensions for
+/// handlers and into the response extensions for outer layers. Handlers
+/// checking the same key again through `check_once` reuse it rather than
+/// counting the request twice.
 pub async fn rate_limit_middleware(
     State(limiter): State<RateLimiter>,
-    ConnectInfo(addr): ConnectInfo<std::net::SocketAddr>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     request: Request<Body>,
     next: Next,
 ) -> Result<Response, StatusCode> {
-    let ip = addr.ip();
-    let path = request.uri().path();
+    match limiter.admit(addr, request).await {
+        Ok((request, admission)) => {
+            let response = next.run(request).await;
+            Ok(limiter.finish(admission, response).await)
+        }
+        Err(rejection) => Ok(rejection),
+    }
+}
+
+/// `RateLimitError::Exceeded` message for a quota
+fn exceeded_message(quota: Quota) -> String {
+    format!("Maximum {} requests per {} seconds exceeded", quota.max_requests, quota.window_secs)
+}
 
-    // Create rate limit key based on IP and path
-    let key = format!("{}:{}", ip, path);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    // Check rate limit
-    match limiter.check_rate_limit(&key).await {
-        Ok(()) => {
-            // Request is within limits, proceed
-            Ok(next.run(request).await)
+    fn limiter_at(config: RateLimitConfig, now: u64) -> RateLimiter {
+        let mut limiter = RateLimiter::new(config);
+        limiter.clock = Clock::at(now);
+        limiter
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bans_near_the_end_of_time_saturate() {
+        let config = RateLimitConfig {
+            max_requests_per_window: 1,
+            ban_threshold: Some(1),
+            ban_duration_secs: u64::MAX,
+            ..RateLimitConfig::default()
+        };
+        let limiter = limiter_at(config, u64::MAX - 5);
+        assert!(limiter.check("key").await.allowed);
+        assert!(!limiter.check("key").await.allowed);
+
+        let banned = limiter.check("key").await;
+        assert!(matches!(banned.reason, DecisionReason::Banned(_)), "{:?}", banned);
+        assert_eq!(banned.retry_after, Some(5));
+        assert!(limiter.status("key").await.banned_until.is_some());
+
+        // The clock saturates at u64::MAX with the attempt still in the window
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert!(!limiter.check("key").await.allowed);
+        limiter.status("key").await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn huge_windows_never_reset() {
+        let config = RateLimitConfig {
+            max_requests_per_window: 2,
+            rate_window_secs: u64::MAX,
+            ..RateLimitConfig::default()
+        };
+        for now in [1, 1_700_000_000, u64::MAX - 1] {
+            let limiter = limiter_at(config.clone(), now);
+            assert!(limiter.check("key").await.allowed);
+            assert!(limiter.check("key").await.allowed);
+            let rejected = limiter.check("key").await;
+            assert!(!rejected.allowed);
+            assert_eq!(rejected.retry_after, Some(u64::MAX - now));
+
+            tokio::time::advance(Duration::from_secs(3600)).await;
+            assert!(!limiter.check("key").await.allowed);
+            assert_eq!(limiter.status("key").await.attempts, 2);
         }
-        Err(RateLimitError::Exceeded(_)) => {
-            warn!("Rate limit exceeded for IP {} on path {}", ip, path);
-            Err(StatusCode::TOO_MANY_REQUESTS)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_after_headers_saturate() {
+        for format in [RetryAfterFormat::Seconds, RetryAfterFormat::HttpDate] {
+            let config = RateLimitConfig {
+                retry_after_format: format,
+                retry_after_jitter_secs: u64::MAX,
+                rate_limit_headers: RateLimitHeaders::Both,
+                json_rejection_body: true,
+                ..RateLimitConfig::default()
+            };
+            let limiter = limiter_at(config, u64::MAX - 1);
+            let decision = RateLimitDecision::reject("key".to_string(), DecisionReason::Exceeded, Some(u64::MAX));
+            let response = limiter.rejection_response(decision).await;
+            assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+            assert!(response.headers().contains_key(header::RETRY_AFTER));
         }
-        Err(_) => {
-            // Other errors, allow request but log
-            warn!("Rate limit check failed for IP {} on path {}", ip, path);
-            Ok(next.run(request).await)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refused_delay_queue_entries_leave_nothing_behind() {
+        let limiter = RateLimiter::new(RateLimitConfig { max_requests_per_window: 1, ..RateLimitConfig::default() });
+        let quota = limiter.config.quota();
+        for key in ["a", "b", "c"] {
+            assert!(limiter.check(key).await.allowed);
+            let decision = limiter.check_with_delay(key, quota, Duration::from_secs(3600), 0).await;
+            assert_eq!(decision.reason, DecisionReason::Exceeded);
         }
+        assert!(limiter.queued.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn decisions_follow_the_injected_clock_through_window_expiry() {
+        let config = RateLimitConfig {
+            max_requests_per_window: 2,
+            rate_window_secs: 60,
+            ..RateLimitConfig::default()
+        };
+        let limiter = limiter_at(config, 1_700_000_000);
+        let request = || {
+            let mut request = Request::get("/api").body(Body::empty()).unwrap();
+            request.extensions_mut().insert(ConnectInfo("10.0.0.1:1000".parse::<SocketAddr>().unwrap()));
+            request
+        };
+
+        assert_eq!(limiter.decide(&request()).await.remaining, 1);
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(limiter.decide(&request()).await.allowed);
+        let rejected = limiter.decide(&request()).await;
+        assert_eq!(rejected.reason, DecisionReason::Exceeded);
+        assert_eq!(rejected.key, "10.0.0.1:/api");
+        assert_eq!(rejected.retry_after, Some(30));
+
+        // The first attempt leaves the window 60 seconds after it was made
+        tokio::time::advance(Duration::from_secs(31)).await;
+        assert!(limiter.decide(&request()).await.allowed);
+        assert_eq!(limiter.snapshot().await["10.0.0.1:/api"], [1_700_000_030, 1_700_000_061]);
     }
 }
@@ -1,127 +1,947 @@
 //! Login-specific rate limiter with account lockout
 
-use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::borrow::Cow;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex as StdMutex, PoisonError, RwLock};
+use std::time::Duration;
 use std::collections::HashMap;
 use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{header, HeaderMap, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    body::{self, Body, Bytes},
+};
 use tracing::{info, warn};
 
-use crate::{config::RateLimitConfig, error::RateLimitError};
+use crate::{
+    audit::{LoginAuditEvent, LoginAuditSink, LoginEventKind},
+    clock::Clock,
+    config::{ControlCharPolicy, DistinctPathAction, LoginBodyFormat, RateLimitConfig},
+    error::RateLimitError,
+    extractor::composite_key,
+    limiter::X_RATELIMIT_DISABLED,
+    metrics,
+    paths::{PathScans, PathVerdict},
+    sanitize::has_control_chars,
+};
 
 /// Login-specific rate limiter with account lockout
 #[derive(Clone)]
 pub struct LoginRateLimiter {
     config: RateLimitConfig,
     login_attempts: Arc<Mutex<HashMap<String, LoginAttemptInfo>>>,
+    /// Attempts in flight per identifier, for `max_concurrent_login_attempts`
+    in_flight: Arc<StdMutex<HashMap<String, u32>>>,
+    /// Identifiers tried per source IP, per `max_login_identifiers_per_ip`
+    identifier_scans: Option<Arc<PathScans>>,
+    /// Monotonic lockout expiry per locked key, per `login_lockout_fast_path`
+    lockouts: Option<Arc<RwLock<HashMap<String, u64>>>>,
+    clock: Clock,
+    audit_sink: Option<Arc<dyn LoginAuditSink>>,
+}
+
+/// An in-flight login attempt, as returned by
+/// [`LoginRateLimiter::begin_login_attempt`]
+///
+/// Holds one of the identifier's `max_concurrent_login_attempts` slots until
+/// dropped, including when the holding task panics or is cancelled. Keep it
+/// alive while verifying the credentials.
+#[derive(Debug)]
+pub struct LoginAttemptGuard {
+    result: LoginCheckResult,
+    _slot: Option<InFlightSlot>,
+}
+
+impl LoginAttemptGuard {
+    /// Budget reported by the check, as from `check_login_attempt`
+    pub fn result(&self) -> &LoginCheckResult {
+        &self.result
+    }
+}
+
+/// One identifier's slot among its in-flight login attempts, released on drop
+#[derive(Debug)]
+struct InFlightSlot {
+    in_flight: Arc<StdMutex<HashMap<String, u32>>>,
+    identifier: String,
+}
+
+impl InFlightSlot {
+    /// Take a slot unless `max` attempts are already in flight
+    fn enter(in_flight: &Arc<StdMutex<HashMap<String, u32>>>, identifier: &str, max: u32) -> Option<Self> {
+        let mut counts = in_flight.lock().unwrap_or_else(PoisonError::into_inner);
+        // Refused attempts leave no entry behind, even with a `max` of 0
+        if counts.get(identifier).copied().unwrap_or(0) >= max {
+            return None;
+        }
+        *counts.entry(identifier.to_string()).or_insert(0) += 1;
+
+        Some(Self {
+            in_flight: Arc::clone(in_flight),
+            identifier: identifier.to_string(),
+        })
+    }
+}
+
+impl Drop for InFlightSlot {
+    fn drop(&mut self) {
+        let mut counts = self.in_flight.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(count) = counts.get_mut(&self.identifier) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.identifier);
+            }
+        }
+    }
+}
+
+/// Lockout thresholds for an identifier's password login or action attempts
+#[derive(Debug, Clone, Copy)]
+struct Thresholds {
+    max_attempts: u32,
+    lockout_secs: u64,
 }
 
 #[derive(Debug)]
 struct LoginAttemptInfo {
     attempts: Vec<u64>,
     locked_until: Option<u64>,
+    /// End of the reduced `post_lockout_attempts` budget after a lockout
+    probation_until: Option<u64>,
+}
+
+/// Login state of one identifier, as returned by
+/// [`LoginRateLimiter::snapshot`]
+///
+/// Timestamps are the limiter's monotonic seconds, which track unix time from
+/// when the limiter was created.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LoginSnapshot {
+    /// Failed attempt timestamps
+    pub attempts: Vec<u64>,
+    /// End of the lockout, if locked
+    pub locked_until: Option<u64>,
+    /// End of the reduced `post_lockout_attempts` budget, if on probation
+    pub probation_until: Option<u64>,
+}
+
+/// Login attempt budget for an identifier
+///
+/// Failed attempts are recorded separately through `record_failed_attempt`,
+/// so a result from `check_login_attempt` describes the state *before* the
+/// attempt being checked: if it then fails, one fewer attempt remains than
+/// reported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoginCheckResult {
+    /// Failed attempts in the current window
+    pub attempts_used: u32,
+    /// Failed attempts left before lockout
+    pub attempts_remaining: u32,
+    /// Unix timestamp when the oldest failed attempt leaves the window
+    pub window_resets_at: Option<u64>,
+    /// Unix timestamp until which the account is locked
+    ///
+    /// Always `None` from `check_login_attempt`, which reports lockouts as
+    /// `RateLimitError::AccountLocked`.
+    pub locked_until: Option<u64>,
 }
 
 impl LoginRateLimiter {
     /// Create new login rate limiter
     pub fn new(config: RateLimitConfig) -> Self {
+        config.warn_if_disabled("Login");
+        let identifier_scans = config.max_login_identifiers_per_ip.map(|limit| {
+            Arc::new(PathScans::new("login identifiers", limit, config.rate_window_secs,
+                DistinctPathAction::Reject, 0))
+        });
+        let lockouts = config.login_lockout_fast_path.then(|| Arc::new(RwLock::new(HashMap::new())));
         Self {
             config,
             login_attempts: Arc::new(Mutex::new(HashMap::new())),
+            in_flight: Arc::new(StdMutex::new(HashMap::new())),
+            identifier_scans,
+            lockouts,
+            clock: Clock::new(),
+            audit_sink: None,
         }
     }
 
+    /// Send structured audit events for attempts, failures, lockouts, unlocks
+    /// and clears to `sink`, alongside the usual logs
+    pub fn with_audit_sink(mut self, sink: impl LoginAuditSink + 'static) -> Self {
+        self.audit_sink = Some(Arc::new(sink));
+        self
+    }
+
     /// Check login attempt for user
-    pub async fn check_login_attempt(&self, identifier: &str) -> Result<(), RateLimitError> {
+    pub async fn check_login_attempt(&self, identifier: &str) -> Result<LoginCheckResult, RateLimitError> {
+        self.check_attempt(identifier, None, None).await
+    }
+
+    /// `check_login_attempt` for an attempt from `source`, counted toward
+    /// `max_login_identifiers_per_ip`
+    pub async fn check_login_attempt_from(
+        &self,
+        identifier: &str,
+        source: IpAddr,
+    ) -> Result<LoginCheckResult, RateLimitError> {
+        self.check_attempt(identifier, None, Some(source)).await
+    }
+
+    /// Source IPs that tried more than `max_login_identifiers_per_ip`
+    /// identifiers in the current window, in no particular order
+    pub fn flagged_login_sources(&self) -> Vec<IpAddr> {
+        self.identifier_scans.as_ref()
+            .map_or_else(Vec::new, |scans| scans.flagged(self.clock.now()))
+    }
+
+    /// Check login attempt for user and hold one of its
+    /// `max_concurrent_login_attempts` slots until the returned guard drops
+    ///
+    /// Rejects with `RateLimitError::TooManyConcurrentLogins` while the
+    /// identifier already has that many attempts in flight; without the
+    /// setting this is `check_login_attempt` with a guard that holds nothing.
+    pub async fn begin_login_attempt(&self, identifier: &str) -> Result<LoginAttemptGuard, RateLimitError> {
+        self.begin_attempt(identifier, None).await
+    }
+
+    /// `begin_login_attempt` for a request from `source`
+    async fn begin_attempt(&self, identifier: &str, source: Option<IpAddr>) -> Result<LoginAttemptGuard, RateLimitError> {
+        let slot = match self.config.max_concurrent_login_attempts {
+            Some(max) if self.config.enabled => {
+                let slot = InFlightSlot::enter(&self.in_flight, identifier, max);
+                if slot.is_none() {
+                    warn!("Too many login attempts in flight for: {}", self.config.log_key(identifier));
+                    return Err(RateLimitError::TooManyConcurrentLogins);
+                }
+                slot
+            }
+            _ => None,
+        };
+        let result = self.check_attempt(identifier, None, source).await?;
+        Ok(LoginAttemptGuard { result, _slot: slot })
+    }
+
+    /// Check an attempt at a login action other than password login, such as
+    /// `mfa`, against the action's `login_actions` thresholds
+    ///
+    /// Each action has its own attempts and lockout per identifier, tracked
+    /// under the key `<action>:<identifier>` (escaped as by `composite_key`),
+    /// which is also the identifier reported in logs, audit events and
+    /// snapshots. Record and clear the action's failures with
+    /// `record_failed_action` and `clear_action_attempts`.
+    pub async fn check_login_action(&self, identifier: &str, action: &str) -> Result<LoginCheckResult, RateLimitError> {
+        self.check_attempt(identifier, Some(action), None).await
+    }
+
+    /// `check_login_attempt` for an optional action and a request from
+    /// `source`
+    async fn check_attempt(
+        &self,
+        identifier: &str,
+        action: Option<&str>,
+        source: Option<IpAddr>,
+    ) -> Result<LoginCheckResult, RateLimitError> {
+        let thresholds = self.thresholds(action);
         if !self.config.enabled {
-            return Ok(());
+            return Ok(LoginCheckResult {
+                attempts_used: 0,
+                attempts_remaining: thresholds.max_attempts,
+                window_resets_at: None,
+                locked_until: None,
+            });
+        }
+        let key = attempt_key(identifier, action);
+        let account = identifier;
+        let identifier = &*key;
+
+        if self.config.control_chars == ControlCharPolicy::Reject && has_control_chars(identifier) {
+            warn!("Rejected login identifier with control characters: {}", self.config.log_key(identifier));
+            return Err(RateLimitError::InvalidKey);
         }
 
+        let now = self.clock.now();
+        if let (Some(scans), Some(source)) = (&self.identifier_scans, source) {
+            // Actions on an account count as the same identifier
+            if let PathVerdict::Over(_) = scans.observe(source, account, now) {
+                return Err(RateLimitError::TooManyLoginIdentifiers);
+            }
+        }
+        self.check_lockout_fast(identifier, now)?;
+
         let mut attempts = self.login_attempts.lock().await;
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
 
         let info = attempts.entry(identifier.to_string())
             .or_insert(LoginAttemptInfo {
                 attempts: Vec::new(),
                 locked_until: None,
+                probation_until: None,
             });
 
-        // Check if account is locked
+        self.refresh_lockout(identifier, info, now, source)?;
+
+        // Check if we should lock the account
+        let max_attempts = self.max_attempts(info, now, thresholds);
+        if info.attempts.len() >= max_attempts as usize {
+            return Err(self.lock_account(identifier, info, now, thresholds, source));
+        }
+
+        let result = self.check_result(&info.attempts, None, max_attempts);
+        self.audit(identifier, LoginEventKind::Attempt, source, Some(result.attempts_remaining), None);
+        Ok(result)
+    }
+
+    /// Record a failed login attempt and lock the account once it reaches
+    /// `max_login_attempts`, as one locked operation
+    ///
+    /// Unlike a `check_login_attempt` / `record_failed_attempt` pair, concurrent
+    /// failures can't all pass the check before any is recorded, so the
+    /// lockout triggers at exactly the threshold. Failures against a locked
+    /// account are rejected without being recorded. Returns the budget left
+    /// after this failure.
+    pub async fn check_and_record_failure(&self, identifier: &str) -> Result<LoginCheckResult, RateLimitError> {
+        self.check_and_record(identifier, None).await
+    }
+
+    /// `check_and_record_failure` for a login action, see
+    /// `check_login_action`
+    pub async fn check_and_record_action_failure(
+        &self,
+        identifier: &str,
+        action: &str,
+    ) -> Result<LoginCheckResult, RateLimitError> {
+        self.check_and_record(identifier, Some(action)).await
+    }
+
+    /// `check_and_record_failure` for an optional action
+    async fn check_and_record(&self, identifier: &str, action: Option<&str>) -> Result<LoginCheckResult, RateLimitError> {
+        let thresholds = self.thresholds(action);
+        if !self.config.enabled {
+            return Ok(self.check_result(&[], None, thresholds.max_attempts));
+        }
+        let key = attempt_key(identifier, action);
+        let identifier = &*key;
+
+        if self.config.control_chars == ControlCharPolicy::Reject && has_control_chars(identifier) {
+            warn!("Rejected login identifier with control characters: {}", self.config.log_key(identifier));
+            return Err(RateLimitError::InvalidKey);
+        }
+
+        let now = self.clock.now();
+        self.check_lockout_fast(identifier, now)?;
+        let mut attempts = self.login_attempts.lock().await;
+
+        let info = attempts.entry(identifier.to_string())
+            .or_insert(LoginAttemptInfo {
+                attempts: Vec::new(),
+                locked_until: None,
+                probation_until: None,
+            });
+
+        self.refresh_lockout(identifier, info, now, None)?;
+
+        info.attempts.push(now);
+        info!("Failed login attempt recorded for: {}", self.config.log_key(identifier));
+
+        let max_attempts = self.max_attempts(info, now, thresholds);
+        let remaining = max_attempts.saturating_sub(info.attempts.len() as u32);
+        self.audit(identifier, LoginEventKind::Failure, None, Some(remaining), None);
+        if info.attempts.len() >= max_attempts as usize {
+            return Err(self.lock_account(identifier, info, now, thresholds, None));
+        }
+
+        Ok(self.check_result(&info.attempts, None, max_attempts))
+    }
+
+    /// Reject attempts on a locked account, clearing expired lockouts and
+    /// pruning attempts outside the window
+    fn refresh_lockout(
+        &self,
+        identifier: &str,
+        info: &mut LoginAttemptInfo,
+        now: u64,
+        source: Option<IpAddr>,
+    ) -> Result<(), RateLimitError> {
         if let Some(locked_until) = info.locked_until {
             if now < locked_until {
                 let remaining = locked_until - now;
                 warn!("Login attempt for locked account: {} ({} seconds remaining)",
-                    identifier, remaining);
-                return Err(RateLimitError::AccountLocked(locked_until));
-            } else {
-                // Lockout expired, clear it
-                info.locked_until = None;
-                info.attempts.clear();
+                    self.config.log_key(identifier), remaining);
+                return Err(RateLimitError::AccountLocked(self.clock.wall_time(locked_until)));
+            }
+
+            // Lockout expired, clear it and start any probation
+            info.locked_until = None;
+            self.note_lockout(identifier, None);
+            info.attempts.clear();
+            if self.config.post_lockout_attempts.is_some() {
+                info.probation_until = Some(locked_until.saturating_add(self.config.rate_window_secs));
             }
+            self.audit(identifier, LoginEventKind::Unlock, source, None, None);
         }
 
         // Remove old attempts
         let window_start = now.saturating_sub(self.config.rate_window_secs);
         info.attempts.retain(|&t| t > window_start);
+        Ok(())
+    }
 
-        // Check if we should lock the account
-        if info.attempts.len() >= self.config.max_login_attempts as usize {
-            info.locked_until = Some(now + self.config.lockout_duration_secs);
-            warn!("Account locked due to too many attempts: {}", identifier);
-            return Err(RateLimitError::AccountLocked(info.locked_until.unwrap()));
+    /// Reject a key locked in the `login_lockout_fast_path` copy, which an
+    /// expired or missing lockout leaves to the locked path
+    fn check_lockout_fast(&self, identifier: &str, now: u64) -> Result<(), RateLimitError> {
+        let Some(lockouts) = &self.lockouts else {
+            return Ok(());
+        };
+        let locked_until = lockouts.read().unwrap_or_else(PoisonError::into_inner).get(identifier).copied();
+        match locked_until {
+            Some(locked_until) if now < locked_until => {
+                warn!("Login attempt for locked account: {} ({} seconds remaining)",
+                    self.config.log_key(identifier), locked_until - now);
+                Err(RateLimitError::AccountLocked(self.clock.wall_time(locked_until)))
+            }
+            _ => Ok(()),
         }
+    }
 
-        Ok(())
+    /// Mirror a key's lockout into the `login_lockout_fast_path` copy
+    fn note_lockout(&self, identifier: &str, locked_until: Option<u64>) {
+        let Some(lockouts) = &self.lockouts else {
+            return;
+        };
+        let mut lockouts = lockouts.write().unwrap_or_else(PoisonError::into_inner);
+        match locked_until {
+            Some(locked_until) => lockouts.insert(identifier.to_string(), locked_until),
+            None => lockouts.remove(identifier),
+        };
+    }
+
+    /// Thresholds for `action`, or the default password login ones
+    fn thresholds(&self, action: Option<&str>) -> Thresholds {
+        let limit = action.and_then(|action| self.config.login_actions.get(action));
+        Thresholds {
+            max_attempts: limit.map_or(self.config.max_login_attempts, |limit| limit.max_attempts),
+            lockout_secs: limit.and_then(|limit| limit.lockout_duration_secs)
+                .unwrap_or(self.config.lockout_duration_secs),
+        }
+    }
+
+    /// Failed attempts allowed before lockout, reduced during probation
+    fn max_attempts(&self, info: &LoginAttemptInfo, now: u64, thresholds: Thresholds) -> u32 {
+        // A lockout not yet cleared by `refresh_lockout` still implies probation
+        let probation_until = info.probation_until
+            .max(info.locked_until.map(|until| until.saturating_add(self.config.rate_window_secs)));
+        match self.config.post_lockout_attempts {
+            Some(attempts) if probation_until.is_some_and(|until| now < until) => attempts,
+            _ => thresholds.max_attempts,
+        }
+    }
+
+    /// Lock an account for the thresholds' lockout duration
+    fn lock_account(
+        &self,
+        identifier: &str,
+        info: &mut LoginAttemptInfo,
+        now: u64,
+        thresholds: Thresholds,
+        source: Option<IpAddr>,
+    ) -> RateLimitError {
+        let locked_until = self.clock.wall_time(now.saturating_add(thresholds.lockout_secs));
+        info.locked_until = Some(now.saturating_add(thresholds.lockout_secs));
+        self.note_lockout(identifier, info.locked_until);
+        warn!("Account locked due to too many attempts: {}", self.config.log_key(identifier));
+        self.audit(identifier, LoginEventKind::Lockout, source, Some(0), Some(locked_until));
+        RateLimitError::AccountLocked(locked_until)
+    }
+
+    /// Send an event to the audit sink, if any
+    fn audit(
+        &self,
+        identifier: &str,
+        kind: LoginEventKind,
+        source: Option<IpAddr>,
+        attempts_remaining: Option<u32>,
+        locked_until: Option<u64>,
+    ) {
+        if let Some(sink) = &self.audit_sink {
+            sink.record(&LoginAuditEvent {
+                timestamp: self.clock.wall_time(self.clock.now()),
+                identifier: identifier.to_string(),
+                kind,
+                source,
+                attempts_remaining,
+                locked_until,
+            });
+        }
+    }
+
+    /// Get the login attempt budget for user without checking an attempt
+    pub async fn login_status(&self, identifier: &str) -> LoginCheckResult {
+        self.status(identifier, None).await
+    }
+
+    /// `login_status` for a login action, see `check_login_action`
+    pub async fn login_action_status(&self, identifier: &str, action: &str) -> LoginCheckResult {
+        self.status(identifier, Some(action)).await
+    }
+
+    /// `login_status` for an optional action
+    async fn status(&self, identifier: &str, action: Option<&str>) -> LoginCheckResult {
+        let thresholds = self.thresholds(action);
+        let attempts = self.login_attempts.lock().await;
+        let now = self.clock.now();
+        let window_start = now.saturating_sub(self.config.rate_window_secs);
+
+        let Some(info) = attempts.get(&*attempt_key(identifier, action)) else {
+            return LoginCheckResult {
+                attempts_used: 0,
+                attempts_remaining: thresholds.max_attempts,
+                window_resets_at: None,
+                locked_until: None,
+            };
+        };
+
+        let locked_until = info.locked_until.filter(|&until| now < until);
+        let in_window: Vec<u64> = info.attempts.iter().copied().filter(|&t| t > window_start).collect();
+        self.check_result(&in_window, locked_until, self.max_attempts(info, now, thresholds))
+    }
+
+    /// Summarize already-pruned login state
+    fn check_result(&self, attempts: &[u64], locked_until: Option<u64>, max_attempts: u32) -> LoginCheckResult {
+        let used = attempts.len() as u32;
+        LoginCheckResult {
+            attempts_used: used,
+            attempts_remaining: max_attempts.saturating_sub(used),
+            window_resets_at: attempts.iter().min()
+                .map(|&oldest| self.clock.wall_time(oldest.saturating_add(self.config.rate_window_secs))),
+            locked_until: locked_until.map(|until| self.clock.wall_time(until)),
+        }
     }
 
     /// Record failed login attempt
     pub async fn record_failed_attempt(&self, identifier: &str) {
+        self.record_failure(identifier, None, 1).await
+    }
+
+    /// Record a failed login attempt counting `weight` times toward lockout,
+    /// e.g. 2 for an attempt on a nonexistent account that suggests
+    /// enumeration
+    ///
+    /// The failure is stored as `weight` attempts at the current time, so it
+    /// uses up `weight` of the `max_login_attempts` budget and leaves the
+    /// window all at once. Attempts beyond the lockout threshold aren't
+    /// stored, so `attempts_used` never exceeds it. A weight of 0 records
+    /// nothing.
+    pub async fn record_weighted_failure(&self, identifier: &str, weight: u32) {
+        self.record_failure(identifier, None, weight).await
+    }
+
+    /// Record a failed attempt at a login action, see `check_login_action`
+    pub async fn record_failed_action(&self, identifier: &str, action: &str) {
+        self.record_failure(identifier, Some(action), 1).await
+    }
+
+    /// `record_failed_attempt` for an optional action and weight
+    async fn record_failure(&self, identifier: &str, action: Option<&str>, weight: u32) {
+        if weight == 0 {
+            return;
+        }
+        let thresholds = self.thresholds(action);
+        let key = attempt_key(identifier, action);
+        let identifier = &*key;
         let mut attempts = self.login_attempts.lock().await;
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+        let now = self.clock.now();
 
         let info = attempts.entry(identifier.to_string())
             .or_insert(LoginAttemptInfo {
                 attempts: Vec::new(),
                 locked_until: None,
+                probation_until: None,
             });
 
-        info.attempts.push(now);
-        info!("Failed login attempt recorded for: {}", identifier);
+        // Attempts past the lockout threshold change nothing, so heavy
+        // weights are capped there rather than stored; only attempts still
+        // in the window take up room
+        let window_start = now.saturating_sub(self.config.rate_window_secs);
+        info.attempts.retain(|&t| t > window_start);
+        let max_attempts = self.max_attempts(info, now, thresholds);
+        let room = (max_attempts as usize).saturating_sub(info.attempts.len());
+        let count = (weight as usize).min(room);
+        info.attempts.extend(std::iter::repeat_n(now, count));
+        info!("Failed login attempt recorded for: {}", self.config.log_key(identifier));
+        let remaining = max_attempts.saturating_sub(info.attempts.len() as u32);
+        self.audit(identifier, LoginEventKind::Failure, None, Some(remaining), None);
     }
 
     /// Clear attempts after successful login
     pub async fn clear_attempts(&self, identifier: &str) {
+        self.clear(identifier).await
+    }
+
+    /// Clear a login action's attempts after it succeeds, see
+    /// `check_login_action`
+    pub async fn clear_action_attempts(&self, identifier: &str, action: &str) {
+        self.clear(&attempt_key(identifier, Some(action))).await
+    }
+
+    /// Clear the attempts tracked under a key
+    async fn clear(&self, identifier: &str) {
         let mut attempts = self.login_attempts.lock().await;
         attempts.remove(identifier);
-        info!("Login attempts cleared for: {}", identifier);
+        self.note_lockout(identifier, None);
+        info!("Login attempts cleared for: {}", self.config.log_key(identifier));
+        self.audit(identifier, LoginEventKind::Clear, None, None, None);
+    }
+
+    /// Copy every identifier's login state under a single lock
+    pub async fn snapshot(&self) -> HashMap<String, LoginSnapshot> {
+        let attempts = self.login_attempts.lock().await;
+        attempts.iter()
+            .map(|(identifier, info)| {
+                (identifier.clone(), LoginSnapshot {
+                    attempts: info.attempts.clone(),
+                    locked_until: info.locked_until,
+                    probation_until: info.probation_until,
+                })
+            })
+            .collect()
+    }
+
+    /// Merge a snapshot taken from another login limiter into this one
+    ///
+    /// Failed attempts from both sides are kept, pruned to the window, and
+    /// the most restrictive state wins: an identifier locked on either side
+    /// stays locked until the later of the two lockouts end, and likewise for
+    /// probation. Attempts too far in the future are handled per
+    /// `future_timestamps`.
+    pub async fn merge(&self, snapshot: HashMap<String, LoginSnapshot>) {
+        let mut attempts = self.login_attempts.lock().await;
+        let now = self.clock.now();
+        let window_start = now.saturating_sub(self.config.rate_window_secs);
+        let tolerance = self.config.future_timestamp_tolerance_secs;
+
+        for (identifier, mut merged) in snapshot {
+            let skewed = self.config.future_timestamps.apply(&mut merged.attempts, now, tolerance);
+            if skewed > 0 {
+                warn!("{} login attempts for {} are more than {} seconds ahead of the clock; policy {:?}",
+                    skewed, self.config.log_key(&identifier), tolerance, self.config.future_timestamps);
+            }
+            let info = attempts.entry(identifier.clone()).or_insert(LoginAttemptInfo {
+                attempts: Vec::new(),
+                locked_until: None,
+                probation_until: None,
+            });
+            info.attempts.extend(merged.attempts);
+            info.attempts.retain(|&t| t > window_start);
+            info.attempts.sort_unstable();
+            info.locked_until = info.locked_until.max(merged.locked_until);
+            info.probation_until = info.probation_until.max(merged.probation_until);
+            if info.locked_until.is_some() {
+                self.note_lockout(&identifier, info.locked_until);
+            }
+        }
+    }
+
+    /// Export login limiter state in Prometheus text format
+    pub async fn metrics_text(&self) -> String {
+        let attempts = self.login_attempts.lock().await;
+        let now = self.clock.now();
+
+        let locked = attempts.values()
+            .filter(|info| info.locked_until.is_some_and(|until| now < until))
+            .count();
+
+        let mut out = String::new();
+        metrics::write_gauge(&mut out, "login_rate_limit_tracked_identifiers",
+            "Login identifiers currently tracked", attempts.len() as u64);
+        metrics::write_gauge(&mut out, "login_rate_limit_locked_accounts",
+            "Accounts currently locked out", locked as u64);
+        out
+    }
+
+    /// Configuration in use
+    pub fn config(&self) -> &RateLimitConfig {
+        &self.config
+    }
+
+    /// Number of tracked identifiers and of currently locked accounts
+    #[cfg_attr(not(feature = "status-handler"), allow(dead_code))]
+    pub(crate) async fn identifier_counts(&self) -> (usize, usize) {
+        let attempts = self.login_attempts.lock().await;
+        let now = self.clock.now();
+        let locked = attempts.values()
+            .filter(|info| info.locked_until.is_some_and(|until| now < until))
+            .count();
+        (attempts.len(), locked)
     }
 
     /// Clean up old entries periodically
     pub async fn cleanup(&self) {
         let mut attempts = self.login_attempts.lock().await;
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+        let now = self.clock.now();
+        if let Some(scans) = &self.identifier_scans {
+            scans.cleanup(now);
+        }
+        if let Some(lockouts) = &self.lockouts {
+            lockouts.write().unwrap_or_else(PoisonError::into_inner).retain(|_, &mut until| now < until);
+        }
 
         let window_start = now.saturating_sub(self.config.rate_window_secs);
 
         attempts.retain(|_, info| {
-            // Keep if locked
+            // Keep if locked or on probation
             if let Some(locked_until) = info.locked_until {
                 if now < locked_until {
                     return true;
                 }
             }
+            if info.probation_until.is_some_and(|until| now < until) {
+                return true;
+            }
 
             // Remove old attempts
             info.attempts.retain(|&t| t > window_start);
             !info.attempts.is_empty()
         });
     }
+
+    /// Spawn a background task that cleans up expired login state every `interval`
+    ///
+    /// Skips the scan while fewer than `cleanup_min_keys` identifiers are tracked.
+    pub fn spawn_cleanup(&self, interval: Duration) -> JoinHandle<()> {
+        let limiter = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                limiter.cleanup_if_due().await;
+            }
+        })
+    }
+
+    /// Run `cleanup` unless fewer than `cleanup_min_keys` identifiers are
+    /// tracked
+    pub(crate) async fn cleanup_if_due(&self) {
+        let tracked = self.login_attempts.lock().await.len();
+        if tracked >= self.config.cleanup_min_keys {
+            self.cleanup().await;
+        }
+    }
+}
+
+/// Login identifier extracted by [`login_rate_limit_middleware`]
+///
+/// Inserted into the request extensions so the handler can record failed
+/// attempts against the same identifier the middleware checked: the escaped
+/// body identifier, or `ip:<addr>` for requests limited by IP.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoginIdentifier(pub String);
+
+/// Login rate limiting middleware for Axum
+///
+/// Buffers the request body (up to `login_max_body_bytes`) to read the
+/// `login_identifier_field` from a form or JSON body, checks the login
+/// limiter, then hands the reconstructed body to the handler. Requests whose
+/// body is missing the field, is unparseable, or declares a length above the
+/// cap are limited by client IP instead, under an `ip:<addr>` key. Body
+/// identifiers have `%` and `:` escaped as by [`composite_key`], so no body
+/// can name an IP's key. Bodies without a declared length that
+/// exceed the cap are rejected with `413 Payload Too Large`.
+pub async fn login_rate_limit_middleware(
+    State(limiter): State<LoginRateLimiter>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: Request<Body>,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    match limiter.admit(addr, request).await {
+        Ok((request, _guard)) => Ok(limiter.finish(next.run(request).await)),
+        Err(rejection) => Ok(rejection),
+    }
+}
+
+impl LoginRateLimiter {
+    /// Check a login request, returning it with its body rebuilt and its
+    /// [`LoginIdentifier`] inserted, or the rejection response
+    ///
+    /// The returned guard holds the attempt's in-flight slot; keep it until
+    /// the handler responds.
+    pub(crate) async fn admit(
+        &self,
+        addr: SocketAddr,
+        request: Request<Body>,
+    ) -> Result<(Request<Body>, LoginAttemptGuard), Response> {
+        let max_body_bytes = self.config.login_max_body_bytes;
+        let (parts, body) = request.into_parts();
+
+        let declared_len = parts.headers.get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<usize>().ok());
+
+        let (identifier, body) = match declared_len {
+            Some(len) if len > max_body_bytes => (None, body),
+            _ => {
+                let bytes = match body::to_bytes(body, max_body_bytes).await {
+                    Ok(bytes) => bytes,
+                    Err(_) => {
+                        warn!("Login body from IP {} exceeds {} bytes", addr.ip(), max_body_bytes);
+                        return Err(StatusCode::PAYLOAD_TOO_LARGE.into_response());
+                    }
+                };
+                let identifier = extract_identifier(&self.config, &parts.headers, &bytes);
+                (identifier, Body::from(bytes))
+            }
+        };
+
+        // Body values are escaped so none can pose as the IP fallback's key
+        let identifier = match identifier {
+            Some(identifier) => composite_key(&[&identifier]),
+            None => composite_key(&["ip", &addr.ip().to_string()]),
+        };
+
+        let guard = match self.begin_attempt(&identifier, Some(addr.ip())).await {
+            Ok(guard) => guard,
+            Err(e) => {
+                log_at!(self.config.rejection_level(), "Login rejected for {}: {}",
+                    self.config.log_key(&identifier), e);
+                let status = match e {
+                    RateLimitError::InvalidKey => StatusCode::BAD_REQUEST,
+                    _ => StatusCode::TOO_MANY_REQUESTS,
+                };
+                return Err(status.into_response());
+            }
+        };
+
+        let mut request = Request::from_parts(parts, body);
+        request.extensions_mut().insert(LoginIdentifier(identifier));
+        Ok((request, guard))
+    }
+}
+
+impl LoginRateLimiter {
+    /// Complete the handler's response for an admitted login request
+    pub(crate) fn finish(&self, mut response: Response) -> Response {
+        if self.config.disabled_header && !self.config.enabled {
+            response.headers_mut().insert(X_RATELIMIT_DISABLED, HeaderValue::from_static("true"));
+        }
+        response
+    }
+}
+
+/// Key an identifier's attempts are tracked under, namespaced by action
+fn attempt_key<'a>(identifier: &'a str, action: Option<&str>) -> Cow<'a, str> {
+    match action {
+        Some(action) => Cow::Owned(composite_key(&[action, identifier])),
+        None => Cow::Borrowed(identifier),
+    }
+}
+
+/// Read the identifier field from a buffered login body
+fn extract_identifier(config: &RateLimitConfig, headers: &HeaderMap, bytes: &Bytes) -> Option<String> {
+    let format = config.login_body_format.or_else(|| {
+        let content_type = headers.get(header::CONTENT_TYPE)?.to_str().ok()?;
+        if content_type.starts_with("application/x-www-form-urlencoded") {
+            Some(LoginBodyFormat::Form)
+        } else if content_type.starts_with("application/json") {
+            Some(LoginBodyFormat::Json)
+        } else {
+            None
+        }
+    })?;
+
+    let field = &config.login_identifier_field;
+    let identifier = match format {
+        LoginBodyFormat::Form => {
+            let fields: HashMap<String, String> = serde_urlencoded::from_bytes(bytes).ok()?;
+            fields.get(field).cloned()
+        }
+        LoginBodyFormat::Json => {
+            let value: serde_json::Value = serde_json::from_slice(bytes).ok()?;
+            value.get(field)?.as_str().map(str::to_string)
+        }
+    };
+
+    identifier.filter(|identifier| !identifier.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter_at(config: RateLimitConfig, now: u64) -> LoginRateLimiter {
+        let mut limiter = LoginRateLimiter::new(config);
+        limiter.clock = Clock::at(now);
+        limiter
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lockouts_near_the_end_of_time_saturate() {
+        let config = RateLimitConfig {
+            max_login_attempts: 2,
+            lockout_duration_secs: u64::MAX,
+            rate_window_secs: u64::MAX,
+            post_lockout_attempts: Some(1),
+            login_lockout_fast_path: true,
+            ..RateLimitConfig::default()
+        };
+        for now in [1, u64::MAX - 10] {
+            let limiter = limiter_at(config.clone(), now);
+            assert!(limiter.check_and_record_failure("alice").await.is_ok());
+            assert!(matches!(limiter.check_and_record_failure("alice").await, Err(RateLimitError::AccountLocked(_))));
+            assert!(limiter.login_status("alice").await.locked_until.is_some());
+
+            tokio::time::advance(Duration::from_secs(5)).await;
+            assert!(limiter.check_login_attempt("alice").await.is_err());
+            limiter.record_weighted_failure("alice", u32::MAX).await;
+            limiter.cleanup().await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn attempts_near_the_end_of_time_leave_the_window() {
+        let config = RateLimitConfig {
+            max_login_attempts: 2,
+            rate_window_secs: 60,
+            ..RateLimitConfig::default()
+        };
+        let limiter = limiter_at(config, u64::MAX - 100);
+        limiter.record_failed_attempt("alice").await;
+        let status = limiter.login_status("alice").await;
+        assert_eq!(status.attempts_used, 1);
+        assert!(status.window_resets_at.is_some());
+
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert_eq!(limiter.login_status("alice").await.attempts_used, 0);
+        assert!(limiter.check_and_record_failure("alice").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn in_flight_slots_are_limited_and_released_on_drop() {
+        let limiter = LoginRateLimiter::new(RateLimitConfig {
+            max_concurrent_login_attempts: Some(2),
+            ..RateLimitConfig::default()
+        });
+        let first = limiter.begin_login_attempt("alice").await.unwrap();
+        let second = limiter.begin_login_attempt("alice").await.unwrap();
+        assert!(matches!(limiter.begin_login_attempt("alice").await, Err(RateLimitError::TooManyConcurrentLogins)));
+        assert!(limiter.begin_login_attempt("bob").await.is_ok());
+
+        drop(first);
+        let third = limiter.begin_login_attempt("alice").await.unwrap();
+        drop((second, third));
+        assert!(limiter.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn refused_in_flight_slots_leave_nothing_behind() {
+        let limiter = LoginRateLimiter::new(RateLimitConfig {
+            max_concurrent_login_attempts: Some(0),
+            ..RateLimitConfig::default()
+        });
+        for identifier in ["alice", "bob", "carol"] {
+            assert!(limiter.begin_login_attempt(identifier).await.is_err());
+        }
+        assert!(limiter.in_flight.lock().unwrap().is_empty());
+    }
 }
@@ -0,0 +1,152 @@
+//! Maps holding `MemoryStore` attempt windows
+
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::sync::{Mutex, PoisonError};
+
+use crate::window::AttemptWindow;
+
+/// Attempt windows per key and the window length they were last counted over
+type Windows = HashMap<String, (AttemptWindow, u64)>;
+
+/// Concurrent map of per-key attempt windows behind a [`MemoryStore`]
+///
+/// Implementations differ only in how they lock, so workloads can compare
+/// them, e.g. with the crate's `store` benchmarks, and pick one with
+/// `MemoryStore::with_map`. Timestamps are the store's monotonic seconds.
+///
+/// Only [`MemoryStore`] is backed by these maps. A `RateLimiter` without a
+/// store keeps its per-key state, bans and overrides in its own single map
+/// whichever is chosen here; limit over one of them by passing the store to
+/// `RateLimiter::with_store`.
+///
+/// [`MemoryStore`]: crate::MemoryStore
+pub trait InMemoryStore: Send + Sync {
+    /// Record `hits` attempts against `key` at `now` and return its attempt
+    /// count in the `window_secs` window ending at `now`, including them
+    fn record(&self, key: &str, hits: u32, window_secs: u64, now: u64) -> u32;
+
+    /// Remove keys with no attempts left in their window at `now`
+    fn cleanup(&self, now: u64);
+}
+
+/// Prune a key's window to the one ending at `now` and record `hits` in it
+fn record_in(entry: &mut (AttemptWindow, u64), hits: u32, window_secs: u64, now: u64) -> u32 {
+    let (window, last_window_secs) = entry;
+    *last_window_secs = window_secs;
+    window.prune(now.saturating_sub(window_secs));
+    for _ in 0..hits {
+        window.record(now);
+    }
+    window.count()
+}
+
+/// Whether a window still holds attempts at `now`, pruning it
+fn live_at(entry: &mut (AttemptWindow, u64), now: u64) -> bool {
+    let (window, window_secs) = entry;
+    window.prune(now.saturating_sub(*window_secs));
+    !window.is_empty()
+}
+
+fn new_window(window_secs: u64) -> (AttemptWindow, u64) {
+    (AttemptWindow::new(window_secs, None), window_secs)
+}
+
+/// Every key in one `Mutex<HashMap>`, the `MemoryStore::new` default
+#[derive(Default)]
+pub struct MutexMap {
+    windows: Mutex<Windows>,
+}
+
+impl MutexMap {
+    /// Create an empty map
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl InMemoryStore for MutexMap {
+    fn record(&self, key: &str, hits: u32, window_secs: u64, now: u64) -> u32 {
+        let mut windows = self.windows.lock().unwrap_or_else(PoisonError::into_inner);
+        // Look up before inserting so existing keys don't allocate
+        if let Some(entry) = windows.get_mut(key) {
+            return record_in(entry, hits, window_secs, now);
+        }
+        let entry = windows.entry(key.to_string()).or_insert_with(|| new_window(window_secs));
+        record_in(entry, hits, window_secs, now)
+    }
+
+    fn cleanup(&self, now: u64) {
+        self.windows.lock().unwrap_or_else(PoisonError::into_inner)
+            .retain(|_, entry| live_at(entry, now));
+    }
+}
+
+/// Keys spread over independently locked `Mutex<HashMap>` shards
+///
+/// More shards cut lock contention when many keys are checked concurrently,
+/// at the cost of a hash per check to pick the shard. Checks on a single hot
+/// key still contend on its shard.
+pub struct ShardedMap {
+    shards: Vec<MutexMap>,
+    hasher: RandomState,
+}
+
+impl ShardedMap {
+    /// Create an empty map split into `shards` shards (at least one)
+    pub fn new(shards: usize) -> Self {
+        Self {
+            shards: (0..shards.max(1)).map(|_| MutexMap::new()).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Shard holding a key
+    fn shard(&self, key: &str) -> &MutexMap {
+        let index = self.hasher.hash_one(key) % self.shards.len() as u64;
+        &self.shards[index as usize]
+    }
+}
+
+impl InMemoryStore for ShardedMap {
+    fn record(&self, key: &str, hits: u32, window_secs: u64, now: u64) -> u32 {
+        self.shard(key).record(key, hits, window_secs, now)
+    }
+
+    fn cleanup(&self, now: u64) {
+        for shard in &self.shards {
+            shard.cleanup(now);
+        }
+    }
+}
+
+/// Keys in a [`dashmap::DashMap`], which shards internally with
+/// reader-writer locks
+#[cfg(feature = "dashmap")]
+#[derive(Default)]
+pub struct ConcurrentMap {
+    windows: dashmap::DashMap<String, (AttemptWindow, u64)>,
+}
+
+#[cfg(feature = "dashmap")]
+impl ConcurrentMap {
+    /// Create an empty map
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(feature = "dashmap")]
+impl InMemoryStore for ConcurrentMap {
+    fn record(&self, key: &str, hits: u32, window_secs: u64, now: u64) -> u32 {
+        if let Some(mut entry) = self.windows.get_mut(key) {
+            return record_in(&mut entry, hits, window_secs, now);
+        }
+        let mut entry = self.windows.entry(key.to_string()).or_insert_with(|| new_window(window_secs));
+        record_in(&mut entry, hits, window_secs, now)
+    }
+
+    fn cleanup(&self, now: u64) {
+        self.windows.retain(|_, entry| live_at(entry, now));
+    }
+}
@@ -0,0 +1,130 @@
+//! Distinct paths or login identifiers per client IP, a crude crawler and
+//! credential stuffing detector
+
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::net::IpAddr;
+use std::sync::{Mutex, PoisonError};
+use tracing::warn;
+
+use crate::config::DistinctPathAction;
+
+/// Paths each IP requested recently, see
+/// `RateLimitConfig::max_distinct_paths_per_window`, or identifiers it tried
+/// to log in as under `max_login_identifiers_per_ip`
+pub(crate) struct PathScans {
+    /// What is counted, for logs
+    noun: &'static str,
+    limit: u32,
+    window_secs: u64,
+    action: DistinctPathAction,
+    ban_secs: u64,
+    seed: RandomState,
+    ips: Mutex<HashMap<IpAddr, PathSet>>,
+}
+
+#[derive(Default)]
+struct PathSet {
+    /// Hash of each distinct path and when it was last requested, at most
+    /// `limit + 1` of them
+    paths: Vec<(u64, u64)>,
+    flagged: bool,
+    banned_until: Option<u64>,
+}
+
+/// Outcome of a request under `max_distinct_paths_per_window`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PathVerdict {
+    Allow,
+    /// Over the limit with this many distinct paths
+    Over(u32),
+    /// Banned until this monotonic time
+    Banned(u64),
+}
+
+impl PathScans {
+    pub(crate) fn new(
+        noun: &'static str,
+        limit: u32,
+        window_secs: u64,
+        action: DistinctPathAction,
+        ban_secs: u64,
+    ) -> Self {
+        Self {
+            noun,
+            limit,
+            window_secs,
+            action,
+            ban_secs,
+            seed: RandomState::new(),
+            ips: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn limit(&self) -> u32 {
+        self.limit
+    }
+
+    /// Note a request from `ip` for `path` at `now`
+    pub(crate) fn observe(&self, ip: IpAddr, path: &str, now: u64) -> PathVerdict {
+        let hash = self.seed.hash_one(path);
+        let mut ips = self.ips.lock().unwrap_or_else(PoisonError::into_inner);
+        let set = ips.entry(ip).or_default();
+        if let Some(until) = set.banned_until.filter(|&until| now < until) {
+            return PathVerdict::Banned(until);
+        }
+
+        let window_start = now.saturating_sub(self.window_secs);
+        set.paths.retain(|&(_, seen)| seen > window_start);
+        let tracked = set.paths.len();
+        match set.paths.iter_mut().find(|(path, _)| *path == hash) {
+            Some((_, seen)) => *seen = now,
+            // Paths past the first one over the limit change nothing
+            None if tracked <= self.limit as usize => set.paths.push((hash, now)),
+            None => {}
+        }
+
+        let distinct = set.paths.len() as u32;
+        if distinct <= self.limit {
+            set.flagged = false;
+            return PathVerdict::Allow;
+        }
+        if !set.flagged {
+            set.flagged = true;
+            warn!("IP {} used more than {} distinct {} in {} seconds; action {:?}",
+                ip, self.limit, self.noun, self.window_secs, self.action);
+        }
+        match self.action {
+            DistinctPathAction::Flag => PathVerdict::Allow,
+            DistinctPathAction::Reject => PathVerdict::Over(distinct),
+            DistinctPathAction::Ban => {
+                let until = now.saturating_add(self.ban_secs);
+                set.banned_until = Some(until);
+                set.paths.clear();
+                PathVerdict::Banned(until)
+            }
+        }
+    }
+
+    /// IPs currently over the limit or banned for it
+    pub(crate) fn flagged(&self, now: u64) -> Vec<IpAddr> {
+        let ips = self.ips.lock().unwrap_or_else(PoisonError::into_inner);
+        ips.iter()
+            .filter(|(_, set)| set.flagged || set.banned_until.is_some_and(|until| now < until))
+            .map(|(ip, _)| *ip)
+            .collect()
+    }
+
+    /// Forget IPs with no paths in the window and no ban
+    pub(crate) fn cleanup(&self, now: u64) {
+        let window_start = now.saturating_sub(self.window_secs);
+        self.ips.lock().unwrap_or_else(PoisonError::into_inner).retain(|_, set| {
+            set.paths.retain(|&(_, seen)| seen > window_start);
+            set.flagged &= set.paths.len() > self.limit as usize;
+            if set.banned_until.is_some_and(|until| now >= until) {
+                set.banned_until = None;
+            }
+            !set.paths.is_empty() || set.banned_until.is_some()
+        });
+    }
+}
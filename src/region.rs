@@ -0,0 +1,13 @@
+//! Region-based rate limits
+
+use std::net::IpAddr;
+
+/// Resolves client IPs to geographic regions
+///
+/// The crate bundles no GeoIP data; implement this over MaxMind or a similar
+/// database and register it with `RateLimiter::with_region_resolver`. Returned
+/// codes are looked up in `RateLimitConfig::region_limits`.
+pub trait RegionResolver: Send + Sync {
+    /// Region code (e.g. an ISO 3166 country code) for an IP, if known
+    fn resolve(&self, ip: IpAddr) -> Option<String>;
+}
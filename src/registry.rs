@@ -0,0 +1,172 @@
+//! Named limiters managed together
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, State},
+    http::{Request, StatusCode},
+    middleware::{from_fn_with_state, FromFnLayer, Next},
+    response::Response,
+};
+use tokio::task::JoinHandle;
+
+use crate::{
+    limiter::{rate_limit_middleware, RateLimiter},
+    login::{login_rate_limit_middleware, LoginRateLimiter},
+};
+
+type MiddlewareFuture = Pin<Box<dyn Future<Output = Result<Response, StatusCode>> + Send>>;
+type MiddlewareFn<L> = fn(State<L>, ConnectInfo<SocketAddr>, Request<Body>, Next) -> MiddlewareFuture;
+
+/// Middleware layer for one limiter of a [`RateLimiterRegistry`], as returned
+/// by [`RateLimiterRegistry::middleware`] and
+/// [`RateLimiterRegistry::login_middleware`]
+pub type RegistryLayer<L> = FromFnLayer<MiddlewareFn<L>, L, (State<L>, ConnectInfo<SocketAddr>, Request<Body>)>;
+
+fn api_middleware(
+    state: State<RateLimiter>,
+    connect_info: ConnectInfo<SocketAddr>,
+    request: Request<Body>,
+    next: Next,
+) -> MiddlewareFuture {
+    Box::pin(rate_limit_middleware(state, connect_info, request, next))
+}
+
+fn login_middleware(
+    state: State<LoginRateLimiter>,
+    connect_info: ConnectInfo<SocketAddr>,
+    request: Request<Body>,
+    next: Next,
+) -> MiddlewareFuture {
+    Box::pin(login_rate_limit_middleware(state, connect_info, request, next))
+}
+
+/// Limiters registered by name, e.g. `api`, `uploads` and `webhooks`, each
+/// with its own config
+///
+/// The registry is cheap to clone and can be shared as router state. One
+/// cleanup task covers every limiter, and with the `status-handler` feature
+/// `registry_status_handler` reports them all.
+///
+/// # Example
+/// ```rust
+/// use axum::{routing::post, Router};
+/// use pleme_middleware_rate_limit::{RateLimitConfig, RateLimiter, RateLimiterRegistry};
+///
+/// let uploads = RateLimitConfig {
+///     max_requests_per_window: 10,
+///     ..RateLimitConfig::default()
+/// };
+/// let registry = RateLimiterRegistry::new()
+///     .with_limiter("api", RateLimiter::new(RateLimitConfig::default()))
+///     .with_limiter("uploads", RateLimiter::new(uploads));
+///
+/// let app: Router = Router::new()
+///     .route("/upload", post(|| async { "ok" }).layer(registry.middleware("uploads")))
+///     .route("/items", post(|| async { "ok" }).layer(registry.middleware("api")));
+/// ```
+#[derive(Clone, Default)]
+pub struct RateLimiterRegistry {
+    limiters: Arc<HashMap<String, RateLimiter>>,
+    login_limiters: Arc<HashMap<String, LoginRateLimiter>>,
+}
+
+impl RateLimiterRegistry {
+    /// Create an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register an API limiter under `name`, replacing any limiter already
+    /// registered there
+    pub fn with_limiter(mut self, name: impl Into<String>, limiter: RateLimiter) -> Self {
+        Arc::make_mut(&mut self.limiters).insert(name.into(), limiter);
+        self
+    }
+
+    /// Register a login limiter under `name`, replacing any login limiter
+    /// already registered there
+    ///
+    /// Login limiters have their own namespace, so `login` may name both an
+    /// API and a login limiter.
+    pub fn with_login_limiter(mut self, name: impl Into<String>, limiter: LoginRateLimiter) -> Self {
+        Arc::make_mut(&mut self.login_limiters).insert(name.into(), limiter);
+        self
+    }
+
+    /// API limiter registered under `name`
+    pub fn get(&self, name: &str) -> Option<&RateLimiter> {
+        self.limiters.get(name)
+    }
+
+    /// Login limiter registered under `name`
+    pub fn login(&self, name: &str) -> Option<&LoginRateLimiter> {
+        self.login_limiters.get(name)
+    }
+
+    /// Registered API limiters and their names, in no particular order
+    pub fn limiters(&self) -> impl Iterator<Item = (&str, &RateLimiter)> {
+        self.limiters.iter().map(|(name, limiter)| (name.as_str(), limiter))
+    }
+
+    /// Registered login limiters and their names, in no particular order
+    pub fn login_limiters(&self) -> impl Iterator<Item = (&str, &LoginRateLimiter)> {
+        self.login_limiters.iter().map(|(name, limiter)| (name.as_str(), limiter))
+    }
+
+    /// `rate_limit_middleware` for the API limiter registered under `name`,
+    /// as a layer for a route or router
+    ///
+    /// # Panics
+    /// If no API limiter is registered under `name`, as routes are normally
+    /// built once at startup.
+    pub fn middleware(&self, name: &str) -> RegistryLayer<RateLimiter> {
+        let limiter = self.get(name)
+            .unwrap_or_else(|| panic!("no rate limiter registered as {:?}", name));
+        from_fn_with_state(limiter.clone(), api_middleware as MiddlewareFn<RateLimiter>)
+    }
+
+    /// `login_rate_limit_middleware` for the login limiter registered under
+    /// `name`
+    ///
+    /// # Panics
+    /// If no login limiter is registered under `name`.
+    pub fn login_middleware(&self, name: &str) -> RegistryLayer<LoginRateLimiter> {
+        let limiter = self.login(name)
+            .unwrap_or_else(|| panic!("no login rate limiter registered as {:?}", name));
+        from_fn_with_state(limiter.clone(), login_middleware as MiddlewareFn<LoginRateLimiter>)
+    }
+
+    /// Spawn one background task cleaning every registered limiter each
+    /// `interval`
+    ///
+    /// Each limiter's `cleanup_min_keys` still applies, and quiesced limiters
+    /// are skipped. Limiters registered after the call aren't covered.
+    pub fn spawn_cleanup(&self, interval: Duration) -> JoinHandle<()> {
+        let registry = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                for limiter in registry.limiters.values().filter(|limiter| !limiter.is_draining()) {
+                    limiter.cleanup_if_due().await;
+                }
+                for limiter in registry.login_limiters.values() {
+                    limiter.cleanup_if_due().await;
+                }
+            }
+        })
+    }
+
+    /// Quiesce every registered API limiter ahead of shutdown
+    pub fn quiesce(&self) {
+        for limiter in self.limiters.values() {
+            limiter.quiesce();
+        }
+    }
+}
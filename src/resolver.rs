@@ -0,0 +1,30 @@
+//! Per-key limits loaded from an external source
+
+use crate::{config::Quota, store::StoreFuture};
+
+/// Looks up a key's quota in an external source, e.g. a customer's plan in
+/// a database
+///
+/// Register it with `RateLimiter::with_limit_resolver`. Keys the resolver
+/// returns `None` for use the quota the check would otherwise apply.
+///
+/// # Example
+/// ```rust
+/// use pleme_middleware_rate_limit::{LimitResolver, Quota, StoreFuture};
+///
+/// struct Plans;
+///
+/// impl LimitResolver for Plans {
+///     fn resolve<'a>(&'a self, key: &'a str) -> StoreFuture<'a, Option<Quota>> {
+///         Box::pin(async move {
+///             // Query the plan for `key` here
+///             let premium = key.starts_with("premium-");
+///             Ok(premium.then_some(Quota { max_requests: 10_000, window_secs: 60 }))
+///         })
+///     }
+/// }
+/// ```
+pub trait LimitResolver: Send + Sync {
+    /// Quota for a key, or `None` to use the default
+    fn resolve<'a>(&'a self, key: &'a str) -> StoreFuture<'a, Option<Quota>>;
+}
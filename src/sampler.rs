@@ -0,0 +1,60 @@
+//! Request sampling for approximate limiting at very high request rates
+
+use std::cell::Cell;
+use std::hash::{BuildHasher, Hash, RandomState};
+
+thread_local! {
+    /// Per-thread sequence fed to the sampling hash, so sampling never
+    /// touches shared state
+    static SEQUENCE: Cell<u64> = Cell::new(RandomState::new().hash_one(()));
+}
+
+/// Picks the requests that run the full check under `sample_rate`
+#[derive(Debug)]
+pub(crate) struct Sampler {
+    /// Hashes below this are sampled
+    threshold: u64,
+    /// Attempts recorded for each sampled request
+    weight: u32,
+    per_key: bool,
+    seed: RandomState,
+}
+
+impl Sampler {
+    /// Sampler for `rate`, or `None` when every request is checked
+    pub(crate) fn new(rate: f64, per_key: bool) -> Option<Self> {
+        if rate >= 1.0 {
+            return None;
+        }
+        // NaN and negative rates sample nothing
+        let rate = if rate > 0.0 { rate } else { 0.0 };
+        let weight = if per_key || rate == 0.0 { 1 } else { (1.0 / rate).round().min(u32::MAX as f64) as u32 };
+        Some(Self {
+            threshold: (rate * u64::MAX as f64) as u64,
+            weight,
+            per_key,
+            seed: RandomState::new(),
+        })
+    }
+
+    /// Whether a request for `key` should run the full check
+    pub(crate) fn sample<Q: Hash + ?Sized>(&self, key: &Q) -> bool {
+        let hash = if self.per_key {
+            self.seed.hash_one(key)
+        } else {
+            let sequence = SEQUENCE.with(|sequence| {
+                let next = sequence.get().wrapping_add(0x9e37_79b9_7f4a_7c15);
+                sequence.set(next);
+                next
+            });
+            self.seed.hash_one(sequence)
+        };
+        hash < self.threshold
+    }
+
+    /// Attempts to record for a sampled request so counts estimate the full
+    /// traffic
+    pub(crate) fn weight(&self) -> u32 {
+        self.weight
+    }
+}
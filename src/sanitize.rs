@@ -0,0 +1,146 @@
+//! Log-safe rendering of client-controlled keys
+
+use std::fmt::{self, Display, Write};
+
+/// Displays a key with control characters escaped, so keys containing
+/// newlines or ANSI sequences can't forge or corrupt log lines
+pub(crate) struct Sanitized<'a, T: ?Sized>(pub(crate) &'a T);
+
+impl<T: Display + ?Sized> Display for Sanitized<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rendered = self.0.to_string();
+        for c in rendered.chars() {
+            if c.is_control() {
+                write!(f, "{}", c.escape_default())?;
+            } else {
+                f.write_char(c)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Displays a key in logs, as its [`key_fingerprint`] under
+/// `fingerprint_keys` or else [`Sanitized`]
+pub(crate) struct LogKey<'a, T: ?Sized> {
+    pub(crate) key: &'a T,
+    pub(crate) fingerprint: bool,
+}
+
+impl<T: Display + ?Sized> Display for LogKey<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.fingerprint {
+            write!(f, "{:016x}", fnv1a(self.key))
+        } else {
+            Sanitized(self.key).fmt(f)
+        }
+    }
+}
+
+/// Short hash of a key, stable across processes and releases, for
+/// correlating log lines about a key without logging the key itself
+///
+/// This is 64-bit FNV-1a over the key's display form, as 16 hex digits. It
+/// isn't keyed or cryptographic: keys from a small or guessable space, such
+/// as emails or IPs, can be recovered by hashing candidates, so it keeps
+/// keys out of casual sight rather than from a determined attacker.
+///
+/// # Example
+/// ```rust
+/// use pleme_middleware_rate_limit::key_fingerprint;
+///
+/// let fingerprint = key_fingerprint("alice@example.com");
+/// assert_eq!(fingerprint.len(), 16);
+/// assert_eq!(fingerprint, key_fingerprint("alice@example.com"));
+/// ```
+pub fn key_fingerprint<T: Display + ?Sized>(key: &T) -> String {
+    LogKey { key, fingerprint: true }.to_string()
+}
+
+/// FNV-1a hash of a key's display form
+fn fnv1a<T: Display + ?Sized>(key: &T) -> u64 {
+    struct Fnv(u64);
+
+    impl Write for Fnv {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            self.0 = fnv1a_extend(self.0, s.as_bytes());
+            Ok(())
+        }
+    }
+
+    let mut hasher = Fnv(FNV_OFFSET_BASIS);
+    let _ = write!(hasher, "{}", key);
+    hasher.0
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// 64-bit FNV-1a hash of `bytes`, stable across processes and releases
+/// unlike `DefaultHasher`, for hashes that end up in shared keys
+pub(crate) fn fnv1a_bytes(bytes: &[u8]) -> u64 {
+    fnv1a_extend(FNV_OFFSET_BASIS, bytes)
+}
+
+/// Continue an FNV-1a hash over `bytes`
+fn fnv1a_extend(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// Whether a key's display form contains control characters
+pub(crate) fn has_control_chars<T: Display + ?Sized>(key: &T) -> bool {
+    struct Detector(bool);
+
+    impl Write for Detector {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            if s.chars().any(char::is_control) {
+                self.0 = true;
+                return Err(fmt::Error);
+            }
+            Ok(())
+        }
+    }
+
+    let mut detector = Detector(false);
+    let _ = write!(detector, "{}", key);
+    detector.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORGED: &str = "alice\n2024-01-01 INFO Login succeeded for: admin";
+    const ANSI: &str = "bob\u{1b}[2J\u{1b}[31mred";
+
+    #[test]
+    fn fnv1a_matches_the_reference_values() {
+        assert_eq!(fnv1a_bytes(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a_bytes(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(key_fingerprint("a"), "af63dc4c8601ec8c");
+    }
+
+    #[test]
+    fn control_characters_are_escaped() {
+        assert_eq!(Sanitized(FORGED).to_string(), "alice\\n2024-01-01 INFO Login succeeded for: admin");
+        assert_eq!(Sanitized(ANSI).to_string(), "bob\\u{1b}[2J\\u{1b}[31mred");
+        assert_eq!(Sanitized("10.0.0.1:/api").to_string(), "10.0.0.1:/api");
+    }
+
+    #[test]
+    fn control_characters_are_detected() {
+        assert!(has_control_chars(FORGED));
+        assert!(has_control_chars(ANSI));
+        assert!(has_control_chars("tab\there"));
+        assert!(!has_control_chars("10.0.0.1:/api?q=ünïcode"));
+    }
+
+    #[test]
+    fn log_keys_never_contain_control_characters() {
+        for fingerprint in [false, true] {
+            for key in [FORGED, ANSI] {
+                let logged = LogKey { key, fingerprint }.to_string();
+                assert!(!has_control_chars(&logged), "{:?}", logged);
+            }
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Tower layer for stacks outside `axum::middleware`
+
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use axum::{
+    body::Body,
+    http::{header, HeaderValue, Request, StatusCode},
+    response::{IntoResponse, Response},
+};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::{
+    config::RateLimitMode,
+    decision::{DecisionReason, RateLimitDecision, RejectionDetail},
+    limiter::RateLimiter,
+};
+
+/// Boxed error returned by [`RateLimitService`], as in `tower::BoxError`
+pub type BoxError = Box<dyn Error + Send + Sync>;
+
+/// Request rejected by [`RateLimitService`]
+///
+/// Returned boxed as the service error; downcast it to handle rejections in
+/// an error-handling layer, or convert it into the same response
+/// `rate_limit_middleware` sends with [`IntoResponse`]. The response never
+/// includes `detail`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateLimitRejection {
+    /// Why the request was rejected
+    pub reason: DecisionReason,
+    /// Seconds until the key may retry, when known
+    pub retry_after: Option<u64>,
+    /// Which limit rejected the request, when it was a limit or ban
+    pub detail: Option<RejectionDetail>,
+}
+
+impl fmt::Display for RateLimitRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.reason {
+            DecisionReason::Draining => write!(f, "Rate limiter is draining"),
+            DecisionReason::InvalidKey => write!(f, "Invalid rate limit key"),
+            DecisionReason::Blocked => write!(f, "Request blocked by a User-Agent rule"),
+            DecisionReason::Banned(_) => write!(f, "Rate limit key is banned"),
+            DecisionReason::Contended => write!(f, "Rate limiter state is contended"),
+            DecisionReason::Vetoed => write!(f, "Request refused by the record hook"),
+            _ => write!(f, "Rate limit exceeded"),
+        }?;
+        if let Some(detail) = self.detail {
+            write!(f, " ({})", detail)?;
+        }
+        if let Some(retry_after) = self.retry_after {
+            write!(f, "; retry after {} seconds", retry_after)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for RateLimitRejection {}
+
+impl IntoResponse for RateLimitRejection {
+    fn into_response(self) -> Response {
+        let status = match self.reason {
+            DecisionReason::Draining => StatusCode::SERVICE_UNAVAILABLE,
+            DecisionReason::InvalidKey => StatusCode::BAD_REQUEST,
+            DecisionReason::Blocked => StatusCode::FORBIDDEN,
+            _ => StatusCode::TOO_MANY_REQUESTS,
+        };
+        let mut response = status.into_response();
+        if let Some(retry_after) = self.retry_after {
+            response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        response
+    }
+}
+
+impl<K> From<RateLimitDecision<K>> for RateLimitRejection {
+    fn from(decision: RateLimitDecision<K>) -> Self {
+        Self {
+            reason: decision.reason,
+            retry_after: decision.retry_after,
+            detail: decision.detail,
+        }
+    }
+}
+
+/// Layer applying a [`RateLimiter`] to a tower service
+///
+/// Unlike `rate_limit_middleware`, rejections surface as a
+/// [`RateLimitRejection`] error instead of a response. Requests are checked
+/// with `RateLimiter::decide`, so the client IP comes from the request's
+/// `ConnectInfo` or `ClientIp` extension; body-hash limits and response
+/// status tracking only apply through the Axum middleware.
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    limiter: RateLimiter,
+}
+
+impl RateLimitLayer {
+    /// Limit requests with `limiter`
+    pub fn new(limiter: RateLimiter) -> Self {
+        Self { limiter }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimitService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitService {
+            inner,
+            limiter: self.limiter.clone(),
+        }
+    }
+}
+
+/// Service built by [`RateLimitLayer`]
+#[derive(Clone)]
+pub struct RateLimitService<S> {
+    inner: S,
+    limiter: RateLimiter,
+}
+
+impl<S> Service<Request<Body>> for RateLimitService<S>
+where
+    S: Service<Request<Body>> + Clone + Send + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, mut request: Request<Body>) -> Self::Future {
+        // Call the service that was polled ready, leaving a fresh clone in its place
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let limiter = self.limiter.clone();
+
+        Box::pin(async move {
+            let decision = limiter.decide(&request).await;
+            if !decision.allowed && limiter.config().mode != RateLimitMode::Advisory {
+                let mut rejection = RateLimitRejection::from(decision);
+                rejection.retry_after = rejection.retry_after
+                    .map(|retry_after| limiter.config().capped_retry_after(retry_after));
+                return Err(Box::new(rejection) as BoxError);
+            }
+
+            if let Some(level) = limiter.throttle_level(&decision).await {
+                request.extensions_mut().insert(level);
+            }
+            request.extensions_mut().insert(decision);
+            inner.call(request).await.map_err(Into::into)
+        })
+    }
+}
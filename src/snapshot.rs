@@ -0,0 +1,171 @@
+//! Encodings for saved limiter state
+
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::error::SnapshotError;
+
+/// Version written by both codecs; bump it when either encoding changes
+const SNAPSHOT_VERSION: u64 = 1;
+
+/// Leading bytes of a [`BinaryCodec`] snapshot
+const BINARY_MAGIC: &[u8; 4] = b"PRLS";
+
+/// Attempt timestamps by key, as from `RateLimiter::snapshot`
+pub type Snapshot = HashMap<String, Vec<u64>>;
+
+/// Format of the bytes produced by `RateLimiter::save_snapshot_with`
+///
+/// Encodings carry a format version, and `decode` rejects versions it doesn't
+/// know with `SnapshotError::UnsupportedVersion` rather than guessing. A
+/// round trip keeps every key and each key's timestamps, though not
+/// necessarily in their original order.
+pub trait SnapshotCodec {
+    /// Encode a snapshot
+    fn encode(snapshot: &Snapshot) -> Vec<u8>;
+
+    /// Decode bytes produced by `encode`
+    fn decode(bytes: &[u8]) -> Result<Snapshot, SnapshotError>;
+}
+
+/// Human-readable JSON snapshots, e.g.
+/// `{"version":1,"keys":{"10.0.0.1:/login":[1700000000,1700000003]}}`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+#[derive(Serialize, Deserialize)]
+struct JsonSnapshot<K> {
+    version: u64,
+    keys: K,
+}
+
+/// Just the version of a JSON snapshot of any layout
+#[derive(Deserialize)]
+struct JsonVersion {
+    version: u64,
+}
+
+impl SnapshotCodec for JsonCodec {
+    fn encode(snapshot: &Snapshot) -> Vec<u8> {
+        let snapshot = JsonSnapshot { version: SNAPSHOT_VERSION, keys: snapshot };
+        serde_json::to_vec(&snapshot).unwrap_or_default()
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Snapshot, SnapshotError> {
+        // Read the version first so a future layout isn't reported as malformed
+        let versioned: JsonVersion = serde_json::from_slice(bytes)
+            .map_err(|e| SnapshotError::Malformed(e.to_string()))?;
+        if versioned.version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(versioned.version));
+        }
+        let snapshot: JsonSnapshot<Snapshot> = serde_json::from_slice(bytes)
+            .map_err(|e| SnapshotError::Malformed(e.to_string()))?;
+        Ok(snapshot.keys)
+    }
+}
+
+/// Compact binary snapshots, the default for `RateLimiter::save_snapshot`
+///
+/// After a magic number and the version, each key is stored as its UTF-8
+/// bytes followed by its sorted timestamps, the first in full and the rest
+/// as gaps. Every number is a LEB128 varint, so a timestamp recorded within
+/// a couple of minutes of the previous one takes a single byte.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BinaryCodec;
+
+impl SnapshotCodec for BinaryCodec {
+    fn encode(snapshot: &Snapshot) -> Vec<u8> {
+        let mut out = BINARY_MAGIC.to_vec();
+        write_varint(&mut out, SNAPSHOT_VERSION);
+        write_varint(&mut out, snapshot.len() as u64);
+        for (key, timestamps) in snapshot {
+            write_varint(&mut out, key.len() as u64);
+            out.extend_from_slice(key.as_bytes());
+
+            let mut sorted = timestamps.clone();
+            sorted.sort_unstable();
+            write_varint(&mut out, sorted.len() as u64);
+            let mut previous = 0;
+            for timestamp in sorted {
+                write_varint(&mut out, timestamp - previous);
+                previous = timestamp;
+            }
+        }
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Snapshot, SnapshotError> {
+        let mut reader = Reader(bytes);
+        if reader.take(BINARY_MAGIC.len())? != BINARY_MAGIC {
+            return Err(SnapshotError::Malformed("not a binary rate limit snapshot".to_string()));
+        }
+        let version = reader.varint()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        // Counts come from the input, so capacity is bounded by its length
+        let keys = reader.varint()?;
+        let mut snapshot = HashMap::with_capacity(reader.bound(keys));
+        for _ in 0..keys {
+            let len = reader.varint()?;
+            let key = reader.take(usize::try_from(len).unwrap_or(usize::MAX))?;
+            let key = String::from_utf8(key.to_vec())
+                .map_err(|_| SnapshotError::Malformed("key is not UTF-8".to_string()))?;
+
+            let count = reader.varint()?;
+            let mut timestamps = Vec::with_capacity(reader.bound(count));
+            let mut timestamp: u64 = 0;
+            for _ in 0..count {
+                timestamp = timestamp.checked_add(reader.varint()?)
+                    .ok_or_else(|| SnapshotError::Malformed("timestamp overflows".to_string()))?;
+                timestamps.push(timestamp);
+            }
+            snapshot.insert(key, timestamps);
+        }
+
+        if !reader.0.is_empty() {
+            return Err(SnapshotError::Malformed("trailing bytes".to_string()));
+        }
+        Ok(snapshot)
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Cursor over binary snapshot bytes
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.0.len() < len {
+            return Err(SnapshotError::Malformed("unexpected end of snapshot".to_string()));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn varint(&mut self) -> Result<u64, SnapshotError> {
+        let mut value: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.take(1)?[0];
+            value |= u64::from(byte & 0x7f).checked_shl(shift).unwrap_or(0);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(SnapshotError::Malformed("varint too long".to_string()))
+    }
+
+    /// `count` items' capacity, capped at one per remaining byte
+    fn bound(&self, count: u64) -> usize {
+        count.min(self.0.len() as u64) as usize
+    }
+}
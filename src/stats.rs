@@ -0,0 +1,138 @@
+//! Rolling request statistics kept in atomic counters
+
+use std::array;
+use std::hash::{BuildHasher, Hash, RandomState};
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+/// Sub-windows the stats window is split into
+const BUCKETS: usize = 10;
+/// Registers per sub-window for estimating unique keys
+const REGISTERS: usize = 256;
+
+/// Aggregate traffic over a limiter's recent window, from
+/// [`KeyedRateLimiter::stats`](crate::KeyedRateLimiter::stats)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RateLimitStats {
+    /// Requests checked, allowed or not
+    pub requests: u64,
+    /// Requests rejected
+    pub rejections: u64,
+    /// Estimated number of distinct keys checked, typically within about 7%
+    pub unique_keys: u64,
+    /// Seconds the stats cover
+    pub window_secs: u64,
+}
+
+/// One sub-window of counters
+struct Bucket {
+    /// Sub-window the counters belong to
+    epoch: AtomicU64,
+    requests: AtomicU64,
+    rejections: AtomicU64,
+    /// HyperLogLog registers over the keys seen
+    registers: [AtomicU8; REGISTERS],
+}
+
+impl Bucket {
+    fn new() -> Self {
+        Self {
+            epoch: AtomicU64::new(u64::MAX),
+            requests: AtomicU64::new(0),
+            rejections: AtomicU64::new(0),
+            registers: array::from_fn(|_| AtomicU8::new(0)),
+        }
+    }
+
+    /// Take the bucket over for `epoch`, clearing the counts of the sub-window
+    /// it last held
+    fn claim(&self, epoch: u64) {
+        if self.epoch.load(Ordering::Relaxed) == epoch {
+            return;
+        }
+        if self.epoch.swap(epoch, Ordering::Relaxed) != epoch {
+            self.requests.store(0, Ordering::Relaxed);
+            self.rejections.store(0, Ordering::Relaxed);
+            for register in &self.registers {
+                register.store(0, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Request, rejection and unique-key counts over a rolling window
+///
+/// Counters use relaxed atomics and never take a lock, so a snapshot taken
+/// while requests are in flight may be slightly off, and counts recorded as a
+/// sub-window rolls over can be lost.
+pub(crate) struct StatsWindow {
+    buckets: [Bucket; BUCKETS],
+    bucket_secs: u64,
+    seed: RandomState,
+}
+
+impl StatsWindow {
+    /// Stats over roughly the last `window_secs`
+    pub(crate) fn new(window_secs: u64) -> Self {
+        Self {
+            buckets: array::from_fn(|_| Bucket::new()),
+            bucket_secs: window_secs.div_ceil(BUCKETS as u64).max(1),
+            seed: RandomState::new(),
+        }
+    }
+
+    /// Count a checked request for `key` at `now`
+    pub(crate) fn record<Q: Hash + ?Sized>(&self, key: &Q, rejected: bool, now: u64) {
+        let epoch = now / self.bucket_secs;
+        let bucket = &self.buckets[(epoch % BUCKETS as u64) as usize];
+        bucket.claim(epoch);
+
+        bucket.requests.fetch_add(1, Ordering::Relaxed);
+        if rejected {
+            bucket.rejections.fetch_add(1, Ordering::Relaxed);
+        }
+        let hash = self.seed.hash_one(key);
+        let register = (hash >> 56) as usize;
+        let rank = ((hash << 8).leading_zeros() + 1).min(57) as u8;
+        bucket.registers[register].fetch_max(rank, Ordering::Relaxed);
+    }
+
+    /// Totals over the sub-windows still inside the window at `now`
+    pub(crate) fn snapshot(&self, now: u64) -> RateLimitStats {
+        let epoch = now / self.bucket_secs;
+        let oldest = epoch.saturating_sub(BUCKETS as u64 - 1);
+        let mut stats = RateLimitStats {
+            window_secs: self.bucket_secs * BUCKETS as u64,
+            ..RateLimitStats::default()
+        };
+        let mut registers = [0u8; REGISTERS];
+
+        for bucket in &self.buckets {
+            let bucket_epoch = bucket.epoch.load(Ordering::Relaxed);
+            if bucket_epoch < oldest || bucket_epoch > epoch {
+                continue;
+            }
+            stats.requests += bucket.requests.load(Ordering::Relaxed);
+            stats.rejections += bucket.rejections.load(Ordering::Relaxed);
+            for (merged, register) in registers.iter_mut().zip(&bucket.registers) {
+                *merged = (*merged).max(register.load(Ordering::Relaxed));
+            }
+        }
+        stats.unique_keys = estimate_cardinality(&registers).min(stats.requests);
+        stats
+    }
+}
+
+/// HyperLogLog estimate from merged registers, with the small-range
+/// correction
+fn estimate_cardinality(registers: &[u8; REGISTERS]) -> u64 {
+    let m = REGISTERS as f64;
+    let sum: f64 = registers.iter().map(|&rank| 2f64.powi(-i32::from(rank))).sum();
+    let raw = 0.7213 / (1.0 + 1.079 / m) * m * m / sum;
+    let zeros = registers.iter().filter(|&&rank| rank == 0).count();
+    let estimate = if raw <= 2.5 * m && zeros > 0 {
+        m * (m / zeros as f64).ln()
+    } else {
+        raw
+    };
+    estimate.round() as u64
+}
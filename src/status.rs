@@ -0,0 +1,161 @@
+//! JSON introspection handler for admin dashboards
+
+use std::collections::BTreeMap;
+use axum::{extract::State, Json};
+use serde::Serialize;
+
+use crate::{
+    combined::CombinedRateLimiter,
+    config::RateLimitMode,
+    registry::RateLimiterRegistry,
+};
+
+/// Aggregate limiter state returned by [`rate_limit_status_handler`]
+///
+/// Only counts and configuration are included, never individual keys or
+/// login identifiers.
+#[derive(Debug, Clone, Serialize)]
+pub struct RateLimitSummary {
+    /// Whether rate limiting is enabled
+    pub enabled: bool,
+    /// Whether the API limiter has been quiesced
+    pub draining: bool,
+    /// Window algorithm: `sliding_log`, `sliding_window` or `fixed_window`
+    pub algorithm: &'static str,
+    /// How requests over the limit are handled
+    pub mode: RateLimitMode,
+    /// Default requests per window
+    pub max_requests_per_window: u32,
+    /// Default window in seconds
+    pub rate_window_secs: u64,
+    /// Keys tracked by the API limiter
+    pub tracked_keys: usize,
+    /// Keys currently banned
+    pub banned_keys: usize,
+    /// Client IPs over `max_distinct_paths_per_window` or banned for it
+    pub path_scanners: usize,
+    /// Failed login attempts allowed before lockout
+    pub max_login_attempts: u32,
+    /// Lockout duration in seconds
+    pub lockout_duration_secs: u64,
+    /// Identifiers tracked by the login limiter
+    pub tracked_login_identifiers: usize,
+    /// Accounts currently locked out
+    pub locked_accounts: usize,
+}
+
+/// Axum handler reporting aggregate state of both limiters as JSON, e.g. for
+/// an internal `/admin/ratelimit` route
+///
+/// Mount it behind authentication: while it never lists keys, it does reveal
+/// limits and load.
+pub async fn rate_limit_status_handler(
+    State(limiters): State<CombinedRateLimiter>,
+) -> Json<RateLimitSummary> {
+    let config = limiters.api.config();
+    let login_config = limiters.login.config();
+    let (tracked_keys, banned_keys) = limiters.api.key_counts().await;
+    let (tracked_login_identifiers, locked_accounts) = limiters.login.identifier_counts().await;
+
+    Json(RateLimitSummary {
+        enabled: config.enabled,
+        draining: limiters.api.is_draining(),
+        algorithm: config.algorithm(),
+        mode: config.mode,
+        max_requests_per_window: config.max_requests_per_window,
+        rate_window_secs: config.rate_window_secs,
+        tracked_keys,
+        banned_keys,
+        path_scanners: limiters.api.path_scanners().len(),
+        max_login_attempts: login_config.max_login_attempts,
+        lockout_duration_secs: login_config.lockout_duration_secs,
+        tracked_login_identifiers,
+        locked_accounts,
+    })
+}
+
+/// State of one API limiter in a [`RegistrySummary`]
+#[derive(Debug, Clone, Serialize)]
+pub struct LimiterSummary {
+    /// Whether rate limiting is enabled
+    pub enabled: bool,
+    /// Whether the limiter has been quiesced
+    pub draining: bool,
+    /// Window algorithm: `sliding_log`, `sliding_window` or `fixed_window`
+    pub algorithm: &'static str,
+    /// How requests over the limit are handled
+    pub mode: RateLimitMode,
+    /// Default requests per window
+    pub max_requests_per_window: u32,
+    /// Default window in seconds
+    pub rate_window_secs: u64,
+    /// Keys tracked
+    pub tracked_keys: usize,
+    /// Keys currently banned
+    pub banned_keys: usize,
+    /// Client IPs over `max_distinct_paths_per_window` or banned for it
+    pub path_scanners: usize,
+}
+
+/// State of one login limiter in a [`RegistrySummary`]
+#[derive(Debug, Clone, Serialize)]
+pub struct LoginLimiterSummary {
+    /// Failed login attempts allowed before lockout
+    pub max_login_attempts: u32,
+    /// Lockout duration in seconds
+    pub lockout_duration_secs: u64,
+    /// Identifiers tracked
+    pub tracked_identifiers: usize,
+    /// Accounts currently locked out
+    pub locked_accounts: usize,
+}
+
+/// Aggregate state of every limiter in a registry, returned by
+/// [`registry_status_handler`]
+#[derive(Debug, Clone, Serialize)]
+pub struct RegistrySummary {
+    /// API limiters by name
+    pub limiters: BTreeMap<String, LimiterSummary>,
+    /// Login limiters by name
+    pub login_limiters: BTreeMap<String, LoginLimiterSummary>,
+}
+
+/// Axum handler reporting aggregate state of every limiter in a
+/// [`RateLimiterRegistry`] as JSON
+///
+/// Like [`rate_limit_status_handler`], it lists no keys but reveals limits
+/// and load, so mount it behind authentication.
+pub async fn registry_status_handler(
+    State(registry): State<RateLimiterRegistry>,
+) -> Json<RegistrySummary> {
+    let mut limiters = BTreeMap::new();
+    for (name, limiter) in registry.limiters() {
+        let config = limiter.config();
+        let (tracked_keys, banned_keys) = limiter.key_counts().await;
+        limiters.insert(name.to_string(), LimiterSummary {
+            enabled: config.enabled,
+            draining: limiter.is_draining(),
+            algorithm: config.algorithm(),
+            mode: config.mode,
+            max_requests_per_window: config.max_requests_per_window,
+            rate_window_secs: config.rate_window_secs,
+            tracked_keys,
+            banned_keys,
+            path_scanners: limiter.path_scanners().len(),
+        });
+    }
+
+    let mut login_limiters = BTreeMap::new();
+    for (name, limiter) in registry.login_limiters() {
+        let config = limiter.config();
+        let (tracked_identifiers, locked_accounts) = limiter.identifier_counts().await;
+        login_limiters.insert(name.to_string(), LoginLimiterSummary {
+            max_login_attempts: config.max_login_attempts,
+            lockout_duration_secs: config.lockout_duration_secs,
+            tracked_identifiers,
+            locked_accounts,
+        });
+    }
+
+    Json(RegistrySummary { limiters, login_limiters })
+}
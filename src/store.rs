@@ -0,0 +1,322 @@
+//! Pluggable attempt stores
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Mutex, PoisonError};
+use tracing::warn;
+
+use crate::{
+    clock::Clock,
+    config::Quota,
+    error::RateLimitError,
+    memory::{InMemoryStore, MutexMap, ShardedMap},
+    sanitize::LogKey,
+};
+
+/// Future returned by [`RateLimitStore`] operations
+pub type StoreFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, RateLimitError>> + Send + 'a>>;
+
+/// Backend counting attempts per key, e.g. a distributed store shared by
+/// several instances
+///
+/// A limiter using a store (see `RateLimiter::with_store`) admits a request when
+/// the count returned after recording it is within the quota. Rejected
+/// requests are recorded too, as with counter stores such as Redis `INCR`.
+pub trait RateLimitStore: Send + Sync {
+    /// Record `hits` attempts against `key` and return the key's attempt count
+    /// in the quota window, including them
+    fn increment<'a>(&'a self, key: &'a str, hits: u32, quota: Quota) -> StoreFuture<'a, u32>;
+
+    /// Persist any buffered state, e.g. before shutdown
+    fn flush(&self) -> StoreFuture<'_, ()> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// In-process [`RateLimitStore`] keeping exact attempt timestamps
+///
+/// Attempts are kept in an [`InMemoryStore`] map chosen at construction: a
+/// single `Mutex<HashMap>` by default, [`ShardedMap`] through `with_shards`,
+/// or any other through `with_map`, e.g. the `dashmap` feature's
+/// `ConcurrentMap`.
+pub struct MemoryStore {
+    map: Box<dyn InMemoryStore>,
+    clock: Clock,
+}
+
+impl MemoryStore {
+    /// Create an empty store in a single `Mutex<HashMap>`
+    pub fn new() -> Self {
+        Self::with_map(MutexMap::new())
+    }
+
+    /// Create an empty store split into `shards` shards (at least one)
+    pub fn with_shards(shards: usize) -> Self {
+        match shards {
+            0 | 1 => Self::new(),
+            _ => Self::with_map(ShardedMap::new(shards)),
+        }
+    }
+
+    /// Create an empty store keeping its attempts in `map`
+    pub fn with_map(map: impl InMemoryStore + 'static) -> Self {
+        Self {
+            map: Box::new(map),
+            clock: Clock::new(),
+        }
+    }
+
+    /// Remove keys with no attempts left in their window
+    pub fn cleanup(&self) {
+        self.map.cleanup(self.clock.now());
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RateLimitStore for MemoryStore {
+    fn increment<'a>(&'a self, key: &'a str, hits: u32, quota: Quota) -> StoreFuture<'a, u32> {
+        let count = self.map.record(key, hits, quota.window_secs, self.clock.now());
+        Box::pin(async move { Ok(count) })
+    }
+}
+
+/// Store that answers from a fast `local` store and reconciles with a slower
+/// `remote` store shared across instances
+///
+/// Hits are counted locally and forwarded to the remote store in batches:
+/// once `sync_every` hits accumulate for a key, or sooner when the key's
+/// estimated count reaches its limit and the local view is too coarse to
+/// decide. Between syncs a key's count is estimated as the remote count from
+/// the last sync plus the hits not yet forwarded, and the larger of that and
+/// the local count is returned.
+///
+/// Consistency: each instance can admit up to `sync_every` requests per key
+/// that other instances haven't seen yet, so a cluster of `n` instances may
+/// overshoot a limit by roughly `n * sync_every`. Cached remote counts only
+/// overestimate (remote attempts expire between syncs), and every sync near
+/// the limit refreshes them. If the remote store fails, the unsynced hits are
+/// kept for the next sync and the local count is used.
+pub struct LayeredStore<L, R> {
+    local: L,
+    remote: R,
+    sync_every: u32,
+    sync: Mutex<HashMap<String, SyncState>>,
+    clock: Clock,
+    fingerprint_keys: bool,
+}
+
+#[derive(Debug, Default)]
+struct SyncState {
+    unsynced: u32,
+    remote_count: u32,
+    last_seen: u64,
+    window_secs: u64,
+    max_requests: u32,
+}
+
+impl<L: RateLimitStore, R: RateLimitStore> LayeredStore<L, R> {
+    /// Layer `local` over `remote`, syncing every `sync_every` hits per key
+    pub fn new(local: L, remote: R, sync_every: u32) -> Self {
+        Self {
+            local,
+            remote,
+            sync_every: sync_every.max(1),
+            sync: Mutex::new(HashMap::new()),
+            clock: Clock::new(),
+            fingerprint_keys: false,
+        }
+    }
+
+    /// Log keys as their `key_fingerprint` when remote syncs fail, as
+    /// `fingerprint_keys` does for the limiter
+    pub fn with_fingerprint_keys(mut self, fingerprint_keys: bool) -> Self {
+        self.fingerprint_keys = fingerprint_keys;
+        self
+    }
+
+    /// Forget sync state for keys idle longer than their window
+    pub fn cleanup(&self) {
+        let now = self.clock.now();
+        let mut sync = self.sync.lock().unwrap_or_else(PoisonError::into_inner);
+        sync.retain(|_, state| {
+            state.unsynced > 0 || now < state.last_seen.saturating_add(state.window_secs)
+        });
+    }
+
+    /// Take the hits to forward for a key if it's due for a sync
+    fn due_hits(&self, key: &str, hits: u32, quota: Quota) -> (u32, Option<u32>) {
+        let mut sync = self.sync.lock().unwrap_or_else(PoisonError::into_inner);
+        let state = sync.entry(key.to_string()).or_default();
+        state.unsynced = state.unsynced.saturating_add(hits);
+        state.last_seen = self.clock.now();
+        state.window_secs = quota.window_secs;
+        state.max_requests = quota.max_requests;
+
+        let estimate = state.remote_count.saturating_add(state.unsynced);
+        if state.unsynced >= self.sync_every || estimate >= quota.max_requests {
+            (estimate, Some(std::mem::take(&mut state.unsynced)))
+        } else {
+            (estimate, None)
+        }
+    }
+}
+
+impl<L: RateLimitStore, R: RateLimitStore> RateLimitStore for LayeredStore<L, R> {
+    fn increment<'a>(&'a self, key: &'a str, hits: u32, quota: Quota) -> StoreFuture<'a, u32> {
+        Box::pin(async move {
+            let local_count = self.local.increment(key, hits, quota).await?;
+            let (mut estimate, due) = self.due_hits(key, hits, quota);
+
+            if let Some(unsynced) = due {
+                let remote = self.remote.increment(key, unsynced, quota).await;
+
+                let mut sync = self.sync.lock().unwrap_or_else(PoisonError::into_inner);
+                let state = sync.entry(key.to_string()).or_default();
+                match remote {
+                    Ok(count) => {
+                        state.remote_count = count;
+                        estimate = count.saturating_add(state.unsynced);
+                    }
+                    Err(e) => {
+                        warn!("Remote rate limit store sync failed for key {}: {}",
+                            LogKey { key, fingerprint: self.fingerprint_keys }, e);
+                        state.unsynced = state.unsynced.saturating_add(unsynced);
+                    }
+                }
+            }
+
+            Ok(local_count.max(estimate))
+        })
+    }
+
+    /// Forward every key's unsynced hits to the remote store
+    ///
+    /// Hits that fail to sync are kept and the last error is returned.
+    fn flush(&self) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            let due: Vec<(String, u32, Quota)> = {
+                let mut sync = self.sync.lock().unwrap_or_else(PoisonError::into_inner);
+                sync.iter_mut()
+                    .filter(|(_, state)| state.unsynced > 0)
+                    .map(|(key, state)| {
+                        let quota = Quota {
+                            max_requests: state.max_requests,
+                            window_secs: state.window_secs,
+                        };
+                        (key.clone(), std::mem::take(&mut state.unsynced), quota)
+                    })
+                    .collect()
+            };
+
+            let mut result = Ok(());
+            for (key, unsynced, quota) in due {
+                let remote = self.remote.increment(&key, unsynced, quota).await;
+
+                let mut sync = self.sync.lock().unwrap_or_else(PoisonError::into_inner);
+                let state = sync.entry(key).or_default();
+                match remote {
+                    Ok(count) => state.remote_count = count,
+                    Err(e) => {
+                        state.unsynced = state.unsynced.saturating_add(unsynced);
+                        result = Err(e);
+                    }
+                }
+            }
+
+            result?;
+            self.remote.flush().await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    const QUOTA: Quota = Quota { max_requests: 100, window_secs: 60 };
+
+    /// Remote store shared with the test, counting the syncs it receives and
+    /// failing them while `down`
+    #[derive(Clone, Default)]
+    struct Remote(Arc<RemoteState>);
+
+    #[derive(Default)]
+    struct RemoteState {
+        store: MemoryStore,
+        syncs: AtomicU32,
+        down: AtomicBool,
+    }
+
+    impl RateLimitStore for Remote {
+        fn increment<'a>(&'a self, key: &'a str, hits: u32, quota: Quota) -> StoreFuture<'a, u32> {
+            Box::pin(async move {
+                if self.0.down.load(Ordering::Relaxed) {
+                    return Err(RateLimitError::Store("remote unavailable".to_string()));
+                }
+                self.0.syncs.fetch_add(1, Ordering::Relaxed);
+                self.0.store.increment(key, hits, quota).await
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn layered_stores_answer_locally_between_syncs() {
+        let remote = Remote::default();
+        let store = LayeredStore::new(MemoryStore::new(), remote.clone(), 5);
+        for expected in 1..=4 {
+            assert_eq!(store.increment("key", 1, QUOTA).await.unwrap(), expected);
+        }
+        assert_eq!(remote.0.syncs.load(Ordering::Relaxed), 0);
+
+        // The fifth hit forwards the batch in one sync
+        assert_eq!(store.increment("key", 1, QUOTA).await.unwrap(), 5);
+        assert_eq!(remote.0.syncs.load(Ordering::Relaxed), 1);
+        assert_eq!(remote.0.store.increment("key", 0, QUOTA).await.unwrap(), 5);
+    }
+
+    #[tokio::test]
+    async fn layered_stores_count_other_instances_hits_after_a_sync() {
+        let remote = Remote::default();
+        remote.increment("key", 50, QUOTA).await.unwrap();
+        let store = LayeredStore::new(MemoryStore::new(), remote.clone(), 3);
+
+        assert_eq!(store.increment("key", 1, QUOTA).await.unwrap(), 1);
+        assert_eq!(store.increment("key", 1, QUOTA).await.unwrap(), 2);
+        assert_eq!(store.increment("key", 1, QUOTA).await.unwrap(), 53);
+        // Until the next sync the cached remote count plus unsynced hits is used
+        assert_eq!(store.increment("key", 1, QUOTA).await.unwrap(), 54);
+        assert_eq!(remote.0.syncs.load(Ordering::Relaxed), 2);
+
+        // Reaching the limit syncs before `sync_every` hits accumulate
+        let near = Quota { max_requests: 55, ..QUOTA };
+        assert_eq!(store.increment("key", 1, near).await.unwrap(), 55);
+        assert_eq!(remote.0.syncs.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn layered_stores_keep_unsynced_hits_while_the_remote_fails() {
+        let remote = Remote::default();
+        remote.0.down.store(true, Ordering::Relaxed);
+        let store = LayeredStore::new(MemoryStore::new(), remote.clone(), 2);
+
+        // The local count still answers
+        for expected in 1..=3 {
+            assert_eq!(store.increment("key", 1, QUOTA).await.unwrap(), expected);
+        }
+        assert!(store.flush().await.is_err());
+
+        remote.0.down.store(false, Ordering::Relaxed);
+        store.flush().await.unwrap();
+        assert_eq!(remote.0.store.increment("key", 0, QUOTA).await.unwrap(), 3);
+        store.flush().await.unwrap();
+        assert_eq!(remote.0.store.increment("key", 0, QUOTA).await.unwrap(), 3);
+    }
+}
@@ -0,0 +1,54 @@
+//! OpenTelemetry-style span for rate limit decisions, enabled by the `otel`
+//! feature
+
+use std::fmt::Display;
+use std::future::Future;
+use tracing::{field, Instrument, Span};
+
+use crate::{config::Quota, decision::RateLimitDecision};
+
+/// Open a `rate_limit.decision` span for a check
+///
+/// The span is a child of the current span (normally the request span), and
+/// its fields become span attributes when exported through
+/// `tracing-opentelemetry`. Call this before the decision future is first
+/// polled so the parent is the caller's span. `key` is rendered as given, so
+/// callers pass it through `RateLimitConfig::log_key`.
+#[cfg(feature = "otel")]
+pub(crate) fn decision_span(key: impl Display) -> Span {
+    tracing::info_span!(
+        "rate_limit.decision",
+        otel.kind = "internal",
+        rate_limit.key = %key,
+        rate_limit.limit = field::Empty,
+        rate_limit.window_secs = field::Empty,
+        rate_limit.decision = field::Empty,
+        rate_limit.reason = field::Empty,
+        rate_limit.remaining = field::Empty,
+    )
+}
+
+/// Disabled span, so decisions cost nothing extra without the `otel` feature
+#[cfg(not(feature = "otel"))]
+pub(crate) fn decision_span(_key: impl Display) -> Span {
+    Span::none()
+}
+
+/// Record the quota a decision is made against, once it's resolved
+pub(crate) fn record_quota(span: &Span, quota: Quota) {
+    span.record("rate_limit.limit", quota.max_requests);
+    span.record("rate_limit.window_secs", quota.window_secs);
+}
+
+/// Run a decision inside `span`, recording its outcome on the span (a no-op
+/// for a disabled span)
+pub(crate) async fn traced<F>(span: Span, decision: F) -> RateLimitDecision
+where
+    F: Future<Output = RateLimitDecision>,
+{
+    let decision = decision.instrument(span.clone()).await;
+    span.record("rate_limit.decision", if decision.allowed { "allow" } else { "reject" });
+    span.record("rate_limit.reason", field::debug(decision.reason));
+    span.record("rate_limit.remaining", decision.remaining);
+    decision
+}
@@ -0,0 +1,58 @@
+//! `User-Agent` rules from `RateLimitConfig::user_agent_rules`
+
+#[cfg(feature = "user-agent-rules")]
+use axum::http::header::USER_AGENT;
+use axum::http::Request;
+use tracing::warn;
+
+use crate::config::{UserAgentAction, UserAgentRule};
+
+/// Compiled `user_agent_rules`, built once per limiter
+#[derive(Debug, Default)]
+pub(crate) struct UserAgentRules {
+    #[cfg(feature = "user-agent-rules")]
+    rules: Vec<(regex::Regex, UserAgentAction)>,
+}
+
+impl UserAgentRules {
+    /// Compile rules, skipping and logging invalid patterns
+    #[cfg(feature = "user-agent-rules")]
+    pub(crate) fn new(rules: &[UserAgentRule]) -> Self {
+        let rules = rules.iter()
+            .filter_map(|rule| match regex::Regex::new(&rule.pattern) {
+                Ok(regex) => Some((regex, rule.action)),
+                Err(e) => {
+                    warn!("Ignoring invalid User-Agent pattern {:?}: {}", rule.pattern, e);
+                    None
+                }
+            })
+            .collect();
+        Self { rules }
+    }
+
+    /// Rules can't be matched without the `user-agent-rules` feature
+    #[cfg(not(feature = "user-agent-rules"))]
+    pub(crate) fn new(rules: &[UserAgentRule]) -> Self {
+        if !rules.is_empty() {
+            warn!("Ignoring user_agent_rules: the user-agent-rules feature is disabled");
+        }
+        Self {}
+    }
+
+    /// Action of the first rule matching a request's `User-Agent`
+    #[cfg(feature = "user-agent-rules")]
+    pub(crate) fn action<B>(&self, request: &Request<B>) -> Option<UserAgentAction> {
+        if self.rules.is_empty() {
+            return None;
+        }
+        let user_agent = request.headers().get(USER_AGENT)?.to_str().ok()?;
+        self.rules.iter()
+            .find(|(regex, _)| regex.is_match(user_agent))
+            .map(|&(_, action)| action)
+    }
+
+    #[cfg(not(feature = "user-agent-rules"))]
+    pub(crate) fn action<B>(&self, _request: &Request<B>) -> Option<UserAgentAction> {
+        None
+    }
+}
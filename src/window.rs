@@ -0,0 +1,239 @@
+//! Sliding window attempt tracking
+
+use std::collections::VecDeque;
+
+/// Attempts recorded for a single key
+///
+/// Attempts are kept in timestamp order, so pruning only pops stale entries
+/// off the front and a check on a hot key with nothing expired costs no scan.
+///
+/// `Exact` keeps one timestamp per attempt. `Buckets` keeps a count per
+/// sub-window of `width` seconds, stamping each attempt with the start of its
+/// bucket. With `N` buckets over a window of `W` seconds an attempt can age out
+/// up to `W / N` seconds early, so the effective window is between
+/// `W * (N - 1) / N` and `W` long and at most one bucket's worth of traffic is
+/// forgotten early. `N = 1` is a fixed window.
+#[derive(Debug, Clone)]
+pub(crate) enum AttemptWindow {
+    Exact(VecDeque<u64>),
+    Buckets {
+        width: u64,
+        counts: VecDeque<(u64, u32)>,
+    },
+}
+
+impl AttemptWindow {
+    /// Create an empty window, bucketed when `buckets` is set
+    pub(crate) fn new(window_secs: u64, buckets: Option<u32>) -> Self {
+        match buckets.filter(|&n| n > 0) {
+            Some(n) => Self::Buckets {
+                width: window_secs.div_ceil(n as u64).max(1),
+                counts: VecDeque::new(),
+            },
+            None => Self::Exact(VecDeque::new()),
+        }
+    }
+
+    /// Remove attempts at or before `window_start`
+    pub(crate) fn prune(&mut self, window_start: u64) {
+        match self {
+            Self::Exact(timestamps) => {
+                while timestamps.front().is_some_and(|&t| t <= window_start) {
+                    timestamps.pop_front();
+                }
+            }
+            Self::Buckets { counts, .. } => {
+                while counts.front().is_some_and(|&(start, _)| start <= window_start) {
+                    counts.pop_front();
+                }
+            }
+        }
+    }
+
+    /// Count attempts after `window_start` without pruning
+    pub(crate) fn count_since(&self, window_start: u64) -> u32 {
+        match self {
+            Self::Exact(timestamps) => {
+                (timestamps.len() - timestamps.partition_point(|&t| t <= window_start)) as u32
+            }
+            Self::Buckets { counts, .. } => counts.range(counts.partition_point(|&(start, _)| start <= window_start)..)
+                .fold(0u32, |total, &(_, count)| total.saturating_add(count)),
+        }
+    }
+
+    /// Count all retained attempts
+    pub(crate) fn count(&self) -> u32 {
+        match self {
+            Self::Exact(timestamps) => timestamps.len() as u32,
+            Self::Buckets { counts, .. } => counts.iter()
+                .fold(0u32, |total, &(_, count)| total.saturating_add(count)),
+        }
+    }
+
+    /// Record an attempt at `now`
+    ///
+    /// Attempts normally arrive in order and are appended; an older one (e.g.
+    /// a fast-path admission flushed late) is inserted in place.
+    pub(crate) fn record(&mut self, now: u64) {
+        match self {
+            Self::Exact(timestamps) => match timestamps.back() {
+                Some(&last) if last > now => {
+                    let index = timestamps.partition_point(|&t| t <= now);
+                    timestamps.insert(index, now);
+                }
+                _ => timestamps.push_back(now),
+            },
+            Self::Buckets { width, counts } => {
+                let start = now - now % *width;
+                match counts.back_mut() {
+                    Some((last, count)) if *last == start => *count = count.saturating_add(1),
+                    Some((last, _)) if *last > start => {
+                        let index = counts.partition_point(|&(bucket, _)| bucket < start);
+                        match counts.get_mut(index) {
+                            Some((bucket, count)) if *bucket == start => *count = count.saturating_add(1),
+                            _ => counts.insert(index, (start, 1)),
+                        }
+                    }
+                    _ => counts.push_back((start, 1)),
+                }
+            }
+        }
+    }
+
+    /// Remove up to `n` of the most recent attempts, returning how many were
+    /// removed
+    pub(crate) fn remove_latest(&mut self, n: u32) -> u32 {
+        match self {
+            Self::Exact(timestamps) => {
+                let removed = (n as usize).min(timestamps.len());
+                timestamps.truncate(timestamps.len() - removed);
+                removed as u32
+            }
+            Self::Buckets { counts, .. } => {
+                let mut left = n;
+                while let (true, Some((_, count))) = (left > 0, counts.back_mut()) {
+                    let taken = (*count).min(left);
+                    *count -= taken;
+                    left -= taken;
+                    if *count == 0 {
+                        counts.pop_back();
+                    }
+                }
+                n - left
+            }
+        }
+    }
+
+    /// Timestamp at which fewer than `max` attempts remain, given each attempt
+    /// expires `window_secs` after its stamp
+    ///
+    /// Returns `Some(0)` if the window already has room and `None` if it never
+    /// will (a zero limit).
+    pub(crate) fn available_at(&self, window_secs: u64, max: u32) -> Option<u64> {
+        let count = self.count();
+        if count < max {
+            return Some(0);
+        }
+
+        let stamps: Vec<(u64, u32)> = match self {
+            Self::Exact(timestamps) => timestamps.iter().map(|&t| (t, 1)).collect(),
+            Self::Buckets { counts, .. } => counts.iter().copied().collect(),
+        };
+
+        // Oldest attempts expire first; wait for enough of them to leave room
+        let mut to_expire = (count - max).saturating_add(1);
+        for (stamp, n) in stamps {
+            if n >= to_expire {
+                return Some(stamp.saturating_add(window_secs));
+            }
+            to_expire -= n;
+        }
+        None
+    }
+
+    /// Attempt timestamps, with bucketed attempts stamped at their bucket start
+    pub(crate) fn timestamps(&self) -> Vec<u64> {
+        match self {
+            Self::Exact(timestamps) => timestamps.iter().copied().collect(),
+            Self::Buckets { counts, .. } => counts.iter()
+                .flat_map(|&(start, count)| std::iter::repeat_n(start, count as usize))
+                .collect(),
+        }
+    }
+
+    /// Switch an exact log longer than `max_len` to `buckets` counters over
+    /// `window_secs`, returning whether it was switched
+    ///
+    /// Bucketed windows never grow with the attempt count, so they're left
+    /// as they are.
+    pub(crate) fn coarsen(&mut self, max_len: usize, window_secs: u64, buckets: u32) -> bool {
+        let Self::Exact(timestamps) = self else {
+            return false;
+        };
+        if timestamps.len() <= max_len {
+            return false;
+        }
+
+        let mut coarse = Self::new(window_secs, Some(buckets));
+        for &t in timestamps.iter() {
+            coarse.record(t);
+        }
+        *self = coarse;
+        true
+    }
+
+    /// Timestamp of the oldest retained attempt
+    pub(crate) fn oldest(&self) -> Option<u64> {
+        match self {
+            Self::Exact(timestamps) => timestamps.front().copied(),
+            Self::Buckets { counts, .. } => counts.front().map(|&(start, _)| start),
+        }
+    }
+
+    /// Whether no attempts are retained
+    pub(crate) fn is_empty(&self) -> bool {
+        match self {
+            Self::Exact(timestamps) => timestamps.is_empty(),
+            Self::Buckets { counts, .. } => counts.is_empty(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pruning_from_the_front_leaves_only_in_window_attempts() {
+        let mut window = AttemptWindow::new(60, None);
+        for t in [10, 20, 30, 40] {
+            window.record(t);
+        }
+        // A late fast-path flush lands in order, so it's pruned with its peers
+        window.record(15);
+        assert_eq!(window.count_since(20), 2);
+
+        window.prune(20);
+        assert_eq!(window.count(), 2);
+        assert_eq!(window.timestamps(), [30, 40]);
+        assert_eq!(window.oldest(), Some(30));
+
+        window.prune(40);
+        assert!(window.is_empty());
+    }
+
+    #[test]
+    fn bucketed_windows_prune_whole_buckets() {
+        let mut window = AttemptWindow::new(60, Some(6));
+        for t in [0, 5, 12, 25, 31] {
+            window.record(t);
+        }
+        window.record(3);
+        assert_eq!(window.count(), 6);
+
+        // Buckets start at 0, 10, 20 and 30; the first two are at or before 10
+        window.prune(10);
+        assert_eq!(window.count(), 2);
+        assert_eq!(window.timestamps(), [20, 30]);
+    }
+}
@@ -0,0 +1,108 @@
+use std::net::SocketAddr;
+
+use axum::{
+    body::Body,
+    extract::ConnectInfo,
+    http::{header, Request, StatusCode},
+    response::Response,
+    routing::post,
+    Router,
+};
+use pleme_middleware_rate_limit::{
+    combined_rate_limit_middleware, CombinedRateLimiter, LoginRateLimiter, MemoryStore, RateLimitConfig,
+    RateLimitError, RateLimitHeaders, RateLimiter,
+};
+use tower::ServiceExt;
+
+fn limiters(max_requests_per_window: u32) -> CombinedRateLimiter {
+    let api = RateLimiter::new(RateLimitConfig {
+        max_requests_per_window,
+        rate_limit_headers: RateLimitHeaders::Legacy,
+        ..RateLimitConfig::default()
+    });
+    let login = LoginRateLimiter::new(RateLimitConfig { max_login_attempts: 1, ..RateLimitConfig::default() });
+    CombinedRateLimiter::new(api, login)
+}
+
+async fn login(app: &Router, username: &str) -> Response {
+    let mut request = Request::post("/login")
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(format!(r#"{{"username":"{}"}}"#, username)))
+        .unwrap();
+    request.extensions_mut().insert(ConnectInfo("10.0.0.1:1000".parse::<SocketAddr>().unwrap()));
+    app.clone().oneshot(request).await.unwrap()
+}
+
+fn remaining(response: &Response) -> &str {
+    response.headers()["x-ratelimit-remaining"].to_str().unwrap()
+}
+
+#[tokio::test]
+async fn login_rejections_give_back_the_api_attempt() {
+    let limiters = limiters(5);
+    let app = Router::new()
+        .route("/login", post(|| async { "ok" }))
+        .layer(axum::middleware::from_fn_with_state(limiters.clone(), combined_rate_limit_middleware));
+    limiters.login.record_failed_attempt("alice").await;
+
+    for _ in 0..3 {
+        let response = login(&app, "alice").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(remaining(&response), "5");
+        assert_eq!(response.headers().get_all("x-ratelimit-remaining").iter().count(), 1);
+    }
+    assert_eq!(limiters.api.status("10.0.0.1:/login").await.attempts, 0);
+
+    let response = login(&app, "bob").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(remaining(&response), "4");
+}
+
+#[tokio::test]
+async fn api_rejections_come_first() {
+    let limiters = limiters(1);
+    let app = Router::new()
+        .route("/login", post(|| async { "ok" }))
+        .layer(axum::middleware::from_fn_with_state(limiters.clone(), combined_rate_limit_middleware));
+    assert_eq!(login(&app, "bob").await.status(), StatusCode::OK);
+
+    // Over the API limit, even a locked account gets the API's rejection
+    limiters.login.record_failed_attempt("alice").await;
+    let response = login(&app, "alice").await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(remaining(&response), "0");
+    assert!(response.headers().contains_key(header::RETRY_AFTER));
+}
+
+#[tokio::test]
+async fn reservations_are_all_or_nothing() {
+    let limiters = limiters(2);
+    limiters.login.record_failed_attempt("alice").await;
+
+    // A locked account leaves the API budget untouched
+    for _ in 0..3 {
+        let reserved = limiters.check_and_reserve("10.0.0.1", "alice").await;
+        assert!(matches!(reserved, Err(RateLimitError::AccountLocked(_))));
+    }
+    assert_eq!(limiters.api.status("10.0.0.1").await.attempts, 0);
+
+    let (decision, _) = limiters.check_and_reserve("10.0.0.1", "bob").await.unwrap();
+    assert_eq!(decision.remaining, 1);
+    limiters.check_and_reserve("10.0.0.1", "bob").await.unwrap();
+    assert!(matches!(limiters.check_and_reserve("10.0.0.1", "bob").await, Err(RateLimitError::Exceeded(_))));
+    assert_eq!(limiters.api.status("10.0.0.1").await.attempts, 2);
+}
+
+#[tokio::test]
+async fn store_backed_reservations_only_count_once_login_has_headroom() {
+    let mut limiters = limiters(2);
+    limiters.api = limiters.api.with_store(MemoryStore::new());
+    limiters.login.record_failed_attempt("alice").await;
+
+    for _ in 0..3 {
+        assert!(limiters.check_and_reserve("10.0.0.1", "alice").await.is_err());
+    }
+    assert!(limiters.check_and_reserve("10.0.0.1", "bob").await.is_ok());
+    assert!(limiters.check_and_reserve("10.0.0.1", "bob").await.is_ok());
+    assert!(limiters.check_and_reserve("10.0.0.1", "bob").await.is_err());
+}
@@ -0,0 +1,61 @@
+use std::sync::Arc;
+
+use pleme_middleware_rate_limit::{LoginRateLimiter, RateLimitConfig, RateLimiter};
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn fast_path_admits_exactly_the_limit_under_contention() {
+    let limiter = Arc::new(RateLimiter::new(RateLimitConfig {
+        max_requests_per_window: 200,
+        fast_path_margin: Some(20),
+        ..RateLimitConfig::default()
+    }));
+
+    for round in 0..10 {
+        let key = format!("hot-{}", round);
+        let tasks: Vec<_> = (0..64)
+            .map(|_| {
+                let (limiter, key) = (Arc::clone(&limiter), key.clone());
+                tokio::spawn(async move {
+                    let mut allowed = 0;
+                    for _ in 0..10 {
+                        if limiter.check(key.as_str()).await.allowed {
+                            allowed += 1;
+                        }
+                    }
+                    allowed
+                })
+            })
+            .collect();
+
+        let mut allowed = 0;
+        for task in tasks {
+            allowed += task.await.unwrap();
+        }
+        assert_eq!(allowed, 200, "round {}", round);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn simultaneous_failures_lock_at_exactly_the_threshold() {
+    let limiter = Arc::new(LoginRateLimiter::new(RateLimitConfig {
+        max_login_attempts: 5,
+        ..RateLimitConfig::default()
+    }));
+
+    let tasks: Vec<_> = (0..64)
+        .map(|_| {
+            let limiter = Arc::clone(&limiter);
+            tokio::spawn(async move { limiter.check_and_record_failure("alice").await.is_ok() })
+        })
+        .collect();
+
+    let mut passed = 0;
+    for task in tasks {
+        passed += u32::from(task.await.unwrap());
+    }
+    // The fifth failure locks the account, and every later one is rejected unrecorded
+    assert_eq!(passed, 4);
+    let status = limiter.login_status("alice").await;
+    assert_eq!(status.attempts_used, 5);
+    assert!(status.locked_until.is_some());
+}
@@ -0,0 +1,97 @@
+use pleme_middleware_rate_limit::{Quota, RateLimitConfig};
+
+fn parse(json: &str) -> Result<RateLimitConfig, String> {
+    serde_json::from_str(json).map_err(|error| error.to_string())
+}
+
+/// The window a `rate` given as raw JSON resolves to
+fn rate_json(rate: &str) -> Result<(u32, u64), String> {
+    let config = parse(&format!(r#"{{"rate":{}}}"#, rate))?;
+    Ok((config.max_requests_per_window, config.rate_window_secs))
+}
+
+fn rate(rate: &str) -> Result<(u32, u64), String> {
+    rate_json(&format!("{:?}", rate))
+}
+
+#[test]
+fn rate_accepts_every_unit_spelling() {
+    for unit in ["s", "sec", "secs", "second", "seconds"] {
+        assert_eq!(rate(&format!("10/{}", unit)), Ok((10, 1)), "{}", unit);
+    }
+    for unit in ["m", "min", "mins", "minute", "minutes"] {
+        assert_eq!(rate(&format!("100/{}", unit)), Ok((100, 60)), "{}", unit);
+    }
+    for unit in ["h", "hour", "hours"] {
+        assert_eq!(rate(&format!("1000/{}", unit)), Ok((1000, 3600)), "{}", unit);
+    }
+    for unit in ["d", "day", "days"] {
+        assert_eq!(rate(&format!("5/{}", unit)), Ok((5, 86_400)), "{}", unit);
+    }
+    assert_eq!(rate("500/15min"), Ok((500, 900)));
+    assert_eq!(rate("2/30s"), Ok((2, 30)));
+}
+
+#[test]
+fn rate_ignores_surrounding_whitespace() {
+    assert_eq!(rate(" 100 / min "), Ok((100, 60)));
+    assert_eq!(rate("100/ 15min"), Ok((100, 900)));
+}
+
+#[test]
+fn rate_accepts_a_zero_count() {
+    assert_eq!(rate("0/min"), Ok((0, 60)));
+}
+
+#[test]
+fn rate_rejects_malformed_and_overflowing_values() {
+    let rejected = [
+        ("4294967296/s", "request count must be a whole number"),
+        ("-1/s", "request count must be a whole number"),
+        ("100", "expected <requests>/<unit>"),
+        ("100/", "missing time unit"),
+        ("100/15", "missing time unit"),
+        ("100/week", "unknown time unit"),
+        ("100/1 5min", "unknown time unit"),
+        ("100/0min", "window must be longer than zero"),
+        ("100/99999999999999999999s", "window is too long"),
+        ("100/999999999999999999d", "window is too long"),
+    ];
+    for (value, reason) in rejected {
+        let error = rate(value).unwrap_err();
+        assert!(error.contains(reason), "{:?}: {}", value, error);
+    }
+    assert_eq!(rate("4294967295/s"), Ok((u32::MAX, 1)));
+}
+
+#[test]
+fn rate_conflicts_with_the_explicit_fields() {
+    for json in [
+        r#"{"rate":"100/min","max_requests_per_window":50}"#,
+        r#"{"rate":"100/min","rate_window_secs":30}"#,
+        r#"{"max_requests_per_window":50,"rate_window_secs":30,"rate":"100/min"}"#,
+    ] {
+        let error = parse(json).unwrap_err();
+        assert!(error.contains("`rate` can't be combined"), "{}: {}", json, error);
+    }
+}
+
+#[test]
+fn explicit_fields_and_defaults_still_apply_without_rate() {
+    let config = parse(r#"{"max_requests_per_window":50,"rate_window_secs":30}"#).unwrap();
+    assert_eq!((config.max_requests_per_window, config.rate_window_secs), (50, 30));
+
+    let defaults = RateLimitConfig::default();
+    let config = parse(r#"{"max_requests_per_window":50}"#).unwrap();
+    assert_eq!((config.max_requests_per_window, config.rate_window_secs), (50, defaults.rate_window_secs));
+    let config = parse("{}").unwrap();
+    assert_eq!(config.max_requests_per_window, defaults.max_requests_per_window);
+    assert_eq!(config.rate_window_secs, defaults.rate_window_secs);
+}
+
+#[test]
+fn rate_accepts_the_map_form() {
+    assert_eq!(rate_json(r#"{"max_requests":7,"window_secs":90}"#), Ok((7, 90)));
+    let quota: Quota = serde_json::from_str(r#""100/min""#).unwrap();
+    assert_eq!((quota.max_requests, quota.window_secs), (100, 60));
+}
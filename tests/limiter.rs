@@ -0,0 +1,369 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use pleme_middleware_rate_limit::{
+    CircuitState, ControlCharPolicy, DecisionReason, EvictionPolicy, FutureTimestampPolicy, GlobalFairness, LimitTier,
+    Quota, RateLimitConfig, RateLimitError, RateLimitMode, RateLimiter, RecordHook, RejectionDetail, StoreFuture,
+};
+
+fn limiter(max_requests_per_window: u32, rate_window_secs: u64) -> RateLimiter {
+    RateLimiter::new(RateLimitConfig {
+        max_requests_per_window,
+        rate_window_secs,
+        ..RateLimitConfig::default()
+    })
+}
+
+async fn allowed(limiter: &RateLimiter, key: &str, checks: u32) -> u32 {
+    let mut allowed = 0;
+    for _ in 0..checks {
+        allowed += u32::from(limiter.check(key).await.allowed);
+    }
+    allowed
+}
+
+#[tokio::test(start_paused = true)]
+async fn only_attempts_in_the_window_count() {
+    let limiter = limiter(3, 60);
+    assert_eq!(allowed(&limiter, "key", 2).await, 2);
+    tokio::time::advance(Duration::from_secs(30)).await;
+    assert_eq!(allowed(&limiter, "key", 2).await, 1);
+
+    // The first two attempts have left the window, the third hasn't
+    tokio::time::advance(Duration::from_secs(31)).await;
+    assert_eq!(allowed(&limiter, "key", 3).await, 2);
+    assert_eq!(limiter.check("key").await.remaining, 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn requests_inside_the_min_interval_are_rejected() {
+    let limiter = RateLimiter::new(RateLimitConfig {
+        min_interval_ms: Some(500),
+        ..RateLimitConfig::default()
+    });
+    assert!(limiter.check("key").await.allowed);
+    let decision = limiter.check("key").await;
+    assert!(!decision.allowed);
+    assert_eq!(decision.detail, Some(RejectionDetail::MinInterval { interval_ms: 500, wait_ms: 500 }));
+    assert_eq!(decision.retry_after, Some(1));
+
+    // Other keys are spaced on their own, and the key passes once the interval is over
+    assert!(limiter.check("other").await.allowed);
+    tokio::time::advance(Duration::from_millis(500)).await;
+    assert!(limiter.check("key").await.allowed);
+}
+
+#[tokio::test(start_paused = true)]
+async fn requests_inside_the_min_interval_are_delayed() {
+    let limiter = RateLimiter::new(RateLimitConfig {
+        min_interval_ms: Some(400),
+        mode: RateLimitMode::Delay { max_wait_secs: 1, max_queue_depth: 10 },
+        ..RateLimitConfig::default()
+    });
+    let start = tokio::time::Instant::now();
+    assert!(limiter.check("key").await.allowed);
+    assert!(limiter.check("key").await.allowed);
+    assert_eq!(start.elapsed(), Duration::from_millis(400));
+
+    // Concurrent waiters are spaced out, and waits past `max_wait_secs` are rejected rather than held
+    let (first, second, third) = tokio::join!(limiter.check("key"), limiter.check("key"), limiter.check("key"));
+    assert!(first.allowed && second.allowed);
+    assert!(matches!(third.detail, Some(RejectionDetail::MinInterval { wait_ms: 1200, .. })));
+    assert_eq!(start.elapsed(), Duration::from_millis(1200));
+}
+
+/// Refuses keys starting with `vetoed`
+struct Veto;
+
+impl RecordHook for Veto {
+    fn before_record<'a>(&'a self, key: &'a str) -> StoreFuture<'a, bool> {
+        Box::pin(async move { Ok(!key.starts_with("vetoed")) })
+    }
+}
+
+#[tokio::test]
+async fn vetoed_requests_are_refunded_at_every_level() {
+    let limiter = limiter(2, 60).with_record_hook(Veto);
+    let quota = Quota { max_requests: 2, window_secs: 60 };
+    let levels = [("global".to_string(), Quota { max_requests: 3, window_secs: 60 })];
+    for _ in 0..5 {
+        assert_eq!(limiter.check_levels("vetoed", quota, &levels).await.reason, DecisionReason::Vetoed);
+    }
+    assert_eq!(limiter.status("vetoed").await.attempts, 0);
+    assert_eq!(limiter.status("global").await.attempts, 0);
+
+    // The global level still has all its room
+    for client in ["a", "b", "c"] {
+        assert!(limiter.check_levels(client, quota, &levels).await.allowed);
+    }
+    assert!(!limiter.check_levels("d", quota, &levels).await.allowed);
+}
+
+#[tokio::test(start_paused = true)]
+async fn circuits_open_after_consecutive_rejections_and_probe_to_close() {
+    let limiter = RateLimiter::new(RateLimitConfig {
+        max_requests_per_window: 2,
+        rate_window_secs: 60,
+        circuit_failure_threshold: Some(2),
+        circuit_cooldown_secs: 30,
+        ..RateLimitConfig::default()
+    });
+    assert_eq!(allowed(&limiter, "key", 3).await, 2);
+    assert_eq!(limiter.status("key").await.circuit, CircuitState::Closed);
+    assert_eq!(limiter.check("key").await.reason, DecisionReason::Exceeded);
+    assert!(matches!(limiter.status("key").await.circuit, CircuitState::Open { .. }));
+
+    // Open circuits reject without reaching the limiter
+    let decision = limiter.check("key").await;
+    assert_eq!(decision.reason, DecisionReason::CircuitOpen);
+    assert_eq!(decision.retry_after, Some(30));
+    assert_eq!(limiter.status("key").await.attempts, 2);
+
+    // The probe is still over the limit, so the circuit reopens
+    tokio::time::advance(Duration::from_secs(30)).await;
+    assert_eq!(limiter.status("key").await.circuit, CircuitState::HalfOpen);
+    assert_eq!(limiter.check("key").await.reason, DecisionReason::Exceeded);
+    assert!(matches!(limiter.status("key").await.circuit, CircuitState::Open { .. }));
+    assert_eq!(limiter.check("key").await.reason, DecisionReason::CircuitOpen);
+
+    // Once the attempts have left the window the probe passes and closes it
+    tokio::time::advance(Duration::from_secs(31)).await;
+    assert!(limiter.check("key").await.allowed);
+    assert_eq!(limiter.status("key").await.circuit, CircuitState::Closed);
+    assert!(limiter.check("key").await.allowed);
+}
+
+#[tokio::test(start_paused = true)]
+async fn delayed_checks_wait_for_a_free_slot() {
+    let limiter = limiter(1, 2);
+    let quota = Quota { max_requests: 1, window_secs: 2 };
+    let start = tokio::time::Instant::now();
+    assert!(limiter.check("key").await.allowed);
+    assert!(limiter.check_with_delay("key", quota, Duration::from_secs(5), 10).await.allowed);
+    assert!(start.elapsed() >= Duration::from_secs(2));
+}
+
+#[tokio::test(start_paused = true)]
+async fn delayed_checks_stop_at_the_wait_bound() {
+    let limiter = limiter(1, 60);
+    let quota = Quota { max_requests: 1, window_secs: 60 };
+    let start = tokio::time::Instant::now();
+    assert!(limiter.check("key").await.allowed);
+
+    // The slot frees up after the budget, so waiting would be pointless
+    let decision = limiter.check_with_delay("key", quota, Duration::from_secs(5), 10).await;
+    assert_eq!(decision.reason, DecisionReason::Exceeded);
+    assert_eq!(start.elapsed(), Duration::ZERO);
+}
+
+#[tokio::test(start_paused = true)]
+async fn full_delay_queues_reject_at_once() {
+    let limiter = limiter(1, 2);
+    let quota = Quota { max_requests: 1, window_secs: 2 };
+    assert!(limiter.check("key").await.allowed);
+
+    let wait = Duration::from_secs(5);
+    let (queued, overflow) = tokio::join!(
+        limiter.check_with_delay("key", quota, wait, 1),
+        limiter.check_with_delay("key", quota, wait, 1),
+    );
+    assert!(queued.allowed);
+    assert_eq!(overflow.reason, DecisionReason::Exceeded);
+}
+
+#[tokio::test(start_paused = true)]
+async fn repeat_offenders_are_banned_until_the_ban_expires() {
+    let limiter = RateLimiter::new(RateLimitConfig {
+        max_requests_per_window: 2,
+        rate_window_secs: 10,
+        ban_threshold: Some(2),
+        ban_duration_secs: 30,
+        ..RateLimitConfig::default()
+    });
+    assert_eq!(allowed(&limiter, "key", 2).await, 2);
+    assert_eq!(limiter.check("key").await.reason, DecisionReason::Exceeded);
+    assert!(limiter.status("key").await.banned_until.is_none());
+
+    // The second rejection in the window bans the key
+    let banned = limiter.check("key").await;
+    assert!(matches!(banned.reason, DecisionReason::Banned(_)));
+    assert_eq!(banned.retry_after, Some(30));
+    assert!(limiter.status("key").await.banned_until.is_some());
+    assert!(matches!(limiter.check_rate_limit("key").await, Err(RateLimitError::Banned(_))));
+
+    // The ban outlasts the window, then lifts
+    tokio::time::advance(Duration::from_secs(11)).await;
+    assert!(matches!(limiter.check("key").await.reason, DecisionReason::Banned(_)));
+    tokio::time::advance(Duration::from_secs(20)).await;
+    assert!(limiter.check("key").await.allowed);
+    assert!(limiter.status("key").await.banned_until.is_none());
+}
+
+#[tokio::test]
+async fn exceeded_errors_describe_the_applied_quota() {
+    let limiter = limiter(10, 60);
+    limiter.set_key_quota("key", Quota { max_requests: 1, window_secs: 5 }).await;
+    assert!(limiter.check_rate_limit("key").await.is_ok());
+    match limiter.check_rate_limit("key").await {
+        Err(RateLimitError::Exceeded(message)) => assert_eq!(message, "Maximum 1 requests per 5 seconds exceeded"),
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn saturated_global_limits_hold_the_heavy_key_to_its_share() {
+    let global = Quota { max_requests: 10, window_secs: 60 };
+    let limiter = RateLimiter::new(RateLimitConfig {
+        max_requests_per_window: 100,
+        global_limit: Some(global),
+        global_fairness: Some(GlobalFairness { saturation: 0.5 }),
+        ..RateLimitConfig::default()
+    });
+    let quota = Quota { max_requests: 100, window_secs: 60 };
+    let levels = [("global".to_string(), global)];
+
+    // First come, first served until half the global limit is used
+    for _ in 0..5 {
+        assert!(limiter.check_levels("heavy", quota, &levels).await.allowed);
+    }
+    assert!(limiter.check_levels("light", quota, &levels).await.allowed);
+
+    // Two keys are active, so each gets five of the ten
+    let rejected = limiter.check_levels("heavy", quota, &levels).await;
+    assert_eq!(rejected.reason, DecisionReason::Exceeded);
+    assert_eq!(rejected.detail, Some(RejectionDetail::FairShare { tier: LimitTier::Global, share: 5, active_keys: 2 }));
+    for _ in 0..4 {
+        assert!(limiter.check_levels("light", quota, &levels).await.allowed);
+    }
+
+    // The global limit itself is now used up, for both keys
+    let light = limiter.check_levels("light", quota, &levels).await;
+    assert!(!light.allowed);
+    assert!(!matches!(light.detail, Some(RejectionDetail::FairShare { .. })));
+}
+
+fn bounded_limiter(eviction_policy: EvictionPolicy) -> RateLimiter {
+    RateLimiter::new(RateLimitConfig {
+        max_tracked_keys: Some(4),
+        eviction_policy,
+        ..RateLimitConfig::default()
+    })
+}
+
+async fn tracked(limiter: &RateLimiter) -> Vec<String> {
+    let mut keys: Vec<String> = limiter.snapshot().await.into_keys().collect();
+    keys.sort();
+    keys
+}
+
+#[tokio::test(start_paused = true)]
+async fn lru_eviction_drops_the_least_recently_checked_key() {
+    let limiter = bounded_limiter(EvictionPolicy::Lru);
+    for key in ["a", "b", "c", "d"] {
+        limiter.check(key).await;
+        tokio::time::advance(Duration::from_secs(1)).await;
+    }
+    limiter.check("a").await;
+    // Overridden keys are never evicted, however idle
+    limiter.set_key_quota("b", Quota { max_requests: 10, window_secs: 60 }).await;
+
+    limiter.check("e").await;
+    assert_eq!(tracked(&limiter).await, ["a", "b", "d", "e"]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn lfu_eviction_drops_the_least_checked_key() {
+    let limiter = bounded_limiter(EvictionPolicy::Lfu);
+    for (key, checks) in [("a", 3), ("b", 2), ("c", 1), ("d", 2)] {
+        allowed(&limiter, key, checks).await;
+    }
+
+    limiter.check("e").await;
+    assert_eq!(tracked(&limiter).await, ["a", "b", "d", "e"]);
+}
+
+const CONTROL_KEYS: [&str; 2] = ["10.0.0.1:/api\n10.0.0.2:/admin", "10.0.0.1:\u{1b}[2J\u{1b}[31m/api"];
+
+#[tokio::test]
+async fn sanitized_control_character_keys_are_limited_as_usual() {
+    let limiter = limiter(2, 60);
+    for key in CONTROL_KEYS {
+        assert_eq!(allowed(&limiter, key, 3).await, 2);
+        assert_eq!(limiter.check(key).await.reason, DecisionReason::Exceeded);
+    }
+}
+
+#[tokio::test]
+async fn rejected_control_character_keys_are_refused() {
+    let limiter = RateLimiter::new(RateLimitConfig {
+        control_chars: ControlCharPolicy::Reject,
+        ..RateLimitConfig::default()
+    });
+    for key in CONTROL_KEYS {
+        let decision = limiter.check(key).await;
+        assert!(!decision.allowed);
+        assert_eq!(decision.reason, DecisionReason::InvalidKey);
+        assert!(matches!(limiter.check_rate_limit(key).await, Err(RateLimitError::InvalidKey)));
+        assert_eq!(limiter.status(key).await.attempts, 0);
+    }
+    assert!(limiter.check("10.0.0.1:/api").await.allowed);
+}
+
+/// The limiter's current monotonic time, read back from a probe attempt
+async fn now(limiter: &RateLimiter) -> u64 {
+    limiter.check("probe").await;
+    let now = limiter.snapshot().await["probe"][0];
+    limiter.reset_key("probe").await;
+    now
+}
+
+fn skewed_limiter(policy: FutureTimestampPolicy) -> RateLimiter {
+    RateLimiter::new(RateLimitConfig {
+        max_requests_per_window: 5,
+        rate_window_secs: 60,
+        future_timestamp_tolerance_secs: 30,
+        future_timestamps: policy,
+        ..RateLimitConfig::default()
+    })
+}
+
+#[tokio::test(start_paused = true)]
+async fn clamped_future_timestamps_expire_with_the_window() {
+    for merge in [false, true] {
+        let limiter = skewed_limiter(FutureTimestampPolicy::Clamp);
+        let now = now(&limiter).await;
+        let snapshot = HashMap::from([
+            ("skewed".to_string(), vec![now + 86_400; 5]),
+            ("tolerated".to_string(), vec![now + 20]),
+        ]);
+        if merge {
+            limiter.merge(snapshot).await;
+        } else {
+            limiter.restore(snapshot).await;
+        }
+
+        // Counted as made now, so the key is full for one window, not a day
+        assert_eq!(allowed(&limiter, "skewed", 1).await, 0);
+        assert_eq!(limiter.snapshot().await["skewed"], [now; 5]);
+        assert_eq!(limiter.snapshot().await["tolerated"], [now + 20]);
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert_eq!(allowed(&limiter, "skewed", 5).await, 5);
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn discarded_future_timestamps_are_not_counted() {
+    for merge in [false, true] {
+        let limiter = skewed_limiter(FutureTimestampPolicy::Discard);
+        let now = now(&limiter).await;
+        let snapshot = HashMap::from([("skewed".to_string(), vec![now + 86_400, now + 31, now + 30, now])]);
+        if merge {
+            limiter.merge(snapshot).await;
+        } else {
+            limiter.restore(snapshot).await;
+        }
+
+        assert_eq!(limiter.status("skewed").await.attempts, 2);
+        assert_eq!(allowed(&limiter, "skewed", 5).await, 3);
+    }
+}
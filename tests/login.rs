@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, State},
+    http::{header, Request, StatusCode},
+    routing::post,
+    Extension, Router,
+};
+use pleme_middleware_rate_limit::{
+    login_rate_limit_middleware, ControlCharPolicy, FutureTimestampPolicy, LoginIdentifier, LoginRateLimiter,
+    LoginSnapshot, RateLimitConfig, RateLimitError,
+};
+use tower::ServiceExt;
+
+fn limiter(max_login_attempts: u32) -> LoginRateLimiter {
+    LoginRateLimiter::new(RateLimitConfig {
+        max_login_attempts,
+        rate_window_secs: 60,
+        ..RateLimitConfig::default()
+    })
+}
+
+/// App failing every login, responding with the identifier it was counted under
+fn login_app(limiter: LoginRateLimiter) -> Router {
+    let fail = |State(limiter): State<LoginRateLimiter>, Extension(LoginIdentifier(identifier))| async move {
+        limiter.record_failed_attempt(&identifier).await;
+        identifier
+    };
+    Router::new()
+        .route("/login", post(fail))
+        .layer(axum::middleware::from_fn_with_state(limiter.clone(), login_rate_limit_middleware))
+        .with_state(limiter)
+}
+
+/// Post `body` as `content_type`, returning the status and response body
+async fn login(app: &Router, content_type: Option<&str>, body: &str) -> (StatusCode, String) {
+    let mut request = Request::post("/login");
+    if let Some(content_type) = content_type {
+        request = request.header(header::CONTENT_TYPE, content_type);
+    }
+    send_login(app, request.body(Body::from(body.to_string())).unwrap()).await
+}
+
+async fn send_login(app: &Router, mut request: Request<Body>) -> (StatusCode, String) {
+    request.extensions_mut().insert(ConnectInfo("10.0.0.1:1000".parse::<SocketAddr>().unwrap()));
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+const JSON: Option<&str> = Some("application/json");
+const FORM: Option<&str> = Some("application/x-www-form-urlencoded");
+
+#[tokio::test]
+async fn login_identifiers_come_from_form_and_json_bodies() {
+    let app = login_app(limiter(10));
+    assert_eq!(login(&app, FORM, "username=alice&password=x").await, (StatusCode::OK, "alice".to_string()));
+    assert_eq!(login(&app, JSON, r#"{"username":"bob","password":"x"}"#).await, (StatusCode::OK, "bob".to_string()));
+
+    // Missing, empty, non-string and unparseable identifiers fall back to the IP
+    let by_ip = (StatusCode::OK, "ip:10.0.0.1".to_string());
+    assert_eq!(login(&app, FORM, "password=x").await, by_ip);
+    assert_eq!(login(&app, JSON, r#"{"username":""}"#).await, by_ip);
+    assert_eq!(login(&app, JSON, r#"{"username":7}"#).await, by_ip);
+    assert_eq!(login(&app, JSON, "username=alice").await, by_ip);
+    assert_eq!(login(&app, None, r#"{"username":"alice"}"#).await, by_ip);
+}
+
+#[tokio::test]
+async fn oversized_login_bodies_are_limited_by_ip_or_refused() {
+    let app = login_app(LoginRateLimiter::new(RateLimitConfig {
+        login_max_body_bytes: 32,
+        ..RateLimitConfig::default()
+    }));
+    let body = format!(r#"{{"username":"alice","padding":"{}"}}"#, "x".repeat(64));
+
+    // A declared length over the cap skips the body, while one without is cut off
+    let declared = Request::post("/login")
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::CONTENT_LENGTH, body.len())
+        .body(Body::from(body.clone()))
+        .unwrap();
+    assert_eq!(send_login(&app, declared).await, (StatusCode::OK, "ip:10.0.0.1".to_string()));
+    assert_eq!(login(&app, JSON, &body).await.0, StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[tokio::test]
+async fn body_identifiers_cannot_pose_as_the_ip_fallback() {
+    let app = login_app(limiter(2));
+    let forged = r#"{"username":"ip:10.0.0.1"}"#;
+    assert_eq!(login(&app, JSON, forged).await, (StatusCode::OK, "ip%3A10.0.0.1".to_string()));
+    login(&app, JSON, forged).await;
+    assert_eq!(login(&app, JSON, forged).await.0, StatusCode::TOO_MANY_REQUESTS);
+
+    // The IP's own budget is untouched
+    assert_eq!(login(&app, JSON, "{}").await, (StatusCode::OK, "ip:10.0.0.1".to_string()));
+}
+
+#[tokio::test(start_paused = true)]
+async fn weighted_failures_never_exceed_the_threshold() {
+    let limiter = limiter(3);
+    limiter.record_weighted_failure("alice", 2).await;
+    limiter.record_weighted_failure("alice", 5).await;
+    assert_eq!(limiter.login_status("alice").await.attempts_used, 3);
+
+    limiter.record_failed_attempt("alice").await;
+    assert_eq!(limiter.login_status("alice").await.attempts_used, 3);
+}
+
+#[tokio::test(start_paused = true)]
+async fn expired_failures_leave_room_for_new_ones() {
+    let limiter = limiter(3);
+    limiter.record_weighted_failure("alice", 3).await;
+
+    tokio::time::advance(Duration::from_secs(61)).await;
+    limiter.record_weighted_failure("alice", 3).await;
+    assert_eq!(limiter.login_status("alice").await.attempts_used, 3);
+    assert!(matches!(limiter.check_login_attempt("alice").await, Err(RateLimitError::AccountLocked(_))));
+}
+
+const CONTROL_IDENTIFIERS: [&str; 2] = ["alice\nLogin succeeded for: admin", "bob\u{1b}[2J\u{1b}[31m"];
+
+#[tokio::test]
+async fn sanitized_control_character_identifiers_lock_as_usual() {
+    let limiter = limiter(2);
+    for identifier in CONTROL_IDENTIFIERS {
+        assert!(limiter.check_and_record_failure(identifier).await.is_ok());
+        assert!(matches!(limiter.check_and_record_failure(identifier).await, Err(RateLimitError::AccountLocked(_))));
+        assert!(matches!(limiter.check_login_attempt(identifier).await, Err(RateLimitError::AccountLocked(_))));
+    }
+}
+
+#[tokio::test]
+async fn rejected_control_character_identifiers_are_refused() {
+    let limiter = LoginRateLimiter::new(RateLimitConfig {
+        control_chars: ControlCharPolicy::Reject,
+        ..RateLimitConfig::default()
+    });
+    for identifier in CONTROL_IDENTIFIERS {
+        assert!(matches!(limiter.check_login_attempt(identifier).await, Err(RateLimitError::InvalidKey)));
+        assert!(matches!(limiter.check_and_record_failure(identifier).await, Err(RateLimitError::InvalidKey)));
+        assert_eq!(limiter.login_status(identifier).await.attempts_used, 0);
+    }
+    assert!(limiter.check_login_attempt("alice").await.is_ok());
+}
+
+#[tokio::test(start_paused = true)]
+async fn merged_future_failures_do_not_hold_accounts_locked() {
+    for policy in [FutureTimestampPolicy::Clamp, FutureTimestampPolicy::Discard] {
+        let limiter = LoginRateLimiter::new(RateLimitConfig {
+            max_login_attempts: 3,
+            rate_window_secs: 60,
+            future_timestamp_tolerance_secs: 30,
+            future_timestamps: policy,
+            ..RateLimitConfig::default()
+        });
+        limiter.record_failed_attempt("probe").await;
+        let now = limiter.snapshot().await["probe"].attempts[0];
+
+        let skewed = LoginSnapshot { attempts: vec![now + 86_400; 2], locked_until: None, probation_until: None };
+        limiter.merge(HashMap::from([("alice".to_string(), skewed)])).await;
+        let expected = match policy {
+            FutureTimestampPolicy::Clamp => 2,
+            FutureTimestampPolicy::Discard => 0,
+        };
+        assert_eq!(limiter.login_status("alice").await.attempts_used, expected);
+
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert_eq!(limiter.login_status("alice").await.attempts_used, 0);
+        assert!(limiter.check_login_attempt("alice").await.is_ok());
+    }
+}
@@ -0,0 +1,229 @@
+use std::net::SocketAddr;
+
+use axum::{
+    body::Body,
+    extract::ConnectInfo,
+    http::{Request, StatusCode},
+    response::Response,
+    routing::{get, post},
+    Router,
+};
+use pleme_middleware_rate_limit::{
+    rate_limit_middleware, Quota, RateLimitConfig, RateLimitDecision, RateLimitHeaders, RateLimiter, RecordHook,
+    RefundPolicy, StoreFuture, ThrottleLevel,
+};
+use tower::ServiceExt;
+
+fn app(limiter: RateLimiter) -> Router {
+    Router::new()
+        .route("/ok", get(|| async { "ok" }))
+        .route("/missing", get(|| async { StatusCode::NOT_FOUND }))
+        .layer(axum::middleware::from_fn_with_state(limiter, rate_limit_middleware))
+}
+
+async fn call(app: &Router, addr: &str, path: &str) -> Response {
+    let addr: SocketAddr = addr.parse().unwrap();
+    let mut request = Request::get(path).body(Body::empty()).unwrap();
+    request.extensions_mut().insert(ConnectInfo(addr));
+    app.clone().oneshot(request).await.unwrap()
+}
+
+async fn send(app: &Router, addr: &str, path: &str) -> StatusCode {
+    call(app, addr, path).await.status()
+}
+
+fn header<'a>(response: &'a Response, name: &str) -> &'a str {
+    response.headers()[name].to_str().unwrap()
+}
+
+#[tokio::test]
+async fn status_ban_counts_against_the_clients_own_key() {
+    let config = RateLimitConfig {
+        status_ban_threshold: Some(3),
+        global_limit: Some(Quota { max_requests: 20, window_secs: 60 }),
+        ..RateLimitConfig::default()
+    };
+    let app = app(RateLimiter::new(config));
+
+    for _ in 0..3 {
+        assert_eq!(send(&app, "10.0.0.1:1000", "/missing").await, StatusCode::NOT_FOUND);
+    }
+    assert_eq!(send(&app, "10.0.0.1:1000", "/missing").await, StatusCode::TOO_MANY_REQUESTS);
+
+    // The ban is the prober's alone, not the global level's
+    assert_eq!(send(&app, "10.0.0.2:1000", "/ok").await, StatusCode::OK);
+    assert_eq!(send(&app, "10.0.0.2:1000", "/missing").await, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn refunds_reach_the_levels_a_request_was_counted_against() {
+    let config = RateLimitConfig {
+        max_requests_per_window: 2,
+        global_limit: Some(Quota { max_requests: 2, window_secs: 60 }),
+        refund_policy: Some(RefundPolicy { fraction: 1.0, max_latency_ms: None, require_success: true }),
+        ..RateLimitConfig::default()
+    };
+    let app = app(RateLimiter::new(config));
+
+    // Each success is refunded to both the client's key and the global level
+    for client in 1..=5 {
+        let addr = format!("10.0.0.{}:1000", client);
+        assert_eq!(send(&app, &addr, "/ok").await, StatusCode::OK);
+    }
+
+    // Failures keep their charge
+    assert_eq!(send(&app, "10.0.0.1:1000", "/missing").await, StatusCode::NOT_FOUND);
+    assert_eq!(send(&app, "10.0.0.2:1000", "/missing").await, StatusCode::NOT_FOUND);
+    assert_eq!(send(&app, "10.0.0.3:1000", "/ok").await, StatusCode::TOO_MANY_REQUESTS);
+}
+
+/// Refuses requests for `/vetoed`
+struct Veto;
+
+impl RecordHook for Veto {
+    fn before_record<'a>(&'a self, key: &'a str) -> StoreFuture<'a, bool> {
+        Box::pin(async move { Ok(!key.ends_with("/vetoed")) })
+    }
+}
+
+#[tokio::test]
+async fn vetoes_refund_the_subnet_and_global_levels() {
+    let config = RateLimitConfig {
+        subnet_limit: Some(Quota { max_requests: 2, window_secs: 60 }),
+        global_limit: Some(Quota { max_requests: 3, window_secs: 60 }),
+        ..RateLimitConfig::default()
+    };
+    let limiter = RateLimiter::new(config).with_record_hook(Veto);
+    let app = app(limiter.clone());
+
+    for _ in 0..5 {
+        assert_eq!(send(&app, "10.0.0.1:1000", "/vetoed").await, StatusCode::TOO_MANY_REQUESTS);
+    }
+    assert_eq!(limiter.status("10.0.0.1:/vetoed").await.attempts, 0);
+    assert_eq!(limiter.status("subnet:10.0.0.0/24").await.attempts, 0);
+    assert_eq!(limiter.status("global").await.attempts, 0);
+
+    // Both levels still have all their room
+    assert_eq!(send(&app, "10.0.0.1:1000", "/ok").await, StatusCode::OK);
+    assert_eq!(send(&app, "10.0.0.2:1000", "/ok").await, StatusCode::OK);
+    assert_eq!(send(&app, "10.0.0.3:1000", "/ok").await, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(send(&app, "10.0.1.1:1000", "/ok").await, StatusCode::OK);
+    assert_eq!(send(&app, "10.0.2.1:1000", "/ok").await, StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn body_keys_use_a_stable_hash() {
+    let config = RateLimitConfig {
+        body_hash_paths: vec!["/submit".to_string()],
+        body_hash_quota: Some(Quota { max_requests: 1, window_secs: 60 }),
+        ..RateLimitConfig::default()
+    };
+    let limiter = RateLimiter::new(config);
+    let app = Router::new()
+        .route("/submit", post(|| async { "ok" }))
+        .layer(axum::middleware::from_fn_with_state(limiter.clone(), rate_limit_middleware));
+    let submit = |body: &'static str| {
+        let mut request = Request::post("/submit").header("content-length", body.len()).body(Body::from(body)).unwrap();
+        request.extensions_mut().insert(ConnectInfo("10.0.0.1:1000".parse::<SocketAddr>().unwrap()));
+        app.clone().oneshot(request)
+    };
+
+    assert_eq!(submit(r#"{"user":"a"}"#).await.unwrap().status(), StatusCode::OK);
+    assert_eq!(limiter.status("10.0.0.1%3A/submit:body:a814d106f86b1193").await.attempts, 1);
+    assert_eq!(submit(r#"{"user":"a"}"#).await.unwrap().status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(submit(r#"{"user":"b"}"#).await.unwrap().status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn check_once_reuses_the_middleware_decision_with_levels() {
+    let config = RateLimitConfig {
+        max_requests_per_window: 2,
+        global_limit: Some(Quota { max_requests: 100, window_secs: 60 }),
+        subnet_limit: Some(Quota { max_requests: 50, window_secs: 60 }),
+        ..RateLimitConfig::default()
+    };
+    let limiter = RateLimiter::new(config);
+    let handler_limiter = limiter.clone();
+    let app = Router::new()
+        .route("/export", get(move |mut request: Request<Body>| async move {
+            let earlier = request.extensions().get::<RateLimitDecision>().cloned().unwrap();
+            let decision = handler_limiter.check_once(request.extensions_mut(), earlier.key.as_str()).await;
+            assert_eq!(decision, earlier);
+            StatusCode::OK
+        }))
+        .layer(axum::middleware::from_fn_with_state(limiter, rate_limit_middleware));
+
+    // Counted once per request, so both fit the key's limit of two
+    assert_eq!(send(&app, "10.0.0.1:1000", "/export").await, StatusCode::OK);
+    assert_eq!(send(&app, "10.0.0.1:1000", "/export").await, StatusCode::OK);
+    assert_eq!(send(&app, "10.0.0.1:1000", "/export").await, StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn headers_and_body_describe_the_tightest_level() {
+    let config = RateLimitConfig {
+        max_requests_per_window: 10,
+        global_limit: Some(Quota { max_requests: 3, window_secs: 30 }),
+        rate_limit_headers: RateLimitHeaders::Legacy,
+        json_rejection_body: true,
+        ..RateLimitConfig::default()
+    };
+    let app = app(RateLimiter::new(config));
+
+    let response = call(&app, "10.0.0.1:1000", "/ok").await;
+    assert_eq!(header(&response, "x-ratelimit-limit"), "3");
+    assert_eq!(header(&response, "x-ratelimit-remaining"), "2");
+    assert_eq!(header(&response, "x-ratelimit-reset"), "30");
+
+    send(&app, "10.0.0.2:1000", "/ok").await;
+    send(&app, "10.0.0.3:1000", "/ok").await;
+    let response = call(&app, "10.0.0.1:1000", "/ok").await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(header(&response, "x-ratelimit-limit"), "3");
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["limit"], 3);
+    assert_eq!(body["window"], 30);
+}
+
+#[tokio::test]
+async fn headers_describe_the_key_while_it_is_tightest() {
+    let config = RateLimitConfig {
+        max_requests_per_window: 5,
+        global_limit: Some(Quota { max_requests: 100, window_secs: 60 }),
+        rate_limit_headers: RateLimitHeaders::Legacy,
+        ..RateLimitConfig::default()
+    };
+    let app = app(RateLimiter::new(config));
+
+    let response = call(&app, "10.0.0.1:1000", "/ok").await;
+    assert_eq!(header(&response, "x-ratelimit-limit"), "5");
+    assert_eq!(header(&response, "x-ratelimit-remaining"), "4");
+}
+
+#[tokio::test]
+async fn throttle_level_follows_the_tightest_level() {
+    let config = RateLimitConfig {
+        max_requests_per_window: 100,
+        global_limit: Some(Quota { max_requests: 4, window_secs: 60 }),
+        soft_throttle_ratio: Some(0.5),
+        ..RateLimitConfig::default()
+    };
+    let app = Router::new()
+        .route("/level", get(|request: Request<Body>| async move {
+            match request.extensions().get::<ThrottleLevel>() {
+                Some(ThrottleLevel::Soft) => "soft",
+                _ => "none",
+            }
+        }))
+        .layer(axum::middleware::from_fn_with_state(RateLimiter::new(config), rate_limit_middleware));
+
+    let mut levels = Vec::new();
+    for client in 1..=4 {
+        let response = call(&app, &format!("10.0.0.{}:1000", client), "/level").await;
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        levels.push(String::from_utf8(body.to_vec()).unwrap());
+    }
+    // Each client used 1 of its 100, but the global level is half used from the second request
+    assert_eq!(levels, ["none", "soft", "soft", "soft"]);
+}
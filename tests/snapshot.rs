@@ -0,0 +1,66 @@
+use pleme_middleware_rate_limit::{BinaryCodec, JsonCodec, Snapshot, SnapshotCodec, SnapshotError};
+
+fn sample() -> Snapshot {
+    let mut snapshot: Snapshot = (0..500u64)
+        .map(|i| (format!("10.0.{}.{}:/login", i / 256, i % 256), vec![1_700_000_000 + i, 1_700_000_000]))
+        .collect();
+    snapshot.insert("empty".to_string(), Vec::new());
+    snapshot.insert("large".to_string(), vec![u64::MAX, 0, u64::MAX - 1, 1 << 63]);
+    snapshot.insert("ключ:/ü".to_string(), vec![42]);
+    snapshot
+}
+
+/// Timestamps sorted per key, since codecs needn't keep their order
+fn sorted(mut snapshot: Snapshot) -> Snapshot {
+    snapshot.values_mut().for_each(|timestamps| timestamps.sort_unstable());
+    snapshot
+}
+
+fn round_trip<C: SnapshotCodec>() {
+    let snapshot = sample();
+    let decoded = C::decode(&C::encode(&snapshot)).unwrap();
+    assert_eq!(sorted(decoded), sorted(snapshot));
+    assert_eq!(C::decode(&C::encode(&Snapshot::new())).unwrap(), Snapshot::new());
+}
+
+#[test]
+fn json_round_trips() {
+    round_trip::<JsonCodec>();
+}
+
+#[test]
+fn binary_round_trips() {
+    round_trip::<BinaryCodec>();
+}
+
+#[test]
+fn unknown_versions_are_rejected() {
+    let json = br#"{"version":2,"keys":{"a":[1]}}"#;
+    assert!(matches!(JsonCodec::decode(json), Err(SnapshotError::UnsupportedVersion(2))));
+    // A future layout is still reported by version, not as malformed
+    let json = br#"{"version":7,"entries":[]}"#;
+    assert!(matches!(JsonCodec::decode(json), Err(SnapshotError::UnsupportedVersion(7))));
+
+    let mut binary = BinaryCodec::encode(&sample());
+    binary[4] = 9;
+    assert!(matches!(BinaryCodec::decode(&binary), Err(SnapshotError::UnsupportedVersion(9))));
+}
+
+#[test]
+fn truncated_and_malformed_binary_input_is_rejected() {
+    let snapshot = Snapshot::from([
+        ("key".to_string(), vec![1_700_000_000, 1_700_000_060]),
+        ("empty".to_string(), Vec::new()),
+    ]);
+    let binary = BinaryCodec::encode(&snapshot);
+    for len in 0..binary.len() {
+        let decoded = BinaryCodec::decode(&binary[..len]);
+        assert!(matches!(decoded, Err(SnapshotError::Malformed(_))), "prefix of {} bytes", len);
+    }
+
+    let mut trailing = binary.clone();
+    trailing.push(0);
+    assert!(matches!(BinaryCodec::decode(&trailing), Err(SnapshotError::Malformed(_))));
+    assert!(matches!(BinaryCodec::decode(b"JSON{}"), Err(SnapshotError::Malformed(_))));
+    assert!(matches!(JsonCodec::decode(&binary), Err(SnapshotError::Malformed(_))));
+}
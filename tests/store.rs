@@ -0,0 +1,75 @@
+#[cfg(feature = "dashmap")]
+use pleme_middleware_rate_limit::ConcurrentMap;
+use pleme_middleware_rate_limit::{
+    InMemoryStore, MemoryStore, MutexMap, Quota, RateLimitConfig, RateLimitStore, RateLimiter, ShardedMap,
+};
+
+fn maps() -> Vec<Box<dyn InMemoryStore>> {
+    #[cfg_attr(not(feature = "dashmap"), allow(unused_mut))]
+    let mut maps: Vec<Box<dyn InMemoryStore>> = vec![Box::new(MutexMap::new()), Box::new(ShardedMap::new(8))];
+    #[cfg(feature = "dashmap")]
+    maps.push(Box::new(ConcurrentMap::new()));
+    maps
+}
+
+#[test]
+fn maps_count_attempts_in_the_window() {
+    for map in maps() {
+        assert_eq!(map.record("a", 2, 60, 100), 2);
+        assert_eq!(map.record("b", 1, 60, 100), 1);
+        assert_eq!(map.record("a", 1, 60, 130), 3);
+        // The attempts at 100 leave the window at 160
+        assert_eq!(map.record("a", 1, 60, 160), 2);
+        assert_eq!(map.record("b", 0, 60, 160), 0);
+    }
+}
+
+#[test]
+fn cleanup_keeps_only_live_keys() {
+    for map in maps() {
+        map.record("old", 1, 10, 100);
+        map.record("new", 1, 60, 100);
+        map.cleanup(120);
+        assert_eq!(map.record("old", 0, 10, 120), 0);
+        assert_eq!(map.record("new", 0, 60, 120), 1);
+    }
+}
+
+#[tokio::test]
+async fn limiters_work_over_any_map() {
+    let config = RateLimitConfig { max_requests_per_window: 3, ..RateLimitConfig::default() };
+    let stores = [MemoryStore::new(), MemoryStore::with_shards(4), MemoryStore::with_map(ShardedMap::new(2))];
+    for store in stores {
+        let limiter = RateLimiter::new(config.clone()).with_store(store);
+        let mut allowed = 0;
+        for _ in 0..5 {
+            allowed += u32::from(limiter.check("key").await.allowed);
+        }
+        assert_eq!(allowed, 3);
+    }
+}
+
+#[tokio::test]
+async fn memory_store_counts_every_increment() {
+    let quota = Quota { max_requests: 3, window_secs: 60 };
+    let store = MemoryStore::with_map(MutexMap::new());
+    for expected in 1..=4 {
+        assert_eq!(store.increment("key", 1, quota).await.unwrap(), expected);
+    }
+}
+
+#[tokio::test]
+async fn failed_reservations_are_not_counted() {
+    let config = RateLimitConfig { max_requests_per_window: 5, ..RateLimitConfig::default() };
+    let limiters = [RateLimiter::new(config.clone()), RateLimiter::new(config).with_store(MemoryStore::new())];
+    for limiter in limiters {
+        // Too large to ever fit, refused without recording anything
+        assert!(!limiter.try_consume("key", u32::MAX).await);
+        assert!(!limiter.try_consume("key", 6).await);
+
+        assert!(limiter.try_consume("key", 4).await);
+        assert!(!limiter.try_consume("key", 2).await);
+        assert!(limiter.try_consume("key", 1).await);
+        assert!(!limiter.try_consume("key", 1).await);
+    }
+}